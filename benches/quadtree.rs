@@ -0,0 +1,156 @@
+//! Compares the flat `QuadTree` against the boxed tree it replaced, for
+//! memory and for traversal. Run with `cargo bench --bench quadtree`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{black_box, Criterion};
+use group_project::quadtree::{util::full_size, QuadTree};
+
+/// Counts the bytes currently allocated, to see what each tree holds on to
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The tree as it used to be: a box per node with four children
+mod boxed {
+    #[derive(Clone)]
+    pub enum QuadTree<T: Clone> {
+        Leaf(T),
+        Node(T, Box<[QuadTree<T>; 4]>),
+    }
+
+    impl<T: Clone> QuadTree<T> {
+        pub fn build_complete_tree(elements: Vec<T>, depth: u32) -> Self {
+            Self::build_node(&elements, 0, 0, depth)
+        }
+
+        fn build_node(all: &[T], index: usize, level: u32, depth: u32) -> Self {
+            if level == depth - 1 {
+                return Self::Leaf(all[index].clone());
+            }
+
+            let children = index << 2;
+            Self::Node(
+                all[index].clone(),
+                Box::new(
+                    [1, 2, 3, 4].map(|i| Self::build_node(all, children + i, level + 1, depth)),
+                ),
+            )
+        }
+
+        pub fn items_at_level(&self, level: u32) -> Vec<&T> {
+            match self {
+                QuadTree::Leaf(e) => vec![e],
+                QuadTree::Node(e, _) if level == 0 => vec![e],
+                QuadTree::Node(_, children) => children
+                    .iter()
+                    .flat_map(|q| q.items_at_level(level - 1))
+                    .collect(),
+            }
+        }
+
+        pub fn select<F: FnMut(&T) -> bool>(&self, mut refine: F) -> Vec<&T> {
+            let mut selected = Vec::new();
+            self.select_into(&mut refine, &mut selected);
+            selected
+        }
+
+        fn select_into<'a, F: FnMut(&T) -> bool>(&'a self, refine: &mut F, into: &mut Vec<&'a T>) {
+            match self {
+                QuadTree::Node(e, children) if refine(e) => {
+                    for q in children.iter() {
+                        q.select_into(refine, into);
+                    }
+                }
+                QuadTree::Leaf(e) | QuadTree::Node(e, _) => into.push(e),
+            }
+        }
+    }
+}
+
+const DEPTH: u32 = 9;
+
+fn elements() -> Vec<u64> {
+    (0..full_size(DEPTH) as u64).collect()
+}
+
+/// The bytes a tree holds on to, found by dropping it
+fn held_by<T>(tree: T) -> usize {
+    let with = ALLOCATED.load(Ordering::Relaxed);
+    drop(tree);
+    with - ALLOCATED.load(Ordering::Relaxed)
+}
+
+fn memory() {
+    let flat = held_by(QuadTree::build_complete_tree(elements(), DEPTH));
+    let boxed = held_by(boxed::QuadTree::build_complete_tree(elements(), DEPTH));
+    println!(
+        "{} nodes of u64: flat holds {} bytes, boxed holds {} bytes",
+        full_size(DEPTH),
+        flat,
+        boxed
+    );
+}
+
+fn traversal(c: &mut Criterion) {
+    let flat = QuadTree::build_complete_tree(elements(), DEPTH);
+    let boxed = boxed::QuadTree::build_complete_tree(elements(), DEPTH);
+
+    let mut group = c.benchmark_group("every node");
+    group.bench_function("flat", |b| b.iter(|| black_box(&flat).iter().sum::<u64>()));
+    group.bench_function("flat dfs", |b| {
+        b.iter(|| black_box(&flat).dfs().map(|(_, e)| e).sum::<u64>())
+    });
+    group.bench_function("boxed", |b| {
+        b.iter(|| {
+            (0..DEPTH)
+                .flat_map(|level| black_box(&boxed).items_at_level(level))
+                .sum::<u64>()
+        })
+    });
+    group.finish();
+
+    // Refine the top six levels, and then every other node below them
+    let refine = |e: &u64| *e < full_size(6) as u64 || e & 1 == 0;
+    let mut group = c.benchmark_group("select");
+    group.bench_function("flat", |b| b.iter(|| black_box(&flat).select(refine).len()));
+    group.bench_function("boxed", |b| {
+        b.iter(|| black_box(&boxed).select(refine).len())
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("build");
+    group.bench_function("flat", |b| {
+        b.iter(|| QuadTree::build_complete_tree(elements(), DEPTH))
+    });
+    group.bench_function("boxed", |b| {
+        b.iter(|| boxed::QuadTree::build_complete_tree(elements(), DEPTH))
+    });
+    group.finish();
+}
+
+fn main() {
+    memory();
+
+    let mut c = Criterion::default().configure_from_args();
+    traversal(&mut c);
+    c.final_summary();
+}
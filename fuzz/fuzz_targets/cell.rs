@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::io::{BufReader, Cursor};
+
+use group_project::cell::Cell;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Cell::read_from(
+        &mut BufReader::new(Cursor::new(data)),
+        (0, 0),
+        None,
+        None,
+        1024,
+    );
+});
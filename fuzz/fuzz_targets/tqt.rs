@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::io::{BufReader, Cursor};
+
+use group_project::texture_quadtree::TexturedQuadTree;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = TexturedQuadTree::read_from(&mut BufReader::new(Cursor::new(data)));
+});
@@ -1,28 +1,33 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
+use nalgebra::Point3;
 use vulkano::{
-    buffer::{BufferUsage, CpuAccessibleBuffer, TypedBufferAccess},
+    buffer::{BufferUsage, CpuAccessibleBuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage,
-        PrimaryCommandBufferAbstract, RenderPassBeginInfo, SubpassContents,
+        allocator::{CommandBufferAllocator, StandardCommandBufferAllocator},
+        AutoCommandBufferBuilder, CommandBufferUsage, DrawIndexedIndirectCommand,
+        RenderPassBeginInfo, SubpassContents,
     },
     descriptor_set::{
         allocator::StandardDescriptorSetAllocator, PersistentDescriptorSet, WriteDescriptorSet,
     },
+    device::DeviceOwned,
     format::Format,
-    image::{view::ImageView, ImageAccess, ImageDimensions, ImmutableImage, SwapchainImage},
+    image::{view::ImageView, AttachmentImage, ImageAccess, SwapchainImage},
     memory::allocator::{FreeListAllocator, GenericMemoryAllocator, StandardMemoryAllocator},
     pipeline::{
         graphics::{
+            depth_stencil::DepthStencilState,
             input_assembly::{InputAssemblyState, PrimitiveTopology},
             rasterization::{PolygonMode, RasterizationState},
-            vertex_input::BuffersDefinition,
             viewport::{Viewport, ViewportState},
         },
         GraphicsPipeline, Pipeline, PipelineBindPoint,
     },
     render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
-    sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+    sampler::{
+        Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE,
+    },
     swapchain::{
         acquire_next_image, AcquireError, SwapchainCreateInfo, SwapchainCreationError,
         SwapchainPresentInfo,
@@ -31,10 +36,20 @@ use vulkano::{
 };
 use winit::window::Window;
 
-use crate::{
+use group_project::{
     camera::Camera,
-    cell::{chunk::HFVertex, tile::Tile},
+    cell::{
+        chunk::Chunk,
+        tile::{Tile, TileId},
+    },
+    coords::{Frame, WorldPos},
+    geometry::IntersectionStatus,
     map::Map,
+    stats::{FrameStats, StatsReporter},
+};
+
+use crate::{
+    tile_pool::{self, TextureTiles, TilePool},
     window_state::WindowState,
 };
 
@@ -44,24 +59,66 @@ pub(crate) mod vs {
         src: "
         #version 460
 
-        layout(location = 0) in vec3 position;
-        layout(location = 1) in vec3 color;
-        layout(location = 2) in vec2 txt_coord;
-        layout(location = 3) in float morph_delta;
+        // x, y, z and morph delta, straight from the cell file
+        layout(location = 0) in ivec4 position;
+        // In world space, from the cell file or worked out from the chunk.
+        // Only maps without a normal map have these; others read nonsense
+        // here and never use it
+        layout(location = 1) in vec4 normal;
 
+        // Everything is drawn relative to the camera, which the view has at
+        // the origin. The scale takes vertex units to world units; its w is
+        // the camera's elevation
         layout(set = 0, binding = 0) uniform WorldObject {
-            mat4 model;
             mat4 view;
             mat4 proj;
+            vec4 scale;
         } world;
 
-        layout(location = 0) out vec3 v_color;
-        layout(location = 1) out vec2 f_txt_coord;
+        // Where the tile's texture starts in the cell and one over its size,
+        // both in vertex units, how far the tile is morphed towards its
+        // parent, the layer of the texture array holding its texture, and
+        // where the tile starts relative to the camera, in world units
+        struct TileInfo {
+            float offset_x;
+            float offset_z;
+            float scale;
+            float morph;
+            uint layer;
+            float origin_x;
+            float origin_y;
+            float origin_z;
+        };
+
+        // One per drawn tile, indexed by the draw's first instance
+        layout(set = 0, binding = 1) readonly buffer Tiles {
+            TileInfo tiles[];
+        };
+
+        layout(location = 0) out vec3 f_txt_coord;
+        layout(location = 1) out float f_view_dist;
+        layout(location = 2) out float f_elevation;
+        layout(location = 3) out vec3 f_normal;
 
         void main() {
-            gl_Position = world.proj * world.view * world.model * vec4(position, 1.0);
-            v_color = color;
-            f_txt_coord = txt_coord;
+            TileInfo tile = tiles[gl_InstanceIndex];
+            vec3 pos = vec3(position.xyz);
+            pos.y += tile.morph * float(position.w);
+
+            // Small numbers only: the vertex within its tile, scaled, from
+            // the tile's corner relative to the camera
+            vec3 in_tile = pos - vec3(tile.offset_x, 0.0, tile.offset_z);
+            vec3 origin = vec3(tile.origin_x, tile.origin_y, tile.origin_z);
+            vec4 rel_pos = vec4(origin + in_tile * world.scale.xyz, 1.0);
+            vec4 view_pos = world.view * rel_pos;
+            gl_Position = world.proj * view_pos;
+            f_view_dist = length(view_pos.xyz);
+            f_elevation = world.scale.w + rel_pos.y;
+            f_normal = normal.xyz;
+            f_txt_coord = vec3(
+                (pos.xz - vec2(tile.offset_x, tile.offset_z)) * tile.scale,
+                float(tile.layer)
+            );
         }
     ",
     types_meta: {
@@ -78,20 +135,159 @@ mod fs {
         src: "
         #version 460
 
-        layout(location = 0) in vec3 v_color; 
-        layout(location = 1) in vec2 txt_coord;
+        layout(location = 0) in vec3 txt_coord;
+        layout(location = 1) in float view_dist;
+        layout(location = 2) in float world_elevation;
+        layout(location = 3) in vec3 vertex_normal;
 
         layout(location = 0) out vec4 f_color;
-        //layout(location = 1) out vec4 t_color;
 
-        layout(set = 1, binding = 0) uniform sampler2D tex;
+        layout(set = 0, binding = 2) uniform sampler2DArray tex;
+        layout(set = 0, binding = 3) uniform sampler2DArray normals;
+
+        const uint TEXTURES = 1;
+        const uint LIGHTING = 2;
+        const uint FOG = 4;
+        const uint RAMP = 8;
+        const uint NORMAL_MAP = 16;
+
+        // The fog's alpha is its density; the elevation has the map's
+        // minimum and maximum elevations
+        layout(push_constant) uniform Shading {
+            vec4 sun_dir;
+            vec4 sun_intensity;
+            vec4 ambient;
+            vec4 fog;
+            vec4 elevation;
+            uint flags;
+        } shading;
+
+        // Hypsometric tints, from lowlands to peaks
+        vec3 ramp(float t) {
+            vec3 color = mix(vec3(0.22, 0.42, 0.20), vec3(0.55, 0.62, 0.33), smoothstep(0.0, 0.3, t));
+            color = mix(color, vec3(0.66, 0.52, 0.36), smoothstep(0.3, 0.65, t));
+            color = mix(color, vec3(0.48, 0.42, 0.40), smoothstep(0.65, 0.85, t));
+            return mix(color, vec3(0.95, 0.95, 0.97), smoothstep(0.85, 1.0, t));
+        }
 
         void main() {
-            f_color = texture(tex, txt_coord);
-            f_color = vec4(v_color, 1.0);
-            //t_color = texture(tex, txt_coord);
+            vec4 color = vec4(0.8, 0.8, 0.8, 1.0);
+            if ((shading.flags & TEXTURES) != 0) {
+                color = texture(tex, txt_coord);
+            } else if ((shading.flags & RAMP) != 0) {
+                float elevation = world_elevation;
+                float t = (elevation - shading.elevation.x)
+                    / max(shading.elevation.y - shading.elevation.x, 0.0001);
+                color = vec4(ramp(clamp(t, 0.0, 1.0)), 1.0);
+            }
+
+            if ((shading.flags & LIGHTING) != 0) {
+                vec3 normal;
+                if ((shading.flags & NORMAL_MAP) != 0) {
+                    // Stored z up, as the map is seen from above
+                    vec3 n = texture(normals, txt_coord).rgb * 2.0 - 1.0;
+                    normal = normalize(n.xzy);
+                } else {
+                    normal = normalize(vertex_normal);
+                }
+                float sun = max(dot(normal, normalize(shading.sun_dir.xyz)), 0.0);
+                color.rgb *= shading.ambient.rgb + shading.sun_intensity.rgb * sun;
+            }
+
+            if ((shading.flags & FOG) != 0) {
+                float visibility = exp(-shading.fog.a * view_dist);
+                color.rgb = mix(shading.fog.rgb, color.rgb, visibility);
+            }
+
+            f_color = color;
+        }
+    ",
+    types_meta: {
+        use bytemuck::{Pod, Zeroable};
+
+        #[derive(Clone, Copy, Default, Zeroable, Pod)]
+    }
+    }
+}
+
+/// Bits of `fs::ty::Shading::flags`, as in the fragment shader
+mod shading_flags {
+    pub const TEXTURES: u32 = 1;
+    pub const LIGHTING: u32 = 2;
+    pub const FOG: u32 = 4;
+    pub const RAMP: u32 = 8;
+    pub const NORMAL_MAP: u32 = 16;
+}
+
+/// What can be picked about the rendering at startup
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Tiles whose projected error is above this many pixels get refined
+    pub pixel_tolerance: f64,
+    /// Color the terrain with the color map if there's one, or with an
+    /// elevation ramp if there isn't
+    pub textures: bool,
+    pub lighting: bool,
+    /// `None` goes with what the map says
+    pub fog: Option<bool>,
+    pub wireframe: bool,
+    /// Where the camera starts, instead of the default
+    pub camera_pos: Option<Point3<f64>>,
+    /// What the camera starts looking at, instead of the default
+    pub camera_target: Option<Point3<f64>>,
+    /// Added to the mip level textures are sampled at; below 0 is sharper,
+    /// above 0 is blurrier
+    pub lod_bias: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            pixel_tolerance: Situation::DEFAULT_PIXEL_TOLERANCE,
+            textures: true,
+            lighting: true,
+            fog: None,
+            wireframe: false,
+            camera_pos: None,
+            camera_target: None,
+            lod_bias: 0.0,
+        }
+    }
+}
+
+impl Settings {
+    const DEFAULT_FOG_COLOR: [f32; 3] = [0.7, 0.75, 0.8];
+    const DEFAULT_FOG_DENSITY: f32 = 0.002;
+
+    fn shading(&self, map: &Map) -> fs::ty::Shading {
+        let [x, y, z] = map.info.sun_dir;
+        let [sr, sg, sb] = map.info.sun_intensity;
+        let [ar, ag, ab] = map.info.ambient_intensity;
+        let [fr, fg, fb] = map.info.fog_color.unwrap_or(Self::DEFAULT_FOG_COLOR);
+        let density = map.info.fog_density.unwrap_or(Self::DEFAULT_FOG_DENSITY);
+        let fog = self.fog.unwrap_or(map.info.has_fog.unwrap_or(false));
+
+        let mut flags = 0;
+        for (on, flag) in [
+            (self.textures && map.info.has_color, shading_flags::TEXTURES),
+            (self.textures && !map.info.has_color, shading_flags::RAMP),
+            (self.lighting, shading_flags::LIGHTING),
+            (map.info.has_normals, shading_flags::NORMAL_MAP),
+            (fog, shading_flags::FOG),
+        ] {
+            if on {
+                flags |= flag;
+            }
+        }
+
+        fs::ty::Shading {
+            sun_dir: [x, y, z, 0.0],
+            sun_intensity: [sr, sg, sb, 0.0],
+            ambient: [ar, ag, ab, 0.0],
+            fog: [fr, fg, fb, density],
+            elevation: [map.info.min_elevation, map.info.max_elevation, 0.0, 0.0],
+            flags,
         }
-    "
     }
 }
 
@@ -117,100 +313,226 @@ pub struct App {
     pub descriptor_set_allocator: StandardDescriptorSetAllocator,
     /// Memory allocator for buffers
     pub memory_allocator: GenericMemoryAllocator<Arc<FreeListAllocator>>,
-    /// The descriptor set that we needed; This field would change if additional
-    /// modes were to be added.
-    pub descriptor_set: Arc<PersistentDescriptorSet>,
+    /// The descriptor set that we needed; rebuilt along with the situation's
+    /// tile buffer, and `None` while nothing is selected.
+    pub descriptor_set: Option<Arc<PersistentDescriptorSet>>,
     /// The world buffer
     pub world_uniform_buffer: Arc<CpuAccessibleBuffer<vs::ty::WorldObject>>,
     /// The camera object, representing orientaiton and position of camera in the world
     pub camera: Camera,
     /// The object tracking the LODs; incomplete
     pub situation: Situation,
+    /// Lighting and fog parameters, and which of them are on
+    pub shading: fs::ty::Shading,
+    /// Counters for the last frame
+    pub stats: FrameStats,
+    /// When the last frame started
+    pub last_frame: Option<Instant>,
+    /// Where the stats go, if anywhere
+    pub reporter: Option<StatsReporter>,
 }
 
-/// The vertex buffers, index buffers, and textures associated with
-/// what is going to be drawn.
+/// What is going to be drawn: the tiles picked by the LOD selection, kept
+/// resident in a pool, and the buffers to draw them all with a single
+/// indirect draw.
 pub struct Situation {
-    vertex_buffers: Vec<Arc<CpuAccessibleBuffer<[HFVertex]>>>,
-    index_buffers: Vec<Arc<CpuAccessibleBuffer<[u16]>>>,
-    images: Vec<Arc<ImageView<ImmutableImage>>>,
+    /// Tiles whose projected error is above this many pixels get refined
+    pub pixel_tolerance: f64,
+    pool: TilePool,
+    /// Per-tile parameters, one per draw command
+    tiles: Option<Arc<CpuAccessibleBuffer<[vs::ty::TileInfo]>>>,
+    /// One draw command per selected tile
+    draws: Option<Arc<CpuAccessibleBuffer<[DrawIndexedIndirectCommand]>>>,
 }
 
 impl Situation {
+    const DEFAULT_PIXEL_TOLERANCE: f64 = 2.0;
+
     fn new(
+        map: &Map,
         memory_allocator: &GenericMemoryAllocator<Arc<FreeListAllocator>>,
-        tiles: Vec<&Tile>,
-        images: Vec<Arc<ImageView<ImmutableImage>>>,
-        _camera: &Camera,
+        queue_family_indices: &[u32],
     ) -> Self {
-        // let frustum = dbg!(dbg!(camera).frustum());
-        let level = tiles[0].level;
-        let chunks = tiles
-            .into_iter()
-            // .filter(
-            //     |tile| match frustum.intersect(tile.bbox.as_ref().unwrap()) {
-            //         crate::geometry::IntersectionStatus::Outside => false,
-            //         _ => true,
-            //     },
-            // )
-            .map(|tile| {
-                let pos = tile.bbox.as_ref().unwrap().max;
-                (&tile.chunk, [pos.x, pos.z])
-            })
-            .collect::<Vec<_>>();
+        // All the tiles of a map share their texture sizes, and BC tiles go
+        // up as they are where the device can sample them. Indices are only
+        // 32-bit if some chunk has too many vertices for 16
+        let compressed = memory_allocator
+            .device()
+            .enabled_features()
+            .texture_compression_bc;
+        let tiles = map.iter_cells().flat_map(|cell| cell.tree.iter());
+        let color_tiles = TextureTiles::of(
+            tiles.clone().filter_map(|tile| tile.texture.as_ref()),
+            compressed,
+        );
+        let normal_tiles = TextureTiles::of(
+            tiles.clone().filter_map(|tile| tile.normals.as_ref()),
+            compressed,
+        );
+        let wide_indices = tiles.map(|tile| &tile.chunk).any(Chunk::needs_wide_indices);
 
-        const COLORS: [[f32; 3]; 4] = [
-            [0.0, 1.0, 0.0],
-            [1.0, 0.0, 0.0],
-            [0.0, 0.0, 1.0],
-            [1.0, 1.0, 1.0],
-        ];
+        Self {
+            pixel_tolerance: Self::DEFAULT_PIXEL_TOLERANCE,
+            pool: TilePool::new(
+                memory_allocator,
+                queue_family_indices,
+                [
+                    map.info.h_scale as f64,
+                    map.info.v_scale as f64,
+                    map.info.h_scale as f64,
+                ],
+                TilePool::VERTEX_CAPACITY,
+                TilePool::INDEX_CAPACITY,
+                wide_indices,
+                color_tiles,
+                normal_tiles,
+                TilePool::TEXTURE_CAPACITY,
+            ),
+            tiles: None,
+            draws: None,
+        }
+    }
 
-        let vertex_buffers = chunks
-            .iter()
-            .enumerate()
-            .map(|(i, chunk)| {
-                let (chunk, chunk_pos) = chunk;
-                CpuAccessibleBuffer::from_iter(
+    /// Redo the LOD selection from the camera's point of view. Tiles that
+    /// aren't resident yet get recorded into `uploads`, and what was picked
+    /// is counted in `stats`. Returns the number of tiles uploaded.
+    fn update<L, A: CommandBufferAllocator>(
+        &mut self,
+        map: &Map,
+        camera: &Camera,
+        memory_allocator: &GenericMemoryAllocator<Arc<FreeListAllocator>>,
+        uploads: &mut AutoCommandBufferBuilder<L, A>,
+        stats: &mut FrameStats,
+    ) -> usize {
+        self.pool.next_generation();
+        stats.clear_selection();
+
+        let tolerance = self.pixel_tolerance;
+        let frame = map.frame();
+        let frustum = camera.frustum();
+        let mut n_uploaded = 0;
+        let mut selected = vec![];
+
+        // Tiles outside the view aren't refined, and are culled rather than
+        // drawn once picked
+        let visible = |tile: &Tile| {
+            !matches!(
+                frustum.intersect(tile.bbox.as_ref().unwrap()),
+                IntersectionStatus::Outside
+            )
+        };
+
+        for cell in map.iter_cells() {
+            let tiles = cell.tree.select(|tile| {
+                let dist = tile.bbox.as_ref().unwrap().distance_to_point(camera.pos);
+                visible(tile) && camera.screen_error(dist, tile.chunk.max_error as f64) > tolerance
+            });
+
+            for tile in tiles {
+                if !visible(tile) {
+                    stats.level_mut(tile.level).culled += 1;
+                    continue;
+                }
+                let id = TileId::new(cell.position, tile);
+                let was_resident = self.pool.is_resident(&id);
+                if !self.pool.request(
+                    id,
+                    &tile.chunk,
+                    tile.texture.as_ref(),
+                    tile.normals.as_ref(),
                     memory_allocator,
-                    BufferUsage {
-                        vertex_buffer: true,
-                        ..Default::default()
+                    uploads,
+                ) {
+                    stats.level_mut(tile.level).dropped += 1;
+                    continue;
+                }
+                if !was_resident {
+                    n_uploaded += 1;
+                }
+                stats.level_mut(tile.level).selected += 1;
+                stats.triangles += tile.chunk.n_triangles() as u64;
+
+                // The parent's error is about twice the tile's; morph all
+                // the way to the parent as the parent stops needing refinement
+                let morph = if tile.level == 0 {
+                    0.0
+                } else {
+                    let dist = tile.bbox.as_ref().unwrap().distance_to_point(camera.pos);
+                    let error = camera.screen_error(dist, tile.chunk.max_error as f64);
+                    (2.0 - 2.0 * error / tolerance).clamp(0.0, 1.0) as f32
+                };
+
+                let origin = frame.to_world(id.cell.into(), frame.tile_origin(tile.node()));
+                selected.push((id, origin.0 - camera.pos, morph));
+            }
+        }
+
+        // Tiles of this generation are never evicted, so their slots hold
+        // until the next selection
+        let (tiles, draws): (Vec<_>, Vec<_>) = selected
+            .into_iter()
+            .filter_map(|(id, origin, morph)| {
+                let resident = self.pool.get(&id)?;
+                let ([offset_x, offset_z], scale) = frame.tex_transform(id.node());
+                Some((
+                    vs::ty::TileInfo {
+                        offset_x: offset_x as f32,
+                        offset_z: offset_z as f32,
+                        scale: scale as f32,
+                        morph,
+                        layer: resident.layer,
+                        origin_x: origin.x as f32,
+                        origin_y: origin.y as f32,
+                        origin_z: origin.z as f32,
                     },
-                    false,
-                    chunk.vertices.iter().map(move |v| {
-                        let coords = [
-                            (v.position[0] - chunk_pos[0] as f32) / 2i32.pow(5 * 2 - level) as f32,
-                            (v.position[2] - chunk_pos[1] as f32) / 2i32.pow(5 * 2 - level) as f32,
-                        ];
-                        v.with_color_and_coords(COLORS[i % 4], coords)
-                    }),
-                )
-                .unwrap()
+                    DrawIndexedIndirectCommand {
+                        index_count: resident.indices.len() as u32,
+                        instance_count: 1,
+                        first_index: resident.indices.start,
+                        vertex_offset: resident.vertices.start,
+                        first_instance: 0,
+                    },
+                ))
             })
-            .collect();
-
-        let index_buffers = chunks
-            .iter()
-            .map(|(chunk, _)| {
-                CpuAccessibleBuffer::from_iter(
-                    memory_allocator,
-                    BufferUsage {
-                        index_buffer: true,
-                        ..Default::default()
+            .enumerate()
+            .map(|(i, (tile, draw))| {
+                (
+                    tile,
+                    DrawIndexedIndirectCommand {
+                        first_instance: i as u32,
+                        ..draw
                     },
-                    false,
-                    chunk.indices.iter().copied(),
                 )
-                .unwrap()
             })
-            .collect();
+            .unzip();
 
-        Self {
-            vertex_buffers,
-            index_buffers,
-            images,
-        }
+        self.tiles = (!tiles.is_empty()).then(|| {
+            CpuAccessibleBuffer::from_iter(
+                memory_allocator,
+                BufferUsage {
+                    storage_buffer: true,
+                    ..Default::default()
+                },
+                false,
+                tiles,
+            )
+            .unwrap()
+        });
+
+        self.draws = (!draws.is_empty()).then(|| {
+            CpuAccessibleBuffer::from_iter(
+                memory_allocator,
+                BufferUsage {
+                    indirect_buffer: true,
+                    ..Default::default()
+                },
+                false,
+                draws,
+            )
+            .unwrap()
+        });
+
+        n_uploaded
     }
 }
 
@@ -221,10 +543,16 @@ pub enum SwapchainState {
 }
 
 impl App {
-    pub fn new(window_state: WindowState, map: Map) -> Self {
+    pub fn new(window_state: WindowState, map: Map, settings: &Settings) -> Self {
         let memory_allocator = StandardMemoryAllocator::new_default(window_state.device.clone());
 
-        let camera = Camera::default();
+        let mut camera = Camera::default();
+        if let Some(pos) = settings.camera_pos {
+            camera.move_to(pos);
+        }
+        if let Some(target) = settings.camera_target {
+            camera.target = target;
+        }
 
         let world_uniform_buffer = CpuAccessibleBuffer::from_data(
             &memory_allocator,
@@ -233,19 +561,33 @@ impl App {
                 ..Default::default()
             },
             false,
-            camera.world_object(map.scale()),
+            world_object(&camera, &map.frame()),
         )
         .unwrap();
 
         let vs = vs::load(window_state.device.clone()).unwrap();
         let fs = fs::load(window_state.device.clone()).unwrap();
 
+        // Tiles don't wrap, so clamp them to their edges, and filter
+        // anisotropically if the device was up for it
+        let device = &window_state.device;
+        let properties = device.physical_device().properties();
         let sampler = Sampler::new(
-            window_state.device.clone(),
+            device.clone(),
             SamplerCreateInfo {
                 mag_filter: Filter::Linear,
                 min_filter: Filter::Linear,
-                address_mode: [SamplerAddressMode::Repeat; 3],
+                mipmap_mode: SamplerMipmapMode::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                mip_lod_bias: settings.lod_bias.clamp(
+                    -properties.max_sampler_lod_bias,
+                    properties.max_sampler_lod_bias,
+                ),
+                anisotropy: device
+                    .enabled_features()
+                    .sampler_anisotropy
+                    .then_some(properties.max_sampler_anisotropy),
+                lod: 0.0..=LOD_CLAMP_NONE,
                 ..Default::default()
             },
         )
@@ -259,11 +601,17 @@ impl App {
                     store: Store,
                     format: window_state.swapchain.image_format(),
                     samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: DontCare,
+                    format: Format::D16_UNORM,
+                    samples: 1,
                 }
             },
             pass: {
                 color: [color],
-                depth_stencil: {}
+                depth_stencil: {depth}
             }
         )
         .unwrap();
@@ -276,70 +624,40 @@ impl App {
 
         let pipeline = GraphicsPipeline::start()
             .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-            .vertex_input_state(BuffersDefinition::new().vertex::<HFVertex>())
+            .vertex_input_state(tile_pool::vertex_input_state())
             .vertex_shader(vs.entry_point("main").unwrap(), ())
             .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
             .fragment_shader(fs.entry_point("main").unwrap(), ())
-            .input_assembly_state(InputAssemblyState {
-                topology: vulkano::pipeline::PartialStateMode::Fixed(
-                    PrimitiveTopology::TriangleStrip,
-                ),
-                ..Default::default()
-            })
+            .input_assembly_state(
+                InputAssemblyState::new()
+                    .topology(PrimitiveTopology::TriangleStrip)
+                    .primitive_restart_enable(),
+            )
             .rasterization_state(RasterizationState {
-                polygon_mode: PolygonMode::Line,
+                polygon_mode: if settings.wireframe {
+                    PolygonMode::Line
+                } else {
+                    PolygonMode::Fill
+                },
                 ..Default::default()
             })
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
             .with_auto_layout(window_state.device.clone(), |layout_create_infos| {
-                let create_info = &mut layout_create_infos[1];
-                let binding = create_info.bindings.get_mut(&0).unwrap();
-                create_info.push_descriptor = true;
-                binding.immutable_samplers = vec![sampler];
+                for binding in [2, 3] {
+                    let binding = layout_create_infos[0].bindings.get_mut(&binding).unwrap();
+                    binding.immutable_samplers = vec![sampler.clone()];
+                }
             })
             // .build(window_state.device.clone())
             .unwrap();
 
-        let mut uploads = AutoCommandBufferBuilder::primary(
-            &command_buffer_allocator,
-            window_state.queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-
-        let cell = &map.cells[0][0];
-        let tiles = cell.tree.items_at_level(0);
-
-        let images = tiles
-            .iter()
-            .map(|tile| {
-                let texture = tile.texture.as_ref().unwrap();
-                let image = ImmutableImage::from_iter(
-                    &memory_allocator,
-                    texture.image.clone(),
-                    ImageDimensions::Dim2d {
-                        width: texture.size,
-                        height: texture.size,
-                        array_layers: 1,
-                    },
-                    vulkano::image::MipmapsCount::One,
-                    Format::R8G8B8A8_SRGB,
-                    &mut uploads,
-                )
-                .unwrap();
-
-                ImageView::new_default(image).unwrap()
-            })
-            .collect();
-
-        let situation = Situation::new(&memory_allocator, tiles, images, &camera);
-
-        let layout = pipeline.layout().set_layouts().get(0).unwrap();
-        let descriptor_set = PersistentDescriptorSet::new(
-            &descriptor_set_allocator,
-            layout.clone(),
-            [WriteDescriptorSet::buffer(0, world_uniform_buffer.clone())],
-        )
-        .unwrap();
+        let mut situation = Situation::new(
+            &map,
+            &memory_allocator,
+            &window_state.queue_family_indices(),
+        );
+        situation.pixel_tolerance = settings.pixel_tolerance;
+        let shading = settings.shading(&map);
 
         let mut viewport = Viewport {
             origin: [0.0, 0.0],
@@ -348,21 +666,17 @@ impl App {
         };
 
         let framebuffers = _window_size_dependent_setup(
+            &memory_allocator,
             &window_state.swapchain_images,
             render_pass.clone(),
             &mut viewport,
         );
 
-        let previous_frame_end = Some(
-            uploads
-                .build()
-                .unwrap()
-                .execute(window_state.queue.clone())
-                .unwrap()
-                .boxed(),
-        );
+        camera.set_viewport(viewport.dimensions[0] as i64, viewport.dimensions[1] as i64);
 
-        Self {
+        let previous_frame_end = Some(sync::now(window_state.device.clone()).boxed());
+
+        let mut app = Self {
             map,
             window_state,
             previous_frame_end,
@@ -373,24 +687,99 @@ impl App {
             command_buffer_allocator,
             descriptor_set_allocator,
             memory_allocator,
-            descriptor_set,
+            descriptor_set: None,
             world_uniform_buffer,
             camera,
             situation,
-        }
+            shading,
+            stats: FrameStats::default(),
+            last_frame: None,
+            reporter: None,
+        };
+
+        app.camera_updated();
+        app
     }
 
     /// Signal that the camera has been updated
     pub fn camera_updated(&mut self) {
         if let Ok(mut world) = self.world_uniform_buffer.write() {
-            *world = self.camera.world_object(self.map.scale())
+            *world = world_object(&self.camera, &self.map.frame())
         }
 
-        // self.situation = Situation::new(
-        //     &self.memory_allocator,
-        //     self.map.cells[0][0].tree.items_at_level(1),
-        //     &self.camera,
-        // )
+        let window = self.window_state.surface.object().unwrap();
+        if let Some(window) = window.downcast_ref::<Window>() {
+            window.set_title(&format!(
+                "{} - {}",
+                self.map.info.name,
+                self.position_readout()
+            ));
+        }
+
+        self.update_situation();
+    }
+
+    /// Where the camera is, on Earth if the map knows where that is
+    pub fn position_readout(&self) -> String {
+        let pos = self.camera.pos;
+        match self.map.geo_pos(WorldPos(pos)) {
+            Some(geo) => geo.to_string(),
+            None => format!("{:.1}, {:.1}, {:.1}", pos.x, pos.y, pos.z),
+        }
+    }
+
+    /// Redo the LOD selection and submit whatever it needs uploaded
+    fn update_situation(&mut self) {
+        let mut uploads = AutoCommandBufferBuilder::primary(
+            &self.command_buffer_allocator,
+            self.window_state.transfer_queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let n_uploaded = self.situation.update(
+            &self.map,
+            &self.camera,
+            &self.memory_allocator,
+            &mut uploads,
+            &mut self.stats,
+        );
+        self.stats.selection_time = start.elapsed();
+        self.stats.upload_bytes += self.situation.pool.take_uploaded_bytes();
+
+        self.descriptor_set = self.situation.tiles.as_ref().map(|tiles| {
+            let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+            PersistentDescriptorSet::new(
+                &self.descriptor_set_allocator,
+                layout.clone(),
+                [
+                    WriteDescriptorSet::buffer(0, self.world_uniform_buffer.clone()),
+                    WriteDescriptorSet::buffer(1, tiles.clone()),
+                    WriteDescriptorSet::image_view(2, self.situation.pool.color_array().clone()),
+                    WriteDescriptorSet::image_view(3, self.situation.pool.normal_array().clone()),
+                ],
+            )
+            .unwrap()
+        });
+
+        if n_uploaded == 0 {
+            return;
+        }
+
+        // Chained after the previous frame, so that slots freed by evictions
+        // aren't overwritten while they're still being drawn from
+        let future = self
+            .previous_frame_end
+            .take()
+            .unwrap()
+            .then_execute(
+                self.window_state.transfer_queue.clone(),
+                uploads.build().unwrap(),
+            )
+            .unwrap();
+
+        self.previous_frame_end = Some(future.boxed());
     }
 
     pub fn recreate_swapchain(&mut self) {
@@ -416,11 +805,25 @@ impl App {
             };
 
         self.window_state.swapchain = new_swapchain;
-        self.framebuffers =
-            _window_size_dependent_setup(&new_images, self.render_pass.clone(), &mut self.viewport);
+        self.framebuffers = _window_size_dependent_setup(
+            &self.memory_allocator,
+            &new_images,
+            self.render_pass.clone(),
+            &mut self.viewport,
+        );
+        self.camera.set_viewport(
+            self.viewport.dimensions[0] as i64,
+            self.viewport.dimensions[1] as i64,
+        );
+        self.camera_updated();
     }
 
     pub fn draw(&mut self) -> SwapchainState {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame.replace(now) {
+            self.stats.frame_time = now - last_frame;
+        }
+
         let mut state = SwapchainState::Good;
         let (image_index, suboptimal, acquire_future) =
             match acquire_next_image(self.window_state.swapchain.clone(), None) {
@@ -442,10 +845,18 @@ impl App {
         )
         .unwrap();
 
+        // Things fade into the fog, so the background has to be the fog too
+        let clear_color = if self.shading.flags & shading_flags::FOG != 0 {
+            let [r, g, b, _] = self.shading.fog;
+            [r, g, b, 1.0]
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+
         builder
             .begin_render_pass(
                 RenderPassBeginInfo {
-                    clear_values: vec![Some([0.0, 0.0, 0.0, 1.0].into())],
+                    clear_values: vec![Some(clear_color.into()), Some(1.0.into())],
                     ..RenderPassBeginInfo::framebuffer(
                         self.framebuffers[image_index as usize].clone(),
                     )
@@ -456,30 +867,18 @@ impl App {
             .bind_pipeline_graphics(self.pipeline.clone())
             .set_viewport(0, [self.viewport.clone()]);
 
-        for ((vertex_buffer, index_buffer), image) in self
-            .situation
-            .vertex_buffers
-            .iter()
-            .zip(self.situation.index_buffers.iter())
-            .zip(self.situation.images.iter())
-        {
+        if let (Some(descriptor_set), Some(draws)) = (&self.descriptor_set, &self.situation.draws) {
             builder
-                .push_descriptor_set(
-                    PipelineBindPoint::Graphics,
-                    self.pipeline.layout().clone(),
-                    1,
-                    [WriteDescriptorSet::image_view(0, image.clone())],
-                )
-                .bind_vertex_buffers(0, vertex_buffer.clone())
-                .bind_index_buffer(index_buffer.clone())
                 .bind_descriptor_sets(
                     PipelineBindPoint::Graphics,
                     self.pipeline.layout().clone(),
                     0,
-                    self.descriptor_set.clone(),
+                    descriptor_set.clone(),
                 )
-                .draw_indexed(index_buffer.len() as u32, 1, 0, 0, 0)
-                .unwrap();
+                .push_constants(self.pipeline.layout().clone(), 0, self.shading);
+            self.situation.pool.bind_vertex_buffers(&mut builder);
+            self.situation.pool.bind_index_buffer(&mut builder);
+            builder.draw_indexed_indirect(draws.clone()).unwrap();
         }
         builder.end_render_pass().unwrap();
 
@@ -512,11 +911,29 @@ impl App {
                 panic!("Failed to flush future: {:?}", e);
             }
         }
+        self.stats.resident_vertex_bytes = self.situation.pool.resident_vertex_bytes();
+        self.stats.resident_index_bytes = self.situation.pool.resident_index_bytes();
+        self.stats.resident_texture_bytes = self.situation.pool.resident_texture_bytes();
+        if let Some(reporter) = self.reporter.as_mut() {
+            reporter.report(&self.stats);
+        }
+        self.stats.upload_bytes = 0;
+
         return state;
     }
 }
 
+fn world_object(camera: &Camera, frame: &Frame) -> vs::ty::WorldObject {
+    let (h_scale, v_scale) = (frame.h_scale as f32, frame.v_scale as f32);
+    vs::ty::WorldObject {
+        view: camera.relative_view_transform().cast::<f32>().into(),
+        proj: camera.proj_transform().cast::<f32>().into(),
+        scale: [h_scale, v_scale, h_scale, camera.pos.y as f32],
+    }
+}
+
 fn _window_size_dependent_setup(
+    memory_allocator: &StandardMemoryAllocator,
     images: &[Arc<SwapchainImage>],
     render_pass: Arc<RenderPass>,
     viewport: &mut Viewport,
@@ -524,6 +941,11 @@ fn _window_size_dependent_setup(
     let dimensions = images[0].dimensions().width_height();
     viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
 
+    let depth_buffer = ImageView::new_default(
+        AttachmentImage::transient(memory_allocator, dimensions, Format::D16_UNORM).unwrap(),
+    )
+    .unwrap();
+
     images
         .iter()
         .map(|image| {
@@ -531,7 +953,7 @@ fn _window_size_dependent_setup(
             Framebuffer::new(
                 render_pass.clone(),
                 FramebufferCreateInfo {
-                    attachments: vec![view],
+                    attachments: vec![view, depth_buffer.clone()],
                     ..Default::default()
                 },
             )
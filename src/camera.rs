@@ -1,7 +1,7 @@
 use bytemuck::{Pod, Zeroable};
 use nalgebra::{Matrix4, OPoint, Perspective3, Point3, Vector3};
 
-use crate::{app::vs, geometry::Frustum};
+use crate::geometry::Frustum;
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Zeroable, Pod)]
@@ -38,8 +38,14 @@ impl Camera {
         Frustum::new(self)
     }
 
+    /// Back to the default position, keeping what was derived from the viewport
     pub fn reset(&mut self) {
-        *self = Camera::default();
+        *self = Camera {
+            asepect_ratio: self.asepect_ratio,
+            error_factor: self.error_factor,
+            width: self.width,
+            ..Camera::default()
+        };
     }
 
     pub fn situate(&mut self, p: Point3<f64>) -> Vector3<f64> {
@@ -58,6 +64,14 @@ impl Camera {
         Matrix4::look_at_lh(&self.pos, &self.target, &self.up())
     }
 
+    /// The view transform with the camera at the origin, for geometry that
+    /// is already relative to it. Far from the world's origin that keeps
+    /// what gets to the GPU small enough for f32
+    pub fn relative_view_transform(&self) -> Matrix4<f64> {
+        let eye = Point3::origin();
+        Matrix4::look_at_lh(&eye, &(eye + (self.target - self.pos)), &self.up())
+    }
+
     pub fn proj_transform(&self) -> Matrix4<f64> {
         Perspective3::new(
             self.asepect_ratio,
@@ -122,16 +136,6 @@ impl Camera {
         self.error_factor * (err / dist)
     }
 
-    pub fn world_object(&self, scale: [f32; 3]) -> vs::ty::WorldObject {
-        vs::ty::WorldObject {
-            model: Matrix4::new_nonuniform_scaling(&scale.into())
-                .append_translation(&Vector3::zeros())
-                .into(),
-            view: self.view_transform().cast::<f32>().into(),
-            proj: self.proj_transform().cast::<f32>().into(),
-        }
-    }
-
     pub fn move_up(&mut self) {
         self.shift_by(-self.stride() * self.up())
     }
@@ -181,3 +185,24 @@ impl Camera {
         self.make_up(rot * self.up);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use nalgebra::Point3;
+
+    use super::Camera;
+
+    #[test]
+    fn relative_view_matches() {
+        let mut camera = Camera::default();
+        camera.move_to(Point3::new(3.0e6, 250.0, 7.5e6));
+        camera.target = Point3::new(3.0e6 + 40.0, 200.0, 7.5e6 - 30.0);
+
+        let p = Point3::new(3.0e6 + 12.5, 180.0, 7.5e6 + 3.25);
+        let absolute = camera.view_transform().transform_point(&p);
+        let relative = camera
+            .relative_view_transform()
+            .transform_point(&(Point3::origin() + (p - camera.pos)));
+        assert!((absolute - relative).norm() < 1e-6);
+    }
+}
@@ -1,44 +1,79 @@
 use std::{
     fs::File,
-    io::{BufReader, Read},
+    io::{BufReader, BufWriter, Read, Seek, Write},
     path::Path,
+    sync::Mutex,
 };
 
 use nalgebra::Point3;
+use rayon::prelude::*;
 
 use crate::{
-    disk_util::read_value,
+    coords::Frame,
+    disk_util::{read_value, stream_len, ReadAt},
     map::Map,
-    quadtree::{util::full_size, QuadTree},
+    quadtree::{
+        util::{full_size, tree_position},
+        QuadTree,
+    },
     texture_quadtree::TexturedQuadTree,
 };
 
+use self::chunk::{Chunk, ChunkFormat};
+
 struct CellHeader {
-    magic: u32,
-    compressed: bool,
+    version: u32,
+    flags: u32,
     size: u32,
     depth: u32,
 }
 
 impl CellHeader {
+    /// Magic, compressed flag, size and depth
+    const V1_SIZE: u64 = 16;
+    /// Magic, version, flags, size and depth
+    const V2_SIZE: u64 = 20;
+
     fn read_from<R: Read>(reader: &mut BufReader<R>) -> Result<Self, &'static str> {
         let mut magic = 0u32;
-        let mut compressed = 0u32;
+        let mut version = 1u32;
+        let mut flags = 0u32;
         let mut size = 0u32;
         let mut depth = 0u32;
 
         read_value(reader, &mut magic, "Unable to read magic no.")?;
-        read_value(reader, &mut compressed, "Unable to read compressed flag")?;
+        match magic {
+            // Version 1 files have no version, just a flag that's never set
+            Cell::MAGIC => {
+                let mut compressed = 0u32;
+                read_value(reader, &mut compressed, "Unable to read compressed flag")?;
+                if compressed != 0 {
+                    return Err("Compressed cells are not supported yet");
+                }
+            }
+            Cell::MAGIC_V2 => {
+                read_value(reader, &mut version, "Unable to read version no.")?;
+                read_value(reader, &mut flags, "Unable to read flags")?;
+            }
+            _ => return Err("Invalid magic no."),
+        }
         read_value(reader, &mut size, "Unable to read size")?;
         read_value(reader, &mut depth, "Unable to read depth")?;
 
         Ok(Self {
-            magic,
-            compressed: compressed != 0,
+            version,
+            flags,
             size,
             depth,
         })
     }
+
+    fn len(&self) -> u64 {
+        match self.version {
+            1 => Self::V1_SIZE,
+            _ => Self::V2_SIZE,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -47,13 +82,17 @@ pub struct Cell {
     pub depth: u32,
     pub tree: QuadTree<tile::Tile>,
 
-    pub worldly_width: Option<f64>,
+    /// Set when put in map
+    pub frame: Option<Frame>,
 }
 
 impl Cell {
     const MAGIC: u32 = 0x63656C6C;
+    /// Version 2 files say so right away, since version 1 has no version
+    const MAGIC_V2: u32 = 0x63656C32;
+    const VERSION: u32 = 2;
     const MIN_DEPTH: u32 = 1;
-    const MAX_DEPTH: u32 = 9;
+    pub const MAX_DEPTH: u32 = 9;
 
     pub fn new<P: AsRef<Path>>(
         path: P,
@@ -62,23 +101,79 @@ impl Cell {
         normal_tqt: Option<TexturedQuadTree>,
         cell_width: u32,
     ) -> Result<Self, &'static str> {
+        Self::load(path, position, color_tqt, normal_tqt, cell_width, &|| ())
+    }
+
+    /// Like `new`, calling `on_tile` after each chunk is decoded, from
+    /// whichever thread decoded it
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        position: (u32, u32),
+        color_tqt: Option<TexturedQuadTree>,
+        normal_tqt: Option<TexturedQuadTree>,
+        cell_width: u32,
+        on_tile: &(dyn Fn() + Sync),
+    ) -> Result<Self, &'static str> {
+        let file = File::open(path).map_err(|_| "Unable to open cell file")?;
+        let offsets = Self::read_offsets(&mut BufReader::new(&file), cell_width)?;
+
+        // The chunks are read straight from the file, each at its offset
+        Self::decode(
+            &file, position, offsets, color_tqt, normal_tqt, cell_width, on_tile,
+        )
+    }
+
+    /// How many tiles the file at `path` has, from its offsets alone
+    pub fn count_tiles<P: AsRef<Path>>(path: P, cell_width: u32) -> Result<usize, &'static str> {
         let file = File::open(path).map_err(|_| "Unable to open cell file")?;
-        let mut reader = BufReader::new(file);
+        let (_, _, offsets, _) = Self::read_offsets(&mut BufReader::new(file), cell_width)?;
+
+        Ok(offsets.iter().filter(|&&offset| offset != 0).count())
+    }
+
+    /// Read a cell from anything; nothing in it is trusted, so a broken or
+    /// hostile file gives an error rather than a panic or a huge allocation
+    pub fn read_from<R: Read + Seek + Send>(
+        reader: &mut BufReader<R>,
+        position: (u32, u32),
+        color_tqt: Option<TexturedQuadTree>,
+        normal_tqt: Option<TexturedQuadTree>,
+        cell_width: u32,
+    ) -> Result<Self, &'static str> {
+        let offsets = Self::read_offsets(reader, cell_width)?;
+
+        Self::decode(
+            &Mutex::new(reader),
+            position,
+            offsets,
+            color_tqt,
+            normal_tqt,
+            cell_width,
+            &|| (),
+        )
+    }
 
+    /// The checked depth, how the chunks are stored, their offsets, and
+    /// the length of the file
+    fn read_offsets<R: Read + Seek>(
+        reader: &mut BufReader<R>,
+        cell_width: u32,
+    ) -> Result<(u32, ChunkFormat, Vec<u64>, u64), &'static str> {
+        let file_len = stream_len(reader)?;
+
+        let header = CellHeader::read_from(reader)?;
         let CellHeader {
-            magic,
-            compressed,
+            version,
+            flags,
             size,
             depth,
-        } = CellHeader::read_from(&mut reader)?;
+        } = header;
 
-        if magic != Self::MAGIC {
-            return Err("Invalid magic no.");
+        if version != 1 && version != Self::VERSION {
+            return Err("Invalid version no.");
         }
 
-        if compressed {
-            return Err("Compressed cells are not supported yet");
-        }
+        let format = ChunkFormat::from_flags(version, flags)?;
 
         if size != cell_width {
             return Err("Cell size does not match map cell size");
@@ -89,26 +184,59 @@ impl Cell {
         }
 
         let n_tiles = full_size(depth) as usize;
+        if header.len() + 8 * n_tiles as u64 > file_len {
+            return Err("Cell file too short for its offsets");
+        }
+
         let mut offsets: Vec<u64> = vec![0; n_tiles];
-        for i in 0..n_tiles {
-            read_value(&mut reader, &mut offsets[i], "Unable to read offset")?;
+        for offset in offsets.iter_mut() {
+            read_value(reader, offset, "Unable to read offset")?;
         }
 
-        let mut lod = QuadTree::read_from(&mut reader, depth, cell_width, &offsets)?;
+        Ok((depth, format, offsets, file_len))
+    }
 
-        if let Some(mut textures) = color_tqt {
-            for (tile, texture) in lod
-                .mut_view()
-                .iter_mut()
-                .zip(textures.lod.mut_view().iter())
-            {
-                tile.texture = Some((*texture).clone());
+    /// Decode the chunks, in parallel, and give them their textures
+    fn decode(
+        source: &impl ReadAt,
+        position: (u32, u32),
+        (depth, format, offsets, file_len): (u32, ChunkFormat, Vec<u64>, u64),
+        color_tqt: Option<TexturedQuadTree>,
+        normal_tqt: Option<TexturedQuadTree>,
+        cell_width: u32,
+        on_tile: &(dyn Fn() + Sync),
+    ) -> Result<Self, &'static str> {
+        let mut lod = QuadTree::decode(
+            source, file_len, depth, cell_width, format, &offsets, on_tile,
+        )?;
+
+        for tqt in color_tqt.iter().chain(normal_tqt.iter()) {
+            if tqt.depth != depth {
+                return Err("Texture depth does not match cell depth");
+            }
+        }
+
+        // The textures move into the tiles. Sparse trees may stop at
+        // different places, but every tile needs its textures
+        if let Some(textures) = color_tqt {
+            for (id, texture) in textures.lod.into_nodes() {
+                if let Some(tile) = lod.get_mut(id.level, id.row, id.col) {
+                    tile.texture = Some(texture);
+                }
+            }
+            if lod.iter().any(|tile| tile.texture.is_none()) {
+                return Err("Color texture missing for a tile");
             }
         }
 
-        if let Some(mut normals) = normal_tqt {
-            for (tile, normal) in lod.mut_view().iter_mut().zip(normals.lod.mut_view().iter()) {
-                tile.normals = Some((*normal).clone());
+        if let Some(normals) = normal_tqt {
+            for (id, normal) in normals.lod.into_nodes() {
+                if let Some(tile) = lod.get_mut(id.level, id.row, id.col) {
+                    tile.normals = Some(normal);
+                }
+            }
+            if lod.iter().any(|tile| tile.normals.is_none()) {
+                return Err("Normal map missing for a tile");
             }
         }
 
@@ -117,31 +245,95 @@ impl Cell {
             depth,
             tree: lod,
 
-            worldly_width: None,
+            frame: None,
         })
     }
 
+    /// Save the cell's geometry to `path` as a version 2 file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), &'static str> {
+        let file = File::create(path).map_err(|_| "Unable to create cell file")?;
+        let mut writer = BufWriter::new(file);
+
+        self.write_to(&mut writer)?;
+        writer.flush().map_err(|_| "Unable to write cell file")
+    }
+
+    /// Write the cell's geometry as a version 2 file. Indices are 32-bit
+    /// only if some chunk needs them; normals are written if every chunk
+    /// has them, and it's an error if only some do.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), &'static str> {
+        let chunks = || self.tree.iter().map(|tile| &tile.chunk);
+        let format = ChunkFormat {
+            version: Self::VERSION,
+            wide_indices: chunks().any(Chunk::needs_wide_indices),
+            normals: chunks().any(|chunk| chunk.normals.is_some()),
+        };
+        if format.normals && chunks().any(|chunk| chunk.normals.is_none()) {
+            return Err("Either every chunk has normals or none does");
+        }
+
+        // Chunks go in tree order, like their offsets
+        let depth = self.tree.depth();
+        let encoded = (0..full_size(depth))
+            .into_par_iter()
+            .map(|index| {
+                let (level, row, col) = tree_position(index);
+                self.tree
+                    .get(level, row, col)
+                    .map(|tile| tile.chunk.encode(format))
+            })
+            .collect::<Vec<_>>();
+
+        let header = [
+            Self::MAGIC_V2,
+            Self::VERSION,
+            format.flags(),
+            self.tree.root().size,
+            depth,
+        ];
+        let mut offset = CellHeader::V2_SIZE + 8 * encoded.len() as u64;
+        let offsets = encoded.iter().map(|chunk| match chunk {
+            Some(data) => {
+                let at = offset;
+                offset += data.len() as u64;
+                at
+            }
+            None => 0,
+        });
+
+        let bytes = header
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .chain(offsets.flat_map(u64::to_le_bytes))
+            .collect::<Vec<_>>();
+        writer
+            .write_all(&bytes)
+            .map_err(|_| "Unable to write cell file")?;
+        for data in encoded.iter().flatten() {
+            writer
+                .write_all(data)
+                .map_err(|_| "Unable to write cell file")?;
+        }
+
+        Ok(())
+    }
+
     pub fn is_in_map(&self) -> bool {
-        self.worldly_width.is_some()
+        self.frame.is_some()
     }
 
     pub fn put_in_map(&mut self, map: &Map) {
-        self.worldly_width = Some(map.world_cell_width());
-
-        let pos = self.corner_world_position();
+        let frame = map.frame();
+        self.frame = Some(frame);
 
-        for tile in self.tree.mut_view() {
-            tile.put_in_map_in_cell(pos, map);
+        for tile in self.tree.iter_mut() {
+            tile.put_in_map_in_cell(self.position.into(), &frame);
         }
     }
 
     pub fn corner_world_position(&self) -> Point3<f64> {
-        match self.worldly_width {
-            Some(width) => Point3::new(
-                width * self.position.1 as f64,
-                0.0,
-                width * self.position.0 as f64,
-            ),
+        match self.frame {
+            Some(frame) => frame.cell_origin(self.position.into()).0,
 
             None => panic!("Put the cell in a map first!"),
         }
@@ -149,21 +341,22 @@ impl Cell {
 }
 
 pub mod tile {
-    use std::io::{BufReader, Read, Seek};
+    use std::io::BufReader;
 
-    use nalgebra::{Point3, Vector3};
+    use rayon::prelude::*;
 
     use crate::{
+        coords::{CellPos, Frame},
+        disk_util::{Extents, ReadAt, ReaderAt},
         geometry::AABB,
-        map::Map,
         quadtree::{
-            util::{full_size, node_index},
-            QuadTree,
+            util::{full_size, node_position, tree_index},
+            NodeId, QuadTree,
         },
         texture_quadtree::Texture,
     };
 
-    use super::chunk::Chunk;
+    use super::chunk::{Chunk, ChunkFormat};
 
     #[derive(Debug, Clone)]
     pub struct Tile {
@@ -179,64 +372,93 @@ pub mod tile {
         pub bbox: Option<AABB<f64>>,
     }
 
+    /// Identifies a tile across the whole map: the cell it belongs to and
+    /// where it is in that cell's tree
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct TileId {
+        pub cell: (u32, u32),
+        pub level: u32,
+        pub row: u32,
+        pub col: u32,
+    }
+
+    impl TileId {
+        pub fn new(cell: (u32, u32), tile: &Tile) -> Self {
+            Self::in_cell(cell, tile.node())
+        }
+
+        pub fn in_cell(cell: (u32, u32), node: NodeId) -> Self {
+            Self {
+                cell,
+                level: node.level,
+                row: node.row,
+                col: node.col,
+            }
+        }
+
+        /// Where the tile is in its cell's tree
+        pub fn node(&self) -> NodeId {
+            NodeId::new(self.level, self.row, self.col)
+        }
+    }
+
     impl Tile {
+        pub fn node(&self) -> NodeId {
+            NodeId::new(self.level, self.position.0, self.position.1)
+        }
+
         pub fn is_in_map(&self) -> bool {
             self.bbox.is_some()
         }
 
-        pub fn put_in_map_in_cell(&mut self, cell_world_pos: Point3<f64>, map: &Map) {
-            let tile_nw_pos = cell_world_pos
-                + Vector3::new(
-                    map.info.h_scale as f64 * self.position.1 as f64,
-                    map.info.base_elevation as f64
-                        + map.info.v_scale as f64 * self.chunk.min_y as f64,
-                    map.info.h_scale as f64 * self.position.0 as f64,
-                );
-
-            let tile_worldly_width =
-                map.info.h_scale as f64 * (map.info.cell_width >> self.level) as f64;
-            let mut tile_se_pos =
-                tile_nw_pos + Vector3::new(tile_worldly_width, 0.0, tile_worldly_width);
-            tile_se_pos.y =
-                map.info.base_elevation as f64 + map.info.v_scale as f64 * self.chunk.max_y as f64;
-
-            self.bbox = Some(AABB::new(tile_nw_pos, tile_se_pos));
+        pub fn put_in_map_in_cell(&mut self, cell: CellPos, frame: &Frame) {
+            self.bbox =
+                Some(frame.tile_bounds(cell, self.node(), self.chunk.min[1], self.chunk.max[1]));
         }
     }
 
     impl QuadTree<Tile> {
-        pub fn read_from<R: Read + Seek>(
-            reader: &mut BufReader<R>,
+        /// Decode the chunks of a file `file_len` bytes long, in parallel
+        pub fn decode(
+            source: &impl ReadAt,
+            file_len: u64,
             depth: u32,
             cell_size: u32,
+            format: ChunkFormat,
             offsets: &[u64],
+            on_tile: &(dyn Fn() + Sync),
         ) -> Result<Self, &'static str> {
-            let mut tiles = Vec::with_capacity(full_size(depth) as usize);
-
-            for level in 0..depth {
-                let n = 2usize.pow(level);
-
-                for row in 0..n as u32 {
-                    for col in 0..n as u32 {
-                        let chunk = Chunk::read_from(
-                            reader,
-                            offsets[node_index(level, row, col) as usize],
-                        )?;
-
-                        tiles.push(Tile {
-                            chunk,
-                            position: (row, col),
-                            level,
-                            size: cell_size >> level,
-                            bbox: None,
-                            texture: None,
-                            normals: None,
-                        });
+            let extents = Extents::new(offsets, file_len)?;
+
+            // Chunks are stored in tree order, but the tree keeps them row
+            // by row. An offset of 0 means there is no finer data there, the
+            // parent is a leaf
+            let tiles = (0..full_size(depth))
+                .into_par_iter()
+                .map(|index| {
+                    let (level, row, col) = node_position(index);
+                    let offset = offsets[tree_index(level, row, col) as usize];
+                    if offset == 0 {
+                        return Ok(None);
                     }
-                }
-            }
+                    // Each chunk gets its own reader into the file
+                    let mut reader = BufReader::new(ReaderAt::new(source, 0, file_len));
+                    let chunk = Chunk::read_from(&mut reader, offset, extents.end(offset), format)?;
+                    on_tile();
+
+                    Ok(Some(Tile {
+                        chunk,
+                        position: (row, col),
+                        level,
+                        size: cell_size >> level,
+                        bbox: None,
+                        texture: None,
+                        normals: None,
+                    }))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
 
-            Ok(QuadTree::build_complete_tree(tiles, depth))
+            QuadTree::from_sparse(tiles, depth)
         }
     }
 }
@@ -245,7 +467,6 @@ pub mod chunk {
     use std::io::{BufReader, Read, Seek, SeekFrom};
 
     use bytemuck::{Pod, Zeroable};
-    use vulkano::impl_vertex;
 
     use crate::disk_util::read_value;
 
@@ -258,15 +479,12 @@ pub mod chunk {
         pub morph_delta: f32,
     }
 
-    impl_vertex!(HFVertex, position, color, txt_coord, morph_delta);
-
     impl HFVertex {
-        pub fn with_color_and_coords(&self, color: [f32; 3], coords: [f32; 2]) -> Self {
-            Self {
-                position: self.position,
-                color,
-                txt_coord: coords,
-                morph_delta: self.morph_delta,
+        /// The vertex back in its on-disk form
+        pub fn quantized(&self) -> QuantizedVertex {
+            let [x, y, z] = self.position;
+            QuantizedVertex {
+                position: [x as i16, y as i16, z as i16, self.morph_delta as i16],
             }
         }
 
@@ -294,21 +512,98 @@ pub mod chunk {
         }
     }
 
+    /// The compact GPU form of a vertex: x, y, z and morph delta exactly as
+    /// they are stored in the cell file. Texture coordinates are derived in
+    /// the vertex shader instead, which makes it a quarter of an `HFVertex`.
+    /// Normals, for maps that need them per vertex, are a stream of their own
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+    pub struct QuantizedVertex {
+        pub position: [i16; 4],
+    }
+
+    /// How the chunks of a file are stored, from its header
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ChunkFormat {
+        pub version: u32,
+        /// 32-bit indices instead of 16-bit ones
+        pub wide_indices: bool,
+        /// A normal after each vertex
+        pub normals: bool,
+    }
+
+    impl ChunkFormat {
+        const WIDE_INDICES: u32 = 1;
+        const NORMALS: u32 = 2;
+
+        pub fn from_flags(version: u32, flags: u32) -> Result<Self, &'static str> {
+            if flags & !(Self::WIDE_INDICES | Self::NORMALS) != 0 {
+                return Err("Unknown cell flags");
+            }
+
+            Ok(Self {
+                version,
+                wide_indices: flags & Self::WIDE_INDICES != 0,
+                normals: flags & Self::NORMALS != 0,
+            })
+        }
+
+        pub fn flags(&self) -> u32 {
+            let mut flags = 0;
+            if self.wide_indices {
+                flags |= Self::WIDE_INDICES;
+            }
+            if self.normals {
+                flags |= Self::NORMALS;
+            }
+            flags
+        }
+
+        fn header_len(&self) -> u64 {
+            match self.version {
+                1 => 16,
+                _ => 24,
+            }
+        }
+
+        /// Bytes taken by each vertex, its normal included
+        fn vertex_len(&self) -> u64 {
+            if self.normals {
+                12
+            } else {
+                8
+            }
+        }
+
+        fn index_len(&self) -> u64 {
+            if self.wide_indices {
+                4
+            } else {
+                2
+            }
+        }
+    }
+
+    /// Version 1 stores only the vertical range of the chunk; version 2
+    /// stores its whole box
     struct ChunkHeader {
         max_error: f32,
         n_verts: u32,
         n_indices: u32,
-        min_y: i16,
-        max_y: i16,
+        min: [i16; 3],
+        max: [i16; 3],
     }
 
     impl ChunkHeader {
-        fn read_from<R: Read>(reader: &mut BufReader<R>) -> Result<Self, &'static str> {
+        fn read_from<R: Read>(
+            reader: &mut BufReader<R>,
+            format: ChunkFormat,
+        ) -> Result<Self, &'static str> {
             let mut max_error = 0f32;
             let mut n_verts = 0u32;
             let mut n_indices = 0u32;
-            let mut min_y = 0i16;
-            let mut max_y = 0i16;
+            let mut min = [0i16; 3];
+            let mut max = [0i16; 3];
 
             read_value(reader, &mut max_error, "Unable to read chunk max error")?;
             read_value(reader, &mut n_verts, "Unable to read chunk no. of vertices")?;
@@ -317,15 +612,25 @@ pub mod chunk {
                 &mut n_indices,
                 "Unable to read chunk no. of indices",
             )?;
-            read_value(reader, &mut min_y, "Unable to read chunk minimum y")?;
-            read_value(reader, &mut max_y, "Unable to read chunk maximum y")?;
+
+            if format.version == 1 {
+                read_value(reader, &mut min[1], "Unable to read chunk minimum y")?;
+                read_value(reader, &mut max[1], "Unable to read chunk maximum y")?;
+            } else {
+                for c in min.iter_mut() {
+                    read_value(reader, c, "Unable to read chunk minimum")?;
+                }
+                for c in max.iter_mut() {
+                    read_value(reader, c, "Unable to read chunk maximum")?;
+                }
+            }
 
             Ok(Self {
                 max_error,
                 n_verts,
                 n_indices,
-                min_y,
-                max_y,
+                min,
+                max,
             })
         }
     }
@@ -333,17 +638,40 @@ pub mod chunk {
     #[derive(Debug, Clone)]
     pub struct Chunk {
         pub max_error: f32,
-        pub min_y: i16,
-        pub max_y: i16,
+        /// Corners of the box around the vertices, in their units. Version 1
+        /// files only store y, so x and z are worked out on load.
+        pub min: [i16; 3],
+        pub max: [i16; 3],
         pub vertices: Vec<HFVertex>,
-        pub indices: Vec<u16>,
+        /// One per vertex, scaled to `i8::MAX`, if the file has them
+        pub normals: Option<Vec<[i8; 3]>>,
+        pub indices: Vec<u32>,
     }
 
     impl Chunk {
+        /// Index that starts a new triangle strip
+        pub const RESTART_INDEX: u32 = u32::MAX;
+        /// Any more and some vertex would need the 16-bit restart index
+        pub const MAX_VERTICES: u32 = u16::MAX as u32;
+
+        /// Whether the chunk has too many vertices for 16-bit indices
+        pub fn needs_wide_indices(&self) -> bool {
+            self.vertices.len() > Self::MAX_VERTICES as usize
+        }
+
+        /// Read the chunk at `offset`, which has to end by `end`, where the
+        /// next chunk starts or the file ends, making sure it fits before
+        /// allocating for it
         pub fn read_from<R: Read + Seek>(
             reader: &mut BufReader<R>,
             offset: u64,
+            end: u64,
+            format: ChunkFormat,
         ) -> Result<Self, &'static str> {
+            if offset.saturating_add(format.header_len()) > end {
+                return Err("Chunk offset out of the file");
+            }
+
             reader
                 .seek(SeekFrom::Start(offset))
                 .map_err(|_| "Unable to seek to chunk")?;
@@ -352,29 +680,402 @@ pub mod chunk {
                 max_error,
                 n_verts,
                 n_indices,
-                min_y,
-                max_y,
-            } = ChunkHeader::read_from(reader)?;
+                mut min,
+                mut max,
+            } = ChunkHeader::read_from(reader, format)?;
+
+            if !max_error.is_finite() || max_error < 0.0 {
+                return Err("Invalid chunk max error");
+            }
+
+            if !format.wide_indices && n_verts > Self::MAX_VERTICES
+                || n_verts == Self::RESTART_INDEX
+            {
+                return Err("Too many vertices in chunk");
+            }
+
+            if (0..3).any(|c| min[c] > max[c]) {
+                return Err("Invalid chunk bounds");
+            }
+
+            let body_len =
+                format.vertex_len() * n_verts as u64 + format.index_len() * n_indices as u64;
+            if offset + format.header_len() + body_len > end {
+                return Err("Chunk runs into the next one or past the end of the file");
+            }
 
             let mut vertices = Vec::with_capacity(n_verts as usize);
             for _ in 0..n_verts {
                 vertices.push(HFVertex::read_from(reader)?);
             }
 
+            let normals = if format.normals {
+                let mut normals = Vec::with_capacity(n_verts as usize);
+                for _ in 0..n_verts {
+                    let mut normal = [0u8; 4];
+                    reader
+                        .read_exact(&mut normal)
+                        .map_err(|_| "Unable to read normal")?;
+                    normals.push([normal[0] as i8, normal[1] as i8, normal[2] as i8]);
+                }
+                Some(normals)
+            } else {
+                None
+            };
+
             let mut indices = Vec::with_capacity(n_indices as usize);
             for _ in 0..n_indices {
-                let mut x = 0u16;
-                read_value(reader, &mut x, "Unable to read index")?;
-                indices.push(x);
+                let index = if format.wide_indices {
+                    let mut x = 0u32;
+                    read_value(reader, &mut x, "Unable to read index")?;
+                    x
+                } else {
+                    let mut x = 0u16;
+                    read_value(reader, &mut x, "Unable to read index")?;
+                    match x {
+                        u16::MAX => Self::RESTART_INDEX,
+                        x => x as u32,
+                    }
+                };
+                if index != Self::RESTART_INDEX && index >= n_verts {
+                    return Err("Chunk index out of range");
+                }
+                indices.push(index);
+            }
+
+            // Version 1 only knows the vertical range
+            if format.version == 1 && !vertices.is_empty() {
+                for c in [0, 2] {
+                    let values = vertices.iter().map(|v| v.position[c] as i16);
+                    min[c] = values.clone().min().unwrap();
+                    max[c] = values.max().unwrap();
+                }
+            } else if vertices
+                .iter()
+                .any(|v| (0..3).any(|c| !(min[c] as f32..=max[c] as f32).contains(&v.position[c])))
+            {
+                return Err("Chunk vertex out of its bounds");
             }
 
             Ok(Self {
                 max_error,
-                min_y,
-                max_y,
+                min,
+                max,
                 vertices,
+                normals,
                 indices,
             })
         }
+
+        /// The chunk as it's stored in a file of `format`
+        pub fn encode(&self, format: ChunkFormat) -> Vec<u8> {
+            let mut out = Vec::with_capacity(
+                (format.header_len()
+                    + format.vertex_len() * self.vertices.len() as u64
+                    + format.index_len() * self.indices.len() as u64) as usize,
+            );
+
+            out.extend(self.max_error.to_le_bytes());
+            out.extend((self.vertices.len() as u32).to_le_bytes());
+            out.extend((self.indices.len() as u32).to_le_bytes());
+            if format.version == 1 {
+                out.extend(self.min[1].to_le_bytes());
+                out.extend(self.max[1].to_le_bytes());
+            } else {
+                out.extend(
+                    self.min
+                        .iter()
+                        .chain(&self.max)
+                        .flat_map(|c| c.to_le_bytes()),
+                );
+            }
+
+            for vertex in self.vertices.iter() {
+                out.extend(
+                    vertex
+                        .quantized()
+                        .position
+                        .iter()
+                        .flat_map(|c| c.to_le_bytes()),
+                );
+            }
+
+            if format.normals {
+                for [x, y, z] in self.normals.iter().flatten() {
+                    out.extend([*x as u8, *y as u8, *z as u8, 0]);
+                }
+            }
+
+            for &index in self.indices.iter() {
+                if format.wide_indices {
+                    out.extend(index.to_le_bytes());
+                } else {
+                    out.extend((index as u16).to_le_bytes());
+                }
+            }
+
+            out
+        }
+
+        /// The triangles of the strips, without the degenerate ones that
+        /// join them. Every other one is wound the other way
+        pub fn triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+            self.indices
+                .split(|&index| index == Self::RESTART_INDEX)
+                .flat_map(|strip| strip.windows(3))
+                .map(|w| [w[0], w[1], w[2]])
+                .filter(|[a, b, c]| a != b && b != c && a != c)
+        }
+
+        /// A normal per vertex: the file's if it has them, or else the
+        /// vertex's faces averaged by area, `scale` taking vertex units to
+        /// world units. Skirts hang straight down and are left out, so the
+        /// bottoms of skirts face up
+        pub fn vertex_normals(&self, scale: [f64; 3]) -> Vec<[i8; 3]> {
+            if let Some(normals) = &self.normals {
+                return normals.clone();
+            }
+
+            let mut sums = vec![[0.0f64; 3]; self.vertices.len()];
+            let world = |index: u32| {
+                let position = self.vertices[index as usize].position;
+                std::array::from_fn::<f64, 3, _>(|c| position[c] as f64 * scale[c])
+            };
+            for [a, b, c] in self.triangles() {
+                let (pa, pb, pc) = (world(a), world(b), world(c));
+                let (u, v) = (
+                    [pb[0] - pa[0], pb[1] - pa[1], pb[2] - pa[2]],
+                    [pc[0] - pa[0], pc[1] - pa[1], pc[2] - pa[2]],
+                );
+                let mut normal = [
+                    u[1] * v[2] - u[2] * v[1],
+                    u[2] * v[0] - u[0] * v[2],
+                    u[0] * v[1] - u[1] * v[0],
+                ];
+                if normal[1] == 0.0 {
+                    continue;
+                }
+                // Terrain faces up, whichever way the strip winds
+                if normal[1] < 0.0 {
+                    normal = normal.map(|n| -n);
+                }
+                for index in [a, b, c] {
+                    for (sum, n) in sums[index as usize].iter_mut().zip(normal) {
+                        *sum += n;
+                    }
+                }
+            }
+
+            sums.into_iter()
+                .map(|[x, y, z]| {
+                    let length = (x * x + y * y + z * z).sqrt();
+                    if length > 0.0 {
+                        [x, y, z].map(|c| (c / length * i8::MAX as f64).round() as i8)
+                    } else {
+                        [0, i8::MAX, 0]
+                    }
+                })
+                .collect()
+        }
+
+        /// Triangles drawn from the strips, degenerate ones included
+        pub fn n_triangles(&self) -> usize {
+            self.indices
+                .split(|&i| i == Self::RESTART_INDEX)
+                .map(|strip| strip.len().saturating_sub(2))
+                .sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        quadtree::{
+            util::{full_size, tree_position},
+            NodeId,
+        },
+        test_util::TempDir,
+    };
+
+    use std::io::{BufReader, Cursor};
+
+    use super::{
+        chunk::{Chunk, ChunkFormat},
+        Cell, CellHeader,
+    };
+
+    #[test]
+    fn works_out_vertex_normals() {
+        use super::chunk::HFVertex;
+
+        // A quad sloping up to the east, with a skirt under its north edge
+        let vertex = |x: f32, y: f32, z: f32| HFVertex {
+            position: [x, y, z],
+            ..Default::default()
+        };
+        let mut chunk = Chunk {
+            max_error: 0.0,
+            min: [0; 3],
+            max: [0; 3],
+            vertices: vec![
+                vertex(0.0, 0.0, 0.0),
+                vertex(1.0, 1.0, 0.0),
+                vertex(0.0, 0.0, 1.0),
+                vertex(1.0, 1.0, 1.0),
+                vertex(0.0, -5.0, 0.0),
+                vertex(1.0, -4.0, 0.0),
+            ],
+            normals: None,
+            indices: vec![0, 2, 1, 3, Chunk::RESTART_INDEX, 0, 4, 1, 5],
+        };
+
+        let normals = chunk.vertex_normals([1.0; 3]);
+        assert_eq!(normals[..4], [[-90, 90, 0]; 4]);
+        assert_eq!(normals[4], [0, 127, 0]);
+        // Stretched out, the slope is gentler
+        assert!(chunk.vertex_normals([10.0, 1.0, 10.0])[3][1] > 120);
+
+        chunk.normals = Some(vec![[1, 2, 3]; 6]);
+        assert_eq!(chunk.vertex_normals([1.0; 3])[5], [1, 2, 3]);
+    }
+
+    #[test]
+    fn counts_triangles_across_restarts() {
+        let chunk = Chunk {
+            max_error: 0.0,
+            min: [0; 3],
+            max: [0; 3],
+            vertices: vec![],
+            normals: None,
+            indices: vec![
+                0,
+                1,
+                2,
+                3,
+                Chunk::RESTART_INDEX,
+                4,
+                5,
+                6,
+                Chunk::RESTART_INDEX,
+                7,
+            ],
+        };
+        assert_eq!(chunk.n_triangles(), 3);
+    }
+
+    #[test]
+    fn tiles_cover_their_chunks() {
+        let cell = Cell::new("maps/test-map2/00_00/hf.cell", (0, 0), None, None, 1024).unwrap();
+        for level in 0..cell.depth {
+            for tile in cell.tree.items_at_level(level) {
+                let (row, col) = tile.position;
+                assert_eq!(
+                    cell.tree.get(level, row, col).map(|t| t.position),
+                    Some((row, col))
+                );
+                let (x_range, z_range) = (
+                    (col * tile.size) as f32..=((col + 1) * tile.size) as f32,
+                    (row * tile.size) as f32..=((row + 1) * tile.size) as f32,
+                );
+
+                for v in tile.chunk.vertices.iter() {
+                    assert!(x_range.contains(&v.position[0]));
+                    assert!(z_range.contains(&v.position[2]));
+
+                    let q = v.quantized().position;
+                    assert_eq!(
+                        q.map(|c| c as f32),
+                        [v.position[0], v.position[1], v.position[2], v.morph_delta]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_cells_stop_early() {
+        // Drop everything under the nw quarter of the cell
+        let mut data = std::fs::read("maps/test-map2/00_00/hf.cell").unwrap();
+        let depth = 5;
+        for index in 0..full_size(depth) {
+            let (level, row, col) = tree_position(index);
+            if level >= 2 && (row | col) >> (level - 1) == 0 {
+                let at = (CellHeader::V1_SIZE + 8 * index as u64) as usize;
+                data[at..at + 8].fill(0);
+            }
+        }
+        let dir = TempDir::new("sparse-test-map2");
+        let path = dir.join("hf.cell");
+        std::fs::write(&path, data).unwrap();
+
+        let cell = Cell::new(&path, (0, 0), None, None, 1024).unwrap();
+        assert!(cell.tree.is_sparse());
+        assert!(cell.tree.is_leaf(NodeId::new(1, 0, 0)));
+        assert!(!cell.tree.is_leaf(NodeId::new(1, 1, 1)));
+        assert_eq!(cell.tree.get(2, 0, 1).map(|t| t.level), None);
+        assert_eq!(cell.tree.items_at_level(4).len(), 3 * 64);
+
+        // Refining everything stops at the nw quarter and goes all the way
+        // down everywhere else
+        let selected = cell.tree.select(|_| true);
+        assert_eq!(selected.len(), 1 + 3 * 64);
+        assert_eq!(selected[0].position, (0, 0));
+        assert_eq!(selected[0].level, 1);
+    }
+
+    #[test]
+    fn version_2_round_trips() {
+        let mut cell = Cell::new("maps/test-map1/00_00/hf.cell", (0, 0), None, None, 1024).unwrap();
+        for tile in cell.tree.iter_mut() {
+            let n = tile.chunk.vertices.len();
+            tile.chunk.normals = Some(vec![[0, 127, 0]; n]);
+        }
+
+        let mut data = Vec::new();
+        cell.write_to(&mut data).unwrap();
+        assert_eq!(data[..4], Cell::MAGIC_V2.to_le_bytes());
+        let read = Cell::read_from(
+            &mut BufReader::new(Cursor::new(data)),
+            (0, 0),
+            None,
+            None,
+            1024,
+        )
+        .unwrap();
+
+        for (a, b) in cell.tree.iter().zip(read.tree.iter()) {
+            let (a, b) = (&a.chunk, &b.chunk);
+            assert_eq!((a.min, a.max), (b.min, b.max));
+            assert_eq!(a.indices, b.indices);
+            assert_eq!(a.normals, b.normals);
+            assert!(a
+                .vertices
+                .iter()
+                .zip(&b.vertices)
+                .all(|(u, v)| u.quantized().position == v.quantized().position));
+        }
+
+        // Loaded from version 1, the box still holds every vertex
+        let root = &cell.tree.root().chunk;
+        assert!(root.vertices.iter().all(|v| (0..3)
+            .all(|c| { (root.min[c] as f32..=root.max[c] as f32).contains(&v.position[c]) })));
+    }
+
+    #[test]
+    fn wide_indices() {
+        let cell = Cell::new("maps/test-map1/00_00/hf.cell", (0, 0), None, None, 1024).unwrap();
+        let chunk = &cell.tree.root().chunk;
+        let format = ChunkFormat::from_flags(2, 1).unwrap();
+        assert!(format.wide_indices && !format.normals);
+
+        let data = chunk.encode(format);
+        let len = data.len() as u64;
+        let read =
+            Chunk::read_from(&mut BufReader::new(Cursor::new(data)), 0, len, format).unwrap();
+        assert_eq!(read.indices, chunk.indices);
+        assert!(read.indices.contains(&Chunk::RESTART_INDEX));
+
+        assert!(ChunkFormat::from_flags(2, 4).is_err());
     }
 }
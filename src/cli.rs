@@ -0,0 +1,796 @@
+use std::{ops::Range, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use nalgebra::Point3;
+use vulkano::{instance::debug::DebugUtilsMessageSeverity, swapchain::PresentMode};
+
+use group_project::{
+    edit::Part,
+    export::{Detail, ExportOptions},
+    generate::{Method, TerrainOptions},
+    import::RawOptions,
+    map::Map,
+    package::PackageOptions,
+};
+
+use crate::{app::Settings, window_state::WindowSettings};
+
+/// A chunked LOD terrain viewer
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The map's directory, or its map.json
+    pub map: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub view: ViewArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Open the map in a window; what happens without a subcommand
+    View {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+
+        #[command(flatten)]
+        view: ViewArgs,
+    },
+    /// Print what the map is made of, without opening a window
+    Info {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+    },
+    /// Draw a number of frames, report their stats and exit
+    Bench {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+
+        /// How many frames to draw
+        #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u32).range(1..))]
+        frames: u32,
+
+        #[command(flatten)]
+        view: ViewArgs,
+    },
+    /// Make a map package out of an elevation model: an SRTM .hgt tile, an
+    /// ESRI ASCII grid (.asc), or a 16-bit heightmap (.pgm, .raw)
+    Import {
+        /// The elevation model
+        input: PathBuf,
+
+        /// Directory to write the map to
+        out: PathBuf,
+
+        #[command(flatten)]
+        package: PackageArgs,
+
+        #[command(flatten)]
+        raw: RawArgs,
+    },
+    /// Make a map package of synthetic terrain
+    Generate {
+        /// Directory to write the map to
+        out: PathBuf,
+
+        #[command(flatten)]
+        terrain: TerrainArgs,
+
+        #[command(flatten)]
+        package: PackageArgs,
+    },
+    /// Write part of the map as a mesh, .obj or .glb, with its color map
+    Export {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+
+        /// The mesh to write; an .obj gets an .mtl and a .png beside it
+        out: PathBuf,
+
+        #[command(flatten)]
+        export: ExportArgs,
+    },
+    /// Make a map package of a rectangle of the map's cells
+    Crop {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+
+        /// Directory to write the map to
+        out: PathBuf,
+
+        /// Rows of cells to keep, the first and one past the last
+        #[arg(long, value_name = "FIRST..END", value_parser = parse_range)]
+        rows: Range<u32>,
+
+        /// Columns of cells to keep, the first and one past the last
+        #[arg(long, value_name = "FIRST..END", value_parser = parse_range)]
+        cols: Range<u32>,
+    },
+    /// Make one map package of several, side by side in one grid
+    Mosaic {
+        /// Directory to write the map to
+        out: PathBuf,
+
+        /// The maps, each with the row and column of its north west cell;
+        /// without one it goes east of the map before it
+        #[arg(required = true, value_name = "MAP[@ROW,COL]", value_parser = parse_part)]
+        maps: Vec<Part>,
+
+        /// The map's name; the output directory's name if not given
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Make a map package with the finest levels of detail left out
+    Downsample {
+        /// The map's directory, or its map.json
+        map: PathBuf,
+
+        /// Directory to write the map to
+        out: PathBuf,
+
+        /// Levels of detail to keep
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=9))]
+        depth: u32,
+    },
+}
+
+/// What part of a map gets exported, and how finely
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// West, north, east and south edges in world units; the whole map if
+    /// not given
+    #[arg(long, value_name = "W,N,E,S", value_parser = parse_region, allow_negative_numbers = true)]
+    pub region: Option<[f64; 4]>,
+
+    /// Tiles of this level, 0 being the coarsest
+    #[arg(long, conflicts_with = "tolerance")]
+    pub level: Option<u32>,
+
+    /// The coarsest tiles with at most this error, in world units; the
+    /// finest tiles if neither this nor a level is given
+    #[arg(long, value_name = "UNITS")]
+    pub tolerance: Option<f64>,
+
+    /// Texels along the longer side of the color texture
+    #[arg(long, value_name = "TEXELS", default_value_t = 2048, value_parser = clap::value_parser!(u32).range(1..=16384))]
+    pub texture_size: u32,
+
+    /// Leave the color texture out
+    #[arg(long)]
+    pub no_texture: bool,
+}
+
+impl ExportArgs {
+    pub fn options(&self, map: &Map) -> ExportOptions {
+        ExportOptions {
+            region: self
+                .region
+                .unwrap_or([map.west(), map.north(), map.east(), map.south()]),
+            detail: match (self.level, self.tolerance) {
+                (Some(level), _) => Detail::Level(level),
+                (_, tolerance) => Detail::Tolerance(tolerance.unwrap_or(0.0)),
+            },
+            texture_size: (!self.no_texture).then_some(self.texture_size),
+        }
+    }
+}
+
+/// Options for anything that writes a map package
+#[derive(Debug, Args)]
+pub struct PackageArgs {
+    /// The map's name; the output directory's name if not given
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Samples across a cell, a power of two
+    #[arg(long, value_name = "SAMPLES", default_value_t = PackageOptions::default().cell_size)]
+    pub cell_size: u32,
+
+    /// Write color and normal maps, with tiles this many texels a side;
+    /// generated maps get them anyway
+    #[arg(long, value_name = "TEXELS")]
+    pub texture_size: Option<u32>,
+
+    /// Write water maps, with water below this elevation
+    #[arg(long, value_name = "ELEVATION", allow_negative_numbers = true)]
+    pub water_level: Option<f32>,
+}
+
+impl PackageArgs {
+    pub fn options(&self, out: &std::path::Path) -> PackageOptions {
+        let name = self.name.clone().or_else(|| {
+            out.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+
+        PackageOptions {
+            name: name.unwrap_or(PackageOptions::default().name),
+            cell_size: self.cell_size,
+            texture_size: self.texture_size,
+            water_level: self.water_level,
+            ..Default::default()
+        }
+    }
+}
+
+/// What synthetic terrain looks like
+#[derive(Debug, Args)]
+pub struct TerrainArgs {
+    /// Cells across and down
+    #[arg(long, value_name = "COLSxROWS", default_value = "2x2", value_parser = parse_size)]
+    pub cells: [u32; 2],
+
+    /// The same seed gives the same terrain
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    #[arg(long, value_enum, default_value_t = MethodArg::Fbm)]
+    pub method: MethodArg,
+
+    /// Samples across the largest features
+    #[arg(long, value_name = "SAMPLES", default_value_t = TerrainOptions::default().feature_size)]
+    pub feature_size: f64,
+
+    /// Layers of noise, each finer than the last
+    #[arg(long, default_value_t = TerrainOptions::default().octaves)]
+    pub octaves: u32,
+
+    /// How much of each octave's height the next keeps
+    #[arg(long, default_value_t = TerrainOptions::default().persistence)]
+    pub persistence: f64,
+
+    /// Height from the lowest point to the highest
+    #[arg(long, value_name = "UNITS", default_value_t = TerrainOptions::default().relief)]
+    pub relief: f32,
+
+    /// Droplets of hydraulic erosion to run
+    #[arg(long, value_name = "DROPLETS", default_value_t = 0)]
+    pub erosion: u32,
+
+    /// Distance between samples
+    #[arg(long, value_name = "UNITS", default_value_t = 10.0)]
+    pub h_scale: f32,
+}
+
+impl TerrainArgs {
+    /// Texture tiles for generated maps, unless asked otherwise
+    pub const TEXTURE_SIZE: u32 = 128;
+
+    /// Enough samples for the cells, which share their edges
+    pub fn options(&self, cell_size: u32) -> Result<TerrainOptions, &'static str> {
+        let samples = |cells: u32| {
+            cells
+                .checked_mul(cell_size)
+                .and_then(|n| n.checked_add(1))
+                .ok_or("Too many cells")
+        };
+
+        Ok(TerrainOptions {
+            seed: self.seed,
+            method: self.method.into(),
+            size: [samples(self.cells[0])?, samples(self.cells[1])?],
+            feature_size: self.feature_size,
+            octaves: self.octaves,
+            persistence: self.persistence,
+            relief: self.relief,
+            erosion: self.erosion,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MethodArg {
+    Fbm,
+    Ridged,
+    DiamondSquare,
+}
+
+impl From<MethodArg> for Method {
+    fn from(method: MethodArg) -> Self {
+        match method {
+            MethodArg::Fbm => Method::Fbm,
+            MethodArg::Ridged => Method::Ridged,
+            MethodArg::DiamondSquare => Method::DiamondSquare,
+        }
+    }
+}
+
+/// How to read heightmaps that don't say
+#[derive(Debug, Args)]
+pub struct RawArgs {
+    /// Size of a .raw heightmap in samples
+    #[arg(long, value_name = "WxH", value_parser = parse_size)]
+    pub raw_size: Option<[u32; 2]>,
+
+    /// .raw samples are big endian rather than little
+    #[arg(long)]
+    pub big_endian: bool,
+
+    /// Elevation of each step of a heightmap's values
+    #[arg(long, value_name = "UNITS", default_value_t = RawOptions::default().elevation_scale)]
+    pub elevation_scale: f32,
+
+    /// Distance between a heightmap's samples
+    #[arg(long, value_name = "UNITS", default_value_t = RawOptions::default().spacing)]
+    pub spacing: f64,
+
+    /// Heightmap value that means there's no data
+    #[arg(long, value_name = "VALUE")]
+    pub nodata: Option<u16>,
+}
+
+impl RawArgs {
+    pub fn options(&self) -> RawOptions {
+        RawOptions {
+            size: self.raw_size,
+            big_endian: self.big_endian,
+            elevation_scale: self.elevation_scale,
+            spacing: self.spacing,
+            nodata: self.nodata,
+        }
+    }
+}
+
+/// Options for anything that opens a window
+#[derive(Debug, Args)]
+pub struct ViewArgs {
+    /// Window size in pixels
+    #[arg(long, value_name = "WxH", default_value = "1280x720", value_parser = parse_size)]
+    pub size: [u32; 2],
+
+    /// Open in borderless fullscreen on the current monitor
+    #[arg(long)]
+    pub fullscreen: bool,
+
+    /// Where the camera starts, in world units
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_point)]
+    pub camera_pos: Option<Point3<f64>>,
+
+    /// What the camera starts looking at, in world units
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_point)]
+    pub camera_target: Option<Point3<f64>>,
+
+    /// Tiles whose projected error is above this many pixels get refined
+    #[arg(long, value_name = "PIXELS", default_value_t = Settings::default().pixel_tolerance)]
+    pub pixel_tolerance: f64,
+
+    /// Draw plain grey terrain instead of the color map
+    #[arg(long)]
+    pub no_textures: bool,
+
+    /// Don't shade the terrain with the map's sun
+    #[arg(long)]
+    pub no_lighting: bool,
+
+    /// Fade distant terrain into fog, even if the map has none
+    #[arg(long, conflicts_with = "no_fog")]
+    pub fog: bool,
+
+    /// No fog, even if the map has some
+    #[arg(long)]
+    pub no_fog: bool,
+
+    /// Draw triangle edges only
+    #[arg(long)]
+    pub wireframe: bool,
+
+    /// Shift the mip level textures are sampled at; negative is sharper
+    #[arg(long, value_name = "LEVELS", default_value_t = Settings::default().lod_bias, allow_negative_numbers = true)]
+    pub lod_bias: f32,
+
+    /// How frames are presented; fifo is vsync
+    #[arg(long, value_enum, default_value_t = PresentModeArg::Fifo)]
+    pub present_mode: PresentModeArg,
+
+    /// Which Vulkan debug messages get printed
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t = DebugLevel::Warning)]
+    pub debug: DebugLevel,
+
+    /// Print frame stats to stdout every second
+    #[arg(long, conflicts_with = "stats_csv")]
+    pub print_stats: bool,
+
+    /// Write frame stats to a CSV file, one row per frame
+    #[arg(long, value_name = "FILE")]
+    pub stats_csv: Option<PathBuf>,
+}
+
+impl ViewArgs {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            pixel_tolerance: self.pixel_tolerance,
+            textures: !self.no_textures,
+            lighting: !self.no_lighting,
+            fog: match (self.fog, self.no_fog) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            wireframe: self.wireframe,
+            camera_pos: self.camera_pos,
+            camera_target: self.camera_target,
+            lod_bias: self.lod_bias,
+        }
+    }
+
+    pub fn window_settings(&self) -> WindowSettings {
+        WindowSettings {
+            size: self.size,
+            fullscreen: self.fullscreen,
+            present_mode: self.present_mode.into(),
+            debug_utils: self.debug != DebugLevel::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PresentModeArg {
+    Immediate,
+    Mailbox,
+    Fifo,
+    FifoRelaxed,
+}
+
+impl From<PresentModeArg> for PresentMode {
+    fn from(mode: PresentModeArg) -> Self {
+        match mode {
+            PresentModeArg::Immediate => PresentMode::Immediate,
+            PresentModeArg::Mailbox => PresentMode::Mailbox,
+            PresentModeArg::Fifo => PresentMode::Fifo,
+            PresentModeArg::FifoRelaxed => PresentMode::FifoRelaxed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum DebugLevel {
+    Off,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+}
+
+impl DebugLevel {
+    /// The severities at this level and above; `None` when off
+    pub fn severity(self) -> Option<DebugUtilsMessageSeverity> {
+        let severity = DebugUtilsMessageSeverity {
+            error: true,
+            warning: self >= DebugLevel::Warning,
+            information: self >= DebugLevel::Info,
+            verbose: self >= DebugLevel::Verbose,
+            ..DebugUtilsMessageSeverity::empty()
+        };
+
+        (self != DebugLevel::Off).then_some(severity)
+    }
+}
+
+fn parse_size(s: &str) -> Result<[u32; 2], String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{}`", s))?;
+    let parse = |v: &str| match v.trim().parse::<u32>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(format!("`{}` is not a positive number of pixels", v)),
+    };
+
+    Ok([parse(width)?, parse(height)?])
+}
+
+fn parse_region(s: &str) -> Result<[f64; 4], String> {
+    let edges = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("`{}` has an edge that is not a number", s))?;
+
+    match edges[..] {
+        [west, north, east, south] if west < east && north < south => {
+            Ok([west, north, east, south])
+        }
+        [_, _, _, _] => Err("west has to be less than east, and north than south".to_string()),
+        _ => Err(format!("expected W,N,E,S, got {} edges", edges.len())),
+    }
+}
+
+fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected FIRST..END, got `{}`", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("`{}` is not a number of cells", v))
+    };
+
+    match (parse(start)?, parse(end)?) {
+        (start, end) if start < end => Ok(start..end),
+        _ => Err(format!("`{}` has no cells in it", s)),
+    }
+}
+
+fn parse_part(s: &str) -> Result<Part, String> {
+    // Only a suffix that is a row and column is one; paths can have an @
+    let at = s.rsplit_once('@').and_then(|(map, at)| {
+        let (row, col) = at.split_once(',')?;
+        let at = (row.trim().parse().ok()?, col.trim().parse().ok()?);
+        Some((map, at))
+    });
+
+    Ok(match at {
+        Some((map, at)) => Part {
+            map: map.into(),
+            at: Some(at),
+        },
+        None => Part {
+            map: s.into(),
+            at: None,
+        },
+    })
+}
+
+fn parse_point(s: &str) -> Result<Point3<f64>, String> {
+    let coords = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("`{}` has a coordinate that is not a number", s))?;
+
+    match coords[..] {
+        [x, y, z] => Ok(Point3::new(x, y, z)),
+        _ => Err(format!("expected X,Y,Z, got {} coordinates", coords.len())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{CommandFactory, Parser};
+
+    use group_project::generate::Method;
+
+    use super::{parse_part, parse_point, parse_range, parse_region, parse_size, Cli, Command};
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(parse_size("800x600"), Ok([800, 600]));
+        assert!(parse_size("800").is_err());
+        assert!(parse_size("0x600").is_err());
+        assert_eq!(
+            parse_point("1, 2.5,-3").unwrap().coords.as_slice(),
+            [1.0, 2.5, -3.0]
+        );
+        assert!(parse_point("1,2").is_err());
+        assert_eq!(parse_region("-10,0,10,20.5"), Ok([-10.0, 0.0, 10.0, 20.5]));
+        assert!(parse_region("10,0,-10,20").is_err());
+        assert!(parse_region("0,0,1").is_err());
+        assert_eq!(parse_range("1..3"), Ok(1..3));
+        assert!(parse_range("3..3").is_err());
+        assert!(parse_range("3").is_err());
+        assert_eq!(parse_part("maps/a@2,1").unwrap().at, Some((2, 1)));
+        let part = parse_part("maps/b@home").unwrap();
+        assert_eq!((part.map.to_str(), part.at), (Some("maps/b@home"), None));
+    }
+
+    #[test]
+    fn map_without_subcommand() {
+        let cli = Cli::try_parse_from(["viewer", "maps/test-map1", "--wireframe"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.view.wireframe);
+        assert_eq!(cli.view.lod_bias, 0.0);
+
+        let cli = Cli::try_parse_from(["viewer", "maps/test-map1", "--lod-bias", "-0.5"]).unwrap();
+        assert_eq!(cli.view.settings().lod_bias, -0.5);
+
+        assert!(Cli::try_parse_from(["viewer", "--fog", "--no-fog", "maps/test-map1"]).is_err());
+    }
+
+    #[test]
+    fn view_options() {
+        let cli = Cli::try_parse_from(["viewer", "view", "maps/test-map1", "--wireframe"]).unwrap();
+        let Some(Command::View { map, view }) = cli.command else {
+            panic!("expected view");
+        };
+        assert_eq!(map.to_str(), Some("maps/test-map1"));
+        assert!(view.wireframe);
+    }
+
+    #[test]
+    fn info_map() {
+        let cli = Cli::try_parse_from(["viewer", "info", "maps/test-map1"]).unwrap();
+        let Some(Command::Info { map }) = cli.command else {
+            panic!("expected info");
+        };
+        assert_eq!(map.to_str(), Some("maps/test-map1"));
+    }
+
+    #[test]
+    fn bench_frames() {
+        let cli =
+            Cli::try_parse_from(["viewer", "bench", "maps/test-map1", "--frames", "10"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Bench { frames: 10, .. })
+        ));
+
+        let cli = Cli::try_parse_from(["viewer", "bench", "maps/test-map1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Bench { frames: 600, .. })
+        ));
+
+        assert!(
+            Cli::try_parse_from(["viewer", "bench", "maps/test-map1", "--frames", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn import_options() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "import",
+            "island.raw",
+            "maps/island",
+            "--raw-size",
+            "1025x513",
+            "--cell-size",
+            "512",
+        ])
+        .unwrap();
+        let Some(Command::Import {
+            package, raw, out, ..
+        }) = cli.command
+        else {
+            panic!("expected import");
+        };
+        assert_eq!(package.options(&out).name, "island");
+        assert_eq!(package.options(&out).cell_size, 512);
+        assert_eq!(raw.options().size, Some([1025, 513]));
+    }
+
+    #[test]
+    fn generate_options() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "generate",
+            "maps/ridges",
+            "--cells",
+            "3x2",
+            "--method",
+            "ridged",
+            "--cell-size",
+            "256",
+        ])
+        .unwrap();
+        let Some(Command::Generate {
+            terrain, package, ..
+        }) = cli.command
+        else {
+            panic!("expected generate");
+        };
+        let options = terrain.options(package.cell_size).unwrap();
+        assert_eq!(options.size, [769, 513]);
+        assert_eq!(options.method, Method::Ridged);
+    }
+
+    #[test]
+    fn export_options() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "export",
+            "maps/test-map1",
+            "island.glb",
+            "--region",
+            "0,0,10,20.5",
+            "--level",
+            "2",
+            "--no-texture",
+        ])
+        .unwrap();
+        let Some(Command::Export { export, .. }) = cli.command else {
+            panic!("expected export");
+        };
+        assert_eq!(export.region, Some([0.0, 0.0, 10.0, 20.5]));
+        assert_eq!((export.level, export.tolerance), (Some(2), None));
+        assert_eq!(export.texture_size, 2048);
+        assert!(export.no_texture);
+
+        assert!(Cli::try_parse_from([
+            "viewer",
+            "export",
+            "maps/test-map1",
+            "island.obj",
+            "--level",
+            "2",
+            "--tolerance",
+            "1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn crop_ranges() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "crop",
+            "maps/test-map1",
+            "maps/corner",
+            "--rows",
+            "0..1",
+            "--cols",
+            "1..3",
+        ])
+        .unwrap();
+        let Some(Command::Crop { rows, cols, .. }) = cli.command else {
+            panic!("expected crop");
+        };
+        assert_eq!((rows, cols), (0..1, 1..3));
+
+        assert!(Cli::try_parse_from([
+            "viewer",
+            "crop",
+            "maps/test-map1",
+            "maps/corner",
+            "--rows",
+            "0..1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn mosaic_parts() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "mosaic",
+            "maps/both",
+            "maps/a",
+            "maps/b@0,1",
+            "--name",
+            "Both",
+        ])
+        .unwrap();
+        let Some(Command::Mosaic { maps, name, .. }) = cli.command else {
+            panic!("expected mosaic");
+        };
+        let at: Vec<_> = maps.iter().map(|part| part.at).collect();
+        assert_eq!(at, [None, Some((0, 1))]);
+        assert_eq!(name.as_deref(), Some("Both"));
+
+        assert!(Cli::try_parse_from(["viewer", "mosaic", "maps/both"]).is_err());
+    }
+
+    #[test]
+    fn downsample_depth() {
+        let cli = Cli::try_parse_from([
+            "viewer",
+            "downsample",
+            "maps/test-map1",
+            "maps/coarse",
+            "--depth",
+            "3",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Downsample { depth: 3, .. })
+        ));
+
+        assert!(Cli::try_parse_from([
+            "viewer",
+            "downsample",
+            "maps/test-map1",
+            "maps/coarse",
+            "--depth",
+            "10",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! The spaces terrain lives in, and the ways between them:
+//!
+//! - world: x east and z south of the map's north west corner, y up, in
+//!   the map's units once `h-scale`, `v-scale` and `base-elev` are applied
+//! - cell: a cell of the grid, by row and column
+//! - tile: a node of a cell's quadtree, a `NodeId`, or a `TileId` across
+//!   the map
+//! - local: a chunk's vertex units, samples from the cell's corner in x and
+//!   z, quantized elevation in y
+//! - texture: across a tile's texture, 0 to 1 from its north west corner,
+//!   or in texels once the texture's size is known
+//!
+//! `Frame` has what it takes to go between them for a given map.
+
+use nalgebra::{Matrix4, Point3, Vector3};
+
+use crate::{geometry::AABB, map::MapInfo, quadtree::NodeId};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldPos(pub Point3<f64>);
+
+impl WorldPos {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self(Point3::new(x, y, z))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellPos {
+    pub row: u32,
+    pub col: u32,
+}
+
+impl CellPos {
+    pub const fn new(row: u32, col: u32) -> Self {
+        Self { row, col }
+    }
+}
+
+impl From<(u32, u32)> for CellPos {
+    fn from((row, col): (u32, u32)) -> Self {
+        Self { row, col }
+    }
+}
+
+impl From<CellPos> for (u32, u32) {
+    fn from(cell: CellPos) -> Self {
+        (cell.row, cell.col)
+    }
+}
+
+/// In a chunk's vertex units, relative to its cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalPos(pub Point3<f64>);
+
+impl LocalPos {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self(Point3::new(x, y, z))
+    }
+
+    pub fn from_vertex([x, y, z]: [i16; 3]) -> Self {
+        Self::new(x as f64, y as f64, z as f64)
+    }
+
+    /// The nearest vertex, `None` if it doesn't fit one
+    pub fn to_vertex(self) -> Option<[i16; 3]> {
+        let fit = |c: f64| {
+            let c = c.round();
+            (i16::MIN as f64..=i16::MAX as f64)
+                .contains(&c)
+                .then_some(c as i16)
+        };
+
+        Some([fit(self.0.x)?, fit(self.0.y)?, fit(self.0.z)?])
+    }
+}
+
+/// Across a tile's texture, 0 to 1 from its north west corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TexCoord(pub [f64; 2]);
+
+/// In texels from the north west corner of a texture; texel centers are at
+/// halves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TexelPos(pub [f64; 2]);
+
+impl TexCoord {
+    pub fn to_texel(self, texture_size: u32) -> TexelPos {
+        let [u, v] = self.0;
+        TexelPos([u * texture_size as f64, v * texture_size as f64])
+    }
+
+    pub fn from_texel(texel: TexelPos, texture_size: u32) -> Self {
+        let [x, y] = texel.0;
+        Self([x / texture_size as f64, y / texture_size as f64])
+    }
+}
+
+/// How a map lays its cells and chunks out in the world
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub h_scale: f64,
+    pub v_scale: f64,
+    pub base_elevation: f64,
+    pub cell_width: u32,
+}
+
+impl Frame {
+    pub fn new(info: &MapInfo) -> Self {
+        Self {
+            h_scale: info.h_scale as f64,
+            v_scale: info.v_scale as f64,
+            base_elevation: info.base_elevation as f64,
+            cell_width: info.cell_width,
+        }
+    }
+
+    pub fn world_cell_width(&self) -> f64 {
+        self.cell_width as f64 * self.h_scale
+    }
+
+    /// The cell's north west corner, at zero elevation
+    pub fn cell_origin(&self, cell: CellPos) -> WorldPos {
+        let width = self.world_cell_width();
+        WorldPos::new(width * cell.col as f64, 0.0, width * cell.row as f64)
+    }
+
+    /// The cell `pos` is over, wherever the map ends; `None` north or west
+    /// of the map
+    pub fn cell_at(&self, pos: WorldPos) -> Option<CellPos> {
+        let width = self.world_cell_width();
+        let (row, col) = (pos.0.z / width, pos.0.x / width);
+        let in_range = |c: f64| (0.0..=u32::MAX as f64).contains(&c);
+        (in_range(row) && in_range(col)).then(|| CellPos::new(row as u32, col as u32))
+    }
+
+    pub fn to_world(&self, cell: CellPos, local: LocalPos) -> WorldPos {
+        WorldPos(self.local_to_world(cell).transform_point(&local.0))
+    }
+
+    pub fn to_local(&self, cell: CellPos, world: WorldPos) -> LocalPos {
+        let origin = self.cell_origin(cell).0;
+        LocalPos::new(
+            (world.0.x - origin.x) / self.h_scale,
+            (world.0.y - self.base_elevation) / self.v_scale,
+            (world.0.z - origin.z) / self.h_scale,
+        )
+    }
+
+    /// Takes a cell's vertices into the world
+    pub fn local_to_world(&self, cell: CellPos) -> Matrix4<f64> {
+        let origin = self.cell_origin(cell).0;
+        Matrix4::new_translation(&Vector3::new(origin.x, self.base_elevation, origin.z))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(
+                self.h_scale,
+                self.v_scale,
+                self.h_scale,
+            ))
+    }
+
+    /// Width of the tile in samples
+    pub fn tile_size(&self, node: NodeId) -> u32 {
+        self.cell_width >> node.level
+    }
+
+    /// The tile's north west corner
+    pub fn tile_origin(&self, node: NodeId) -> LocalPos {
+        let size = self.tile_size(node) as f64;
+        LocalPos::new(node.col as f64 * size, 0.0, node.row as f64 * size)
+    }
+
+    /// The box around a tile whose chunk goes from `min_y` to `max_y`
+    pub fn tile_bounds(&self, cell: CellPos, node: NodeId, min_y: i16, max_y: i16) -> AABB<f64> {
+        let size = self.tile_size(node) as f64;
+        let nw = self.tile_origin(node).0;
+        let min = LocalPos::new(nw.x, min_y as f64, nw.z);
+        let max = LocalPos::new(nw.x + size, max_y as f64, nw.z + size);
+
+        AABB::new(self.to_world(cell, min).0, self.to_world(cell, max).0)
+    }
+
+    /// Where `local` is on the tile's texture
+    pub fn tex_coord(&self, node: NodeId, local: LocalPos) -> TexCoord {
+        let ([offset_x, offset_z], scale) = self.tex_transform(node);
+        TexCoord([
+            (local.0.x - offset_x) * scale,
+            (local.0.z - offset_z) * scale,
+        ])
+    }
+
+    /// Where the texture coordinate is on the tile, at zero elevation
+    pub fn from_tex_coord(&self, node: NodeId, tex: TexCoord) -> LocalPos {
+        let ([offset_x, offset_z], scale) = self.tex_transform(node);
+        LocalPos::new(
+            tex.0[0] / scale + offset_x,
+            0.0,
+            tex.0[1] / scale + offset_z,
+        )
+    }
+
+    /// The offset and scale taking local x and z to the tile's texture
+    /// coordinates, as the vertex shader does
+    pub fn tex_transform(&self, node: NodeId) -> ([f64; 2], f64) {
+        let nw = self.tile_origin(node).0;
+        ([nw.x, nw.z], 1.0 / self.tile_size(node) as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::quadtree::NodeId;
+
+    use super::{CellPos, Frame, LocalPos, TexCoord, TexelPos, WorldPos};
+
+    const FRAME: Frame = Frame {
+        h_scale: 2.0,
+        v_scale: 0.5,
+        base_elevation: -10.0,
+        cell_width: 1024,
+    };
+
+    #[test]
+    fn world_and_local() {
+        let cell = CellPos::new(1, 2);
+        assert_eq!(FRAME.cell_origin(cell), WorldPos::new(4096.0, 0.0, 2048.0));
+
+        let local = LocalPos::new(100.0, 40.0, 1000.0);
+        let world = FRAME.to_world(cell, local);
+        assert_eq!(world, WorldPos::new(4296.0, 10.0, 4048.0));
+        assert_eq!(FRAME.to_local(cell, world), local);
+        assert_eq!(FRAME.cell_at(world), Some(cell));
+
+        assert_eq!(FRAME.cell_at(WorldPos::new(-1.0, 0.0, 0.0)), None);
+        assert_eq!(FRAME.cell_at(WorldPos::new(f64::NAN, 0.0, 0.0)), None);
+        assert_eq!(
+            FRAME.cell_at(WorldPos::new(2048.0, 0.0, 0.0)),
+            Some(CellPos::new(0, 1))
+        );
+
+        assert_eq!(local.to_vertex(), Some([100, 40, 1000]));
+        assert_eq!(LocalPos::new(0.0, 40000.0, 0.0).to_vertex(), None);
+        assert_eq!(
+            LocalPos::from_vertex([1, -2, 3]),
+            LocalPos::new(1.0, -2.0, 3.0)
+        );
+    }
+
+    #[test]
+    fn tiles_and_textures() {
+        let cell = CellPos::new(0, 1);
+        let node = NodeId::new(2, 1, 3);
+        assert_eq!(FRAME.tile_size(node), 256);
+        assert_eq!(FRAME.tile_origin(node), LocalPos::new(768.0, 0.0, 256.0));
+
+        let bounds = FRAME.tile_bounds(cell, node, 0, 20);
+        assert_eq!(bounds.min, WorldPos::new(2048.0 + 1536.0, -10.0, 512.0).0);
+        assert_eq!(bounds.max, WorldPos::new(2048.0 + 2048.0, 0.0, 1024.0).0);
+
+        let local = LocalPos::new(832.0, 0.0, 448.0);
+        let tex = FRAME.tex_coord(node, local);
+        assert_eq!(tex, TexCoord([0.25, 0.75]));
+        assert_eq!(FRAME.from_tex_coord(node, tex), local);
+
+        assert_eq!(tex.to_texel(64), TexelPos([16.0, 48.0]));
+        assert_eq!(TexCoord::from_texel(TexelPos([16.0, 48.0]), 64), tex);
+    }
+}
@@ -1,4 +1,8 @@
-use std::io::{BufReader, Read};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    sync::Mutex,
+};
 
 /// Anything that can be read from a byte array of size N
 pub trait ReadableFromBytes<const N: usize> {
@@ -34,12 +38,166 @@ pub fn read_value<'a, const N: usize, R: Read, T: ReadableFromBytes<N>>(
     Ok(())
 }
 
-/// Adds the alpha channel to RGB images
+/// Length of the whole stream, leaving the position where it was
+pub fn stream_len<R: Read + Seek>(reader: &mut BufReader<R>) -> Result<u64, &'static str> {
+    let position = reader
+        .stream_position()
+        .map_err(|_| "Unable to find stream position")?;
+    let len = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|_| "Unable to find stream length")?;
+    reader
+        .seek(SeekFrom::Start(position))
+        .map_err(|_| "Unable to seek back")?;
+
+    Ok(len)
+}
+
+/// Something to read from at any offset without a shared position, so that
+/// chunks and tiles can each be read in parallel, straight into their own
+/// buffers
+pub trait ReadAt: Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Any other reader, one read at a time
+impl<R: Read + Seek + Send> ReadAt for Mutex<R> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut reader = self.lock().unwrap_or_else(|e| e.into_inner());
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buf)
+    }
+}
+
+/// A reader of its own into a `ReadAt`, starting at `position` and ending
+/// at `end`
+pub struct ReaderAt<'a, S: ReadAt> {
+    source: &'a S,
+    position: u64,
+    end: u64,
+}
+
+impl<'a, S: ReadAt> ReaderAt<'a, S> {
+    pub fn new(source: &'a S, position: u64, end: u64) -> Self {
+        Self {
+            source,
+            position,
+            end,
+        }
+    }
+}
+
+impl<S: ReadAt> Read for ReaderAt<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.end.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        self.source.read_exact_at(&mut buf[..n], self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: ReadAt> Seek for ReaderAt<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.end.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// Where the chunks or tiles of a file end: each runs up to the next one, or
+/// to the end of the file. No two share their data, so what's decoded from a
+/// file grows with its length, not with how often it's pointed into
+pub struct Extents {
+    starts: Vec<u64>,
+    file_len: u64,
+}
+
+impl Extents {
+    /// From the offsets of a file `file_len` bytes long, 0 for none
+    pub fn new(offsets: &[u64], file_len: u64) -> Result<Self, &'static str> {
+        let mut starts: Vec<u64> = offsets.iter().copied().filter(|&o| o != 0).collect();
+        starts.sort_unstable();
+        if starts.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err("Repeated offset");
+        }
+
+        Ok(Self { starts, file_len })
+    }
+
+    /// Where the data at `offset` has to end by
+    pub fn end(&self, offset: u64) -> u64 {
+        let next = self.starts.partition_point(|&start| start <= offset);
+        self.starts
+            .get(next)
+            .copied()
+            .unwrap_or(self.file_len)
+            .min(self.file_len)
+    }
+}
+
+/// Adds the alpha channel to RGB images, in place
 pub fn interlace_alpha(image: &mut Vec<u8>) {
-    *image = image
-        .chunks_exact(3)
-        .map(|chunk| chunk.iter().chain(&[255]))
-        .flatten()
-        .copied()
-        .collect();
+    let n_pixels = image.len() / 3;
+    image.truncate(3 * n_pixels);
+    image.reserve_exact(n_pixels);
+    image.resize(4 * n_pixels, 255);
+
+    // Back to front, so nothing is overwritten before it's moved
+    for pixel in (0..n_pixels).rev() {
+        image.copy_within(3 * pixel..3 * pixel + 3, 4 * pixel);
+        image[4 * pixel + 3] = 255;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{interlace_alpha, Extents};
+
+    #[test]
+    fn interlaces_in_place() {
+        let mut image = vec![1, 2, 3, 4, 5, 6];
+        interlace_alpha(&mut image);
+        assert_eq!(image, [1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(image.capacity(), 8);
+    }
+
+    #[test]
+    fn extents_end_where_the_next_one_starts() {
+        let extents = Extents::new(&[40, 0, 10, 25], 50).unwrap();
+        assert_eq!(extents.end(10), 25);
+        assert_eq!(extents.end(25), 40);
+        assert_eq!(extents.end(40), 50);
+        assert_eq!(extents.end(60), 50);
+
+        assert!(Extents::new(&[10, 25, 10], 50).is_err());
+    }
 }
@@ -0,0 +1,457 @@
+//! Making map packages out of other map packages: cropping one to a
+//! rectangle of its cells, putting several side by side in one grid, and
+//! dropping the finest levels of detail. Cells are copied as they are
+//! wherever they can be, and decoded only when they have to change.
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    cell::Cell,
+    coords::WorldPos,
+    map::{GridEntry, MapInfo},
+    package::Quantizer,
+    texture_quadtree::TexturedQuadTree,
+};
+
+/// A map to put in a mosaic, and where its north west cell goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// The map's directory, or its map.json
+    pub map: PathBuf,
+    /// Row and column of the mosaic; east of the part before it, on that
+    /// part's first row, if not given
+    pub at: Option<(u32, u32)>,
+}
+
+/// Write the cells of `map` in `rows` and `cols` to `out` as a map of their
+/// own. The elevation range is kept from the whole map, and so is the
+/// georeference, moved to the new corner
+pub fn crop(
+    map: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    rows: Range<u32>,
+    cols: Range<u32>,
+) -> Result<MapInfo, String> {
+    let (dir, info) = open(map.as_ref())?;
+    let (n_rows, n_cols) = info.grid_size();
+    if rows.is_empty()
+        || cols.is_empty()
+        || rows.end as usize > n_rows
+        || cols.end as usize > n_cols
+    {
+        return Err("Crop has to be a rectangle of the map's cells".into());
+    }
+
+    let cells = info
+        .grid
+        .iter()
+        .filter_map(|entry| {
+            let (row, col) = entry.position()?;
+            (rows.contains(&row) && cols.contains(&col))
+                .then(|| (dir.join(entry.name()), (row - rows.start, col - cols.start)))
+        })
+        .collect::<Vec<_>>();
+    if cells.is_empty() {
+        return Err("Crop has no cells in it".into());
+    }
+
+    let cell_width = info.cell_width;
+    let corner = |n: u32| (n * cell_width) as f64 * info.h_scale as f64;
+    let cropped = MapInfo {
+        width: cols.len() as u32 * cell_width,
+        height: rows.len() as u32 * cell_width,
+        grid: cells
+            .iter()
+            .map(|&(_, (row, col))| GridEntry::at(row, col))
+            .collect(),
+        georef: info.georef.as_ref().map(|georef| {
+            georef.moved_to(WorldPos::new(corner(cols.start), 0.0, corner(rows.start)))
+        }),
+        ..info.clone()
+    };
+
+    write_cells(out.as_ref(), &cropped, &cells, |_, from, to| {
+        copy_cell(from, to, &cropped)
+    })?;
+    Ok(cropped)
+}
+
+/// Put the maps of `parts` in one grid and write it to `out`. They have to
+/// have the same `cell-size` and `h-scale` and can't overlap; whatever else
+/// the manifest says comes from the first part, and the georeference from
+/// the first that has one. Cells are copied as they are if every part's
+/// elevations are stored alike, and stored again over the mosaic's whole
+/// range if not. Colour, normal and water maps are only kept if every part
+/// has them
+pub fn mosaic(out: impl AsRef<Path>, parts: &[Part], name: &str) -> Result<MapInfo, String> {
+    let maps = parts
+        .iter()
+        .map(|part| open(&part.map))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (_, first) = maps.first().ok_or("Mosaic has no maps in it")?;
+    let cell_width = first.cell_width;
+    let h_scale = first.h_scale;
+    if maps.iter().any(|(_, info)| {
+        info.cell_width != cell_width || (info.h_scale - h_scale).abs() > 1e-6 * h_scale
+    }) {
+        return Err("Maps in a mosaic have to have the same `cell-size` and `h-scale`".into());
+    }
+
+    // Each part's corner, in cells
+    let mut corners = Vec::with_capacity(parts.len());
+    let mut next = (0, 0);
+    for (part, (_, info)) in parts.iter().zip(&maps) {
+        let (row, col) = part.at.unwrap_or(next);
+        corners.push((row, col));
+        next = (row, col + info.grid_size().1 as u32);
+    }
+
+    let mut cells = vec![];
+    for ((dir, info), &(row0, col0)) in maps.iter().zip(&corners) {
+        for entry in &info.grid {
+            let (row, col) = entry.position().ok_or("Cell without a position")?;
+            cells.push((dir.join(entry.name()), (row0 + row, col0 + col)));
+        }
+    }
+    let mut positions = cells.iter().map(|&(_, at)| at).collect::<Vec<_>>();
+    positions.sort();
+    if positions.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err("Maps in the mosaic overlap".into());
+    }
+
+    let (rows, cols) = maps
+        .iter()
+        .zip(&corners)
+        .map(|((_, info), &(row, col))| {
+            let (rows, cols) = info.grid_size();
+            (row + rows as u32, col + cols as u32)
+        })
+        .fold((0, 0), |(rows, cols), (r, c)| (rows.max(r), cols.max(c)));
+    let all = |has: fn(&MapInfo) -> bool| maps.iter().all(|(_, info)| has(info));
+    let min = |of: fn(&MapInfo) -> f32| {
+        maps.iter()
+            .map(|(_, info)| of(info))
+            .fold(f32::MAX, f32::min)
+    };
+    let max = |of: fn(&MapInfo) -> f32| {
+        maps.iter()
+            .map(|(_, info)| of(info))
+            .fold(f32::MIN, f32::max)
+    };
+
+    let (min_elevation, max_elevation) = (min(|i| i.min_elevation), max(|i| i.max_elevation));
+    let stored_alike = maps.iter().all(|(_, info)| {
+        info.v_scale == first.v_scale && info.base_elevation == first.base_elevation
+    });
+    let quantizer = if stored_alike {
+        Quantizer {
+            base: first.base_elevation,
+            v_scale: first.v_scale,
+        }
+    } else {
+        Quantizer::new(min_elevation, max_elevation)
+    };
+
+    let georef = maps
+        .iter()
+        .zip(&corners)
+        .find_map(|((_, info), &(row, col))| {
+            let corner = |n: u32| -((n * cell_width) as f64 * h_scale as f64);
+            let georef = info.georef.as_ref()?;
+            Some(georef.moved_to(WorldPos::new(corner(col), 0.0, corner(row))))
+        });
+
+    let info = MapInfo {
+        name: name.into(),
+        v_scale: quantizer.v_scale,
+        base_elevation: quantizer.base,
+        min_elevation,
+        max_elevation,
+        min_sky: min(|i| i.min_sky),
+        max_sky: max(|i| i.max_sky),
+        width: cols * cell_width,
+        height: rows * cell_width,
+        has_color: all(|i| i.has_color),
+        has_normals: all(|i| i.has_normals),
+        has_water: all(|i| i.has_water),
+        grid: cells
+            .iter()
+            .map(|&(_, (row, col))| GridEntry::at(row, col))
+            .collect(),
+        georef,
+        ..first.clone()
+    };
+
+    // Which part each cell came from, for its elevations
+    let sources = maps
+        .iter()
+        .flat_map(|(_, part)| part.grid.iter().map(move |_| part))
+        .collect::<Vec<_>>();
+    write_cells(out.as_ref(), &info, &cells, |index, from, to| {
+        copy_cell(from, to, &info)?;
+        if stored_alike {
+            return Ok(());
+        }
+        let path = to.join("hf.cell");
+        let mut cell = Cell::new(&path, (0, 0), None, None, cell_width)?;
+        requantize(&mut cell, sources[index], &quantizer);
+        cell.save(path)
+    })?;
+    Ok(info)
+}
+
+/// Write `map` to `out` with its cells and their colour and normal maps cut
+/// down to `depth` levels. Trees that are no deeper are copied as they are
+pub fn downsample(
+    map: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    depth: u32,
+) -> Result<MapInfo, String> {
+    if depth == 0 {
+        return Err("Depth has to be at least 1".into());
+    }
+    let (dir, info) = open(map.as_ref())?;
+    let cells = info
+        .grid
+        .iter()
+        .map(|entry| {
+            let at = entry.position().ok_or("Cell without a position")?;
+            Ok((dir.join(entry.name()), at))
+        })
+        .collect::<Result<Vec<_>, &'static str>>()?;
+
+    write_cells(out.as_ref(), &info, &cells, |_, from, to| {
+        copy_cell(from, to, &info)?;
+        let path = to.join("hf.cell");
+        let cell = Cell::new(&path, (0, 0), None, None, info.cell_width)?;
+        if cell.depth > depth {
+            let tree = cell.tree.pruned(depth);
+            Cell {
+                depth: tree.depth(),
+                tree,
+                ..cell
+            }
+            .save(path)?;
+        }
+
+        let textures = [
+            (info.has_color, "color.tqt"),
+            (info.has_normals, "norm.tqt"),
+        ];
+        for (_, file) in textures.into_iter().filter(|&(has, _)| has) {
+            let path = to.join(file);
+            let texture = TexturedQuadTree::new(&path)?;
+            if texture.depth <= depth {
+                continue;
+            }
+            let lod = texture.lod.pruned(depth);
+            let codec = texture.codec;
+            TexturedQuadTree {
+                depth: lod.depth(),
+                lod,
+                ..texture
+            }
+            .save(path, codec)?;
+        }
+        Ok(())
+    })?;
+    Ok(info)
+}
+
+/// The directory of the map at `path`, which may be its map.json, and its
+/// manifest
+fn open(path: &Path) -> Result<(PathBuf, MapInfo), String> {
+    let (dir, manifest) = if path.is_file() {
+        (path.parent().ok_or("Invalid map path")?, path.to_path_buf())
+    } else {
+        (path, path.join("map.json"))
+    };
+    let info = MapInfo::load(manifest)?;
+
+    Ok((dir.to_path_buf(), info))
+}
+
+/// Check `info`, then make the directory of each cell in `out` from the
+/// one it comes from, in parallel, and write the manifest. `make` gets the
+/// cell's index in `cells`
+fn write_cells(
+    out: &Path,
+    info: &MapInfo,
+    cells: &[(PathBuf, (u32, u32))],
+    make: impl Fn(usize, &Path, &Path) -> Result<(), &'static str> + Sync,
+) -> Result<(), &'static str> {
+    info.validate()?;
+    std::fs::create_dir_all(out).map_err(|_| "Unable to create map directory")?;
+    let out_dir = out.canonicalize().ok();
+    if cells
+        .iter()
+        .any(|(from, _)| from.parent().and_then(|dir| dir.canonicalize().ok()) == out_dir)
+    {
+        return Err("Can't write a map over one it's made from");
+    }
+
+    cells
+        .par_iter()
+        .enumerate()
+        .try_for_each(|(index, (from, (row, col)))| {
+            let to = out.join(GridEntry::at(*row, *col).name());
+            std::fs::create_dir_all(&to).map_err(|_| "Unable to create cell directory")?;
+            make(index, from, &to)
+        })?;
+
+    info.save(out.join("map.json"))
+}
+
+/// Copy the files of a cell that `info` says the map has
+fn copy_cell(from: &Path, to: &Path, info: &MapInfo) -> Result<(), &'static str> {
+    let files = [
+        (true, "hf.cell"),
+        (info.has_color, "color.tqt"),
+        (info.has_normals, "norm.tqt"),
+        (info.has_water, "water.png"),
+    ];
+    for (_, file) in files.into_iter().filter(|&(has, _)| has) {
+        std::fs::copy(from.join(file), to.join(file)).map_err(|_| "Unable to copy cell file")?;
+    }
+
+    Ok(())
+}
+
+/// Store the cell's elevations, quantized for `from`, as `to` does. Morph
+/// deltas are scaled along; errors are in world units and stay
+fn requantize(cell: &mut Cell, from: &MapInfo, to: &Quantizer) {
+    let scale = from.v_scale / to.v_scale;
+    for tile in cell.tree.iter_mut() {
+        let chunk = &mut tile.chunk;
+        for vertex in chunk.vertices.iter_mut() {
+            let elevation = from.base_elevation + vertex.position[1] * from.v_scale;
+            vertex.position[1] = to.quantize(elevation) as f32;
+            vertex.morph_delta = (vertex.morph_delta * scale)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32);
+        }
+        let ys = chunk
+            .vertices
+            .iter()
+            .map(|vertex| vertex.position[1] as i16);
+        chunk.min[1] = ys.clone().min().unwrap_or(0);
+        chunk.max[1] = ys.max().unwrap_or(0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{
+        heightfield::Heightfield,
+        map::Map,
+        package::{write_package, PackageOptions},
+        test_util::TempDir,
+        texture_quadtree::TexturedQuadTree,
+    };
+
+    use super::{crop, downsample, mosaic, Part};
+
+    /// A package of a slope rising to the east, `cols` by `rows` cells of 64
+    fn slope(name: &str, cols: u32, rows: u32, rise: f32) -> TempDir {
+        let (width, height) = (cols * 64 + 1, rows * 64 + 1);
+        let samples = (0..width * height)
+            .map(|i| (i % width) as f32 * rise)
+            .collect();
+        let heights = Heightfield::new(width, height, samples).unwrap();
+        let dir = TempDir::new(name);
+        let options = PackageOptions {
+            cell_size: 64,
+            h_scale: 2.0,
+            texture_size: Some(8),
+            ..Default::default()
+        };
+        write_package(&dir, &heights, &options).unwrap();
+        dir
+    }
+
+    #[test]
+    fn crops_cells() {
+        let dir = slope("edit-crop-source", 3, 2, 1.0);
+        let out = TempDir::new("edit-crop-test-map");
+        let info = crop(&dir, &out, 1..2, 1..3).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+
+        let map = Map::new(&out).unwrap();
+        assert_eq!(map.abstract_size, (1, 2));
+        let source = Map::new(&dir).unwrap();
+        let root = |map: &Map, at| map.cell(at).unwrap().tree.root().chunk.min;
+        assert_eq!(root(&map, (0, 1)), root(&source, (1, 2)));
+
+        assert!(crop(&dir, &out, 0..3, 0..1).is_err());
+        assert!(crop(&dir, &out, 1..1, 0..1).is_err());
+    }
+
+    #[test]
+    fn mosaics_maps() {
+        let west = slope("edit-mosaic-west", 1, 1, 1.0);
+        let east = slope("edit-mosaic-east", 1, 2, 4.0);
+        let out = TempDir::new("edit-mosaic-test-map");
+        let parts = [
+            Part {
+                map: west.to_path_buf(),
+                at: None,
+            },
+            Part {
+                map: east.to_path_buf(),
+                at: None,
+            },
+        ];
+        let info = mosaic(&out, &parts, "Mosaic").unwrap();
+        assert_eq!((info.width, info.height), (128, 128));
+        assert_eq!((info.min_elevation, info.max_elevation), (0.0, 256.0));
+
+        let map = Map::new(&out).unwrap();
+        assert!(map.cell((1, 0)).is_none());
+        // Elevations come out the same, stored differently
+        let top = |map: &Map, at| {
+            let chunk = &map.cell(at).unwrap().tree.root().chunk;
+            map.info.base_elevation + chunk.max[1] as f32 * map.info.v_scale
+        };
+        let (west_map, east_map) = (Map::new(&west).unwrap(), Map::new(&east).unwrap());
+        assert!((top(&map, (0, 0)) - top(&west_map, (0, 0))).abs() < 0.1);
+        assert!((top(&map, (1, 1)) - top(&east_map, (1, 0))).abs() < 0.1);
+
+        let overlapping = [
+            parts[0].clone(),
+            Part {
+                at: Some((0, 0)),
+                ..parts[1].clone()
+            },
+        ];
+        assert!(mosaic(&out, &overlapping, "Overlapping").is_err());
+    }
+
+    #[test]
+    fn downsamples_levels() {
+        let dir = slope("edit-downsample-source", 2, 1, 1.0);
+        let out = TempDir::new("edit-downsample-test-map");
+        downsample(dir.join("map.json"), &out, 1).unwrap();
+
+        let map = Map::new(&out).unwrap();
+        for cell in map.iter_cells() {
+            assert_eq!((cell.depth, cell.tree.len()), (1, 1));
+            assert_eq!(cell.tree.root().texture.as_ref().unwrap().size, 8);
+        }
+        assert!(downsample(&dir, &out, 0).is_err());
+
+        // Colour maps deeper than cells that are already shallow enough
+        // are still cut down
+        let color = |dir: &Path| dir.join(map.info.grid[0].name()).join("color.tqt");
+        std::fs::copy(color(&dir), color(&out)).unwrap();
+        let again = TempDir::new("edit-downsample-again-test-map");
+        downsample(&out, &again, 1).unwrap();
+        assert_eq!(TexturedQuadTree::new(color(&again)).unwrap().depth, 1);
+    }
+}
@@ -0,0 +1,534 @@
+//! Getting terrain out as meshes for other tools: the chunks over a region
+//! of the map, at one level or down to an error, as OBJ or binary glTF with
+//! the color map baked into one texture.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use nalgebra::Vector3;
+use rayon::prelude::*;
+use serde_json::json;
+
+use crate::{
+    cell::tile::Tile,
+    coords::{LocalPos, WorldPos},
+    map::Map,
+    quadtree::NodeId,
+};
+
+/// How fine the exported terrain is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detail {
+    /// Tiles of this level, or the finest there are where the tree stops
+    /// short of it
+    Level(u32),
+    /// The coarsest tiles whose error is at most this, in world units.
+    /// Where tiles of different levels meet there can be small cracks
+    Tolerance(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// West, north, east and south edges, in world units
+    pub region: [f64; 4],
+    pub detail: Detail,
+    /// Texels along the longer side of the baked color texture; none is
+    /// baked without it, or if the map has no color
+    pub texture_size: Option<u32>,
+}
+
+/// An RGB image, row by row from the north west
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Triangles, wound counter-clockwise seen from above, with positions
+/// relative to `origin` so they keep their precision as `f32`s
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    /// The region's north west corner, at zero elevation
+    pub origin: WorldPos,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// Across the region, 0 to 1 from its north west corner
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+    pub texture: Option<Image>,
+}
+
+impl Mesh {
+    /// The terrain of `map` inside `options.region`. Tiles are taken whole
+    /// and their triangles kept if their middle is in the region; skirts
+    /// are left out
+    pub fn extract(map: &Map, options: &ExportOptions) -> Result<Self, &'static str> {
+        let [west, north, east, south] = options.region;
+        if !options.region.iter().all(|c| c.is_finite()) || west >= east || north >= south {
+            return Err("Region has to be a rectangle, west to east and north to south");
+        }
+        let frame = map.frame();
+        // Tiles are placed, with their boxes, as the map loads
+        let overlaps = |tile: &Tile| {
+            tile.bbox.as_ref().is_none_or(|bbox| {
+                bbox.min.x <= east
+                    && bbox.max.x >= west
+                    && bbox.min.z <= south
+                    && bbox.max.z >= north
+            })
+        };
+
+        let origin = WorldPos::new(west, 0.0, north);
+        let mut mesh = Mesh {
+            origin,
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            indices: vec![],
+            texture: None,
+        };
+        // Tiles share the vertices along their edges, and cells theirs
+        let mut welded: HashMap<[i64; 3], u32> = HashMap::new();
+        let mut face_normals: Vec<Vector3<f64>> = vec![];
+
+        for cell in map.iter_cells() {
+            let tiles = cell.tree.select(|tile| {
+                overlaps(tile)
+                    && match options.detail {
+                        Detail::Level(level) => tile.level < level,
+                        Detail::Tolerance(tolerance) => tile.chunk.max_error as f64 > tolerance,
+                    }
+            });
+            let (row, col) = cell.position;
+
+            for tile in tiles.into_iter().filter(|tile| overlaps(tile)) {
+                let world = |index: u32| {
+                    let [x, y, z] = tile.chunk.vertices[index as usize].position;
+                    let local = LocalPos::new(x as f64, y as f64, z as f64);
+                    (frame.to_world((row, col).into(), local).0, [x, y, z])
+                };
+
+                for [a, b, c] in tile.chunk.triangles() {
+                    let (pa, ka) = world(a);
+                    let (mut pb, mut kb) = world(b);
+                    let (mut pc, mut kc) = world(c);
+
+                    // Skirts hang straight down; terrain faces up
+                    let mut normal = (pb - pa).cross(&(pc - pa));
+                    if normal.y.abs() < 1e-9 {
+                        continue;
+                    }
+                    if normal.y < 0.0 {
+                        (pb, kb, pc, kc) = (pc, kc, pb, kb);
+                        normal = -normal;
+                    }
+                    let middle = (pa.coords + pb.coords + pc.coords) / 3.0;
+                    if !(west..=east).contains(&middle.x) || !(north..=south).contains(&middle.z) {
+                        continue;
+                    }
+
+                    for (position, [x, y, z]) in [(pa, ka), (pb, kb), (pc, kc)] {
+                        // Samples across the whole map, which are whole numbers
+                        let key = [
+                            (col * map.info.cell_width) as i64 + x.round() as i64,
+                            y.round() as i64,
+                            (row * map.info.cell_width) as i64 + z.round() as i64,
+                        ];
+                        let index = *welded.entry(key).or_insert_with(|| {
+                            mesh.positions.push([
+                                (position.x - west) as f32,
+                                position.y as f32,
+                                (position.z - north) as f32,
+                            ]);
+                            mesh.uvs.push([
+                                ((position.x - west) / (east - west)) as f32,
+                                ((position.z - north) / (south - north)) as f32,
+                            ]);
+                            face_normals.push(Vector3::zeros());
+                            (mesh.positions.len() - 1) as u32
+                        });
+                        // Weighted by area, as the cross product is
+                        face_normals[index as usize] += normal;
+                        mesh.indices.push(index);
+                    }
+                }
+            }
+        }
+
+        if mesh.indices.is_empty() {
+            return Err("No terrain in the region");
+        }
+        mesh.normals = face_normals
+            .iter()
+            .map(|n| {
+                let n = n.try_normalize(0.0).unwrap_or_else(Vector3::y);
+                [n.x as f32, n.y as f32, n.z as f32]
+            })
+            .collect();
+        if map.info.has_color {
+            mesh.texture = options
+                .texture_size
+                .map(|size| bake_color(map, options.region, size));
+        }
+
+        Ok(mesh)
+    }
+
+    /// Write `.obj`, with a `.mtl` and `.png` beside it for the texture, or
+    /// `.glb`, going by the extension
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), &'static str> {
+        let path = path.as_ref();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("obj") => self.save_obj(path),
+            Some("glb") => self.save_glb(path),
+            _ => Err("Meshes are saved as .obj or .glb"),
+        }
+    }
+
+    fn save_obj(&self, path: &Path) -> Result<(), &'static str> {
+        let error = |_| "Unable to write OBJ file";
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("terrain");
+        let mut out = BufWriter::new(File::create(path).map_err(error)?);
+
+        let o = self.origin.0;
+        writeln!(out, "# Positions are relative to {} {} {}", o.x, o.y, o.z).map_err(error)?;
+        if let Some(texture) = &self.texture {
+            let material = format!(
+                "newmtl terrain\nKa 1 1 1\nKd 1 1 1\nKs 0 0 0\nmap_Kd {}.png\n",
+                stem
+            );
+            std::fs::write(path.with_extension("mtl"), material).map_err(error)?;
+            std::fs::write(path.with_extension("png"), texture.encode_png()?).map_err(error)?;
+            writeln!(out, "mtllib {}.mtl", stem).map_err(error)?;
+        }
+        writeln!(out, "o {}", stem).map_err(error)?;
+
+        for [x, y, z] in &self.positions {
+            writeln!(out, "v {} {} {}", x, y, z).map_err(error)?;
+        }
+        // OBJ's textures go up from the bottom
+        for [u, v] in &self.uvs {
+            writeln!(out, "vt {} {}", u, 1.0 - v).map_err(error)?;
+        }
+        for [x, y, z] in &self.normals {
+            writeln!(out, "vn {} {} {}", x, y, z).map_err(error)?;
+        }
+        if self.texture.is_some() {
+            writeln!(out, "usemtl terrain").map_err(error)?;
+        }
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+            writeln!(out, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}").map_err(error)?;
+        }
+
+        out.flush().map_err(error)
+    }
+
+    fn save_glb(&self, path: &Path) -> Result<(), &'static str> {
+        const FLOAT: u32 = 5126;
+        const UNSIGNED_INT: u32 = 5125;
+        const ARRAY_BUFFER: u32 = 34962;
+        const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+        // Everything goes in the one binary chunk, each view 4-byte aligned
+        let mut bin: Vec<u8> = vec![];
+        let mut views = vec![];
+        let mut view = |bin: &mut Vec<u8>, data: &[u8], target: Option<u32>| {
+            while bin.len() & 3 != 0 {
+                bin.push(0);
+            }
+            let mut view = json!({
+                "buffer": 0,
+                "byteOffset": bin.len(),
+                "byteLength": data.len(),
+            });
+            if let Some(target) = target {
+                view["target"] = json!(target);
+            }
+            bin.extend_from_slice(data);
+            views.push(view);
+            views.len() - 1
+        };
+
+        let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+        for p in &self.positions {
+            for c in 0..3 {
+                min[c] = min[c].min(p[c]);
+                max[c] = max[c].max(p[c]);
+            }
+        }
+        let positions = view(
+            &mut bin,
+            bytemuck::cast_slice(&self.positions),
+            Some(ARRAY_BUFFER),
+        );
+        let normals = view(
+            &mut bin,
+            bytemuck::cast_slice(&self.normals),
+            Some(ARRAY_BUFFER),
+        );
+        let uvs = view(
+            &mut bin,
+            bytemuck::cast_slice(&self.uvs),
+            Some(ARRAY_BUFFER),
+        );
+        let indices = view(
+            &mut bin,
+            bytemuck::cast_slice(&self.indices),
+            Some(ELEMENT_ARRAY_BUFFER),
+        );
+        let n = self.positions.len();
+        let accessors = json!([
+            {"bufferView": positions, "componentType": FLOAT, "count": n, "type": "VEC3", "min": min, "max": max},
+            {"bufferView": normals, "componentType": FLOAT, "count": n, "type": "VEC3"},
+            {"bufferView": uvs, "componentType": FLOAT, "count": n, "type": "VEC2"},
+            {"bufferView": indices, "componentType": UNSIGNED_INT, "count": self.indices.len(), "type": "SCALAR"},
+        ]);
+
+        let mut primitive = json!({
+            "attributes": {"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2},
+            "indices": 3,
+        });
+        let o = self.origin.0;
+        let mut gltf = json!({
+            "asset": {"version": "2.0", "generator": env!("CARGO_PKG_NAME")},
+            "scene": 0,
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0, "name": "terrain", "extras": {"origin": [o.x, o.y, o.z]}}],
+            "accessors": accessors,
+        });
+        if let Some(texture) = &self.texture {
+            let image = view(&mut bin, &texture.encode_png()?, None);
+            primitive["material"] = json!(0);
+            gltf["materials"] = json!([{
+                "pbrMetallicRoughness": {
+                    "baseColorTexture": {"index": 0},
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 1.0,
+                }
+            }]);
+            gltf["textures"] = json!([{"source": 0, "sampler": 0}]);
+            // Linear, mipmapped, clamped to the edges
+            gltf["samplers"] =
+                json!([{"magFilter": 9729, "minFilter": 9987, "wrapS": 33071, "wrapT": 33071}]);
+            gltf["images"] = json!([{"bufferView": image, "mimeType": "image/png"}]);
+        }
+        gltf["meshes"] = json!([{"primitives": [primitive]}]);
+        while bin.len() & 3 != 0 {
+            bin.push(0);
+        }
+        gltf["bufferViews"] = json!(views);
+        gltf["buffers"] = json!([{"byteLength": bin.len()}]);
+
+        let mut json = serde_json::to_vec(&gltf).map_err(|_| "Unable to write glTF file")?;
+        while json.len() & 3 != 0 {
+            json.push(b' ');
+        }
+
+        // Header, then the JSON and binary chunks
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut glb = Vec::with_capacity(length);
+        for word in [0x46546c67, 2, length as u32, json.len() as u32, 0x4e4f534a] {
+            glb.extend(u32::to_le_bytes(word));
+        }
+        glb.extend(&json);
+        for word in [bin.len() as u32, 0x004e4942] {
+            glb.extend(u32::to_le_bytes(word));
+        }
+        glb.extend(&bin);
+
+        std::fs::write(path, glb).map_err(|_| "Unable to write glTF file")
+    }
+}
+
+impl Image {
+    pub fn encode_png(&self) -> Result<Vec<u8>, &'static str> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|_| "Unable to encode png")?;
+        Ok(out)
+    }
+}
+
+/// The color map over the region as one image, `size` texels along its
+/// longer side, from the finest tile under each texel
+fn bake_color(map: &Map, [west, north, east, south]: [f64; 4], size: u32) -> Image {
+    let (width, height) = (east - west, south - north);
+    let scale = size as f64 / width.max(height);
+    let (w, h) = (
+        ((width * scale).round() as u32).max(1),
+        ((height * scale).round() as u32).max(1),
+    );
+    let frame = map.frame();
+
+    let texel = |x: f64, z: f64| -> Option<[u8; 3]> {
+        let cell = map.cell_at_world_pos((x, z))?;
+        let local = frame.to_local(cell.position.into(), WorldPos::new(x, 0.0, z));
+        let finest = cell.depth - 1;
+        let tile_size = (map.info.cell_width >> finest) as f64;
+        let clamp = |c: f64| (c / tile_size).clamp(0.0, ((1 << finest) - 1) as f64) as u32;
+        let node = cell
+            .tree
+            .covering(NodeId::new(finest, clamp(local.0.z), clamp(local.0.x)))?;
+        let texture = cell
+            .tree
+            .get(node.level, node.row, node.col)?
+            .texture
+            .as_ref()?;
+
+        let [u, v] = frame.tex_coord(node, local).to_texel(texture.size).0;
+        let last = (texture.size - 1) as f64;
+        let (u, v) = (u.clamp(0.0, last) as usize, v.clamp(0.0, last) as usize);
+        let channels = texture.channels();
+        let i = (v * texture.size as usize + u) * channels;
+        Some([texture.image[i], texture.image[i + 1], texture.image[i + 2]])
+    };
+
+    let pixels = (0..h)
+        .into_par_iter()
+        .flat_map_iter(|j| {
+            (0..w).flat_map(move |i| {
+                let x = west + (i as f64 + 0.5) / scale;
+                let z = north + (j as f64 + 0.5) / scale;
+                // Off the map and over holes
+                texel(x, z).unwrap_or([128; 3])
+            })
+        })
+        .collect();
+
+    Image {
+        width: w,
+        height: h,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::BufReader, path::Path};
+
+    use crate::{
+        generate::{generate, TerrainOptions},
+        map::Map,
+        package::{write_package, PackageOptions},
+        test_util::TempDir,
+    };
+
+    use super::{Detail, ExportOptions, Mesh};
+
+    fn test_map(dir: &Path) -> Map {
+        let heights = generate(&TerrainOptions {
+            size: [129, 65],
+            feature_size: 32.0,
+            relief: 100.0,
+            ..Default::default()
+        })
+        .unwrap();
+        let options = PackageOptions {
+            cell_size: 64,
+            h_scale: 2.0,
+            texture_size: Some(16),
+            ..Default::default()
+        };
+        write_package(dir, &heights, &options).unwrap();
+        Map::new(dir).unwrap()
+    }
+
+    #[test]
+    fn extracts_levels() {
+        let dir = TempDir::new("export-levels-test-map");
+        let map = test_map(&dir);
+        let whole = [0.0, 0.0, 256.0, 128.0];
+        let extract = |region, detail| {
+            Mesh::extract(
+                &map,
+                &ExportOptions {
+                    region,
+                    detail,
+                    texture_size: Some(64),
+                },
+            )
+            .unwrap()
+        };
+
+        // Two roots of 32 quads a side, sharing an edge
+        let roots = extract(whole, Detail::Level(0));
+        assert_eq!(roots.indices.len(), 2 * 32 * 32 * 2 * 3);
+        assert_eq!(roots.positions.len(), 65 * 33);
+        // Every sample, since the leaves are exact
+        let leaves = extract(whole, Detail::Tolerance(0.0));
+        assert_eq!(leaves.indices.len(), 128 * 64 * 2 * 3);
+        assert_eq!(leaves.positions.len(), 129 * 65);
+        assert!(leaves.normals.iter().all(|n| n[1] > 0.0));
+
+        let texture = leaves.texture.as_ref().unwrap();
+        assert_eq!((texture.width, texture.height), (64, 32));
+
+        // The west half, with its edge on the middle of a quad
+        let half = extract([1.0, 0.0, 129.0, 128.0], Detail::Level(1));
+        assert_eq!(half.indices.len(), 64 * 64 * 2 * 3);
+        assert_eq!(half.origin.0.x, 1.0);
+        assert!(half.positions.iter().all(|p| p[0] >= -1.0 && p[0] <= 129.0));
+
+        assert!(Mesh::extract(
+            &map,
+            &ExportOptions {
+                region: [300.0, 0.0, 400.0, 100.0],
+                detail: Detail::Level(0),
+                texture_size: None,
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn writes_obj_and_glb() {
+        let dir = TempDir::new("export-files-test-map");
+        let map = test_map(&dir);
+        let mesh = Mesh::extract(
+            &map,
+            &ExportOptions {
+                region: [0.0, 0.0, 256.0, 128.0],
+                detail: Detail::Level(0),
+                texture_size: Some(32),
+            },
+        )
+        .unwrap();
+
+        let path = dir.join("export-test.obj");
+        mesh.save(&path).unwrap();
+        let file = BufReader::new(std::fs::File::open(&path).unwrap());
+        let obj: obj::Obj<obj::TexturedVertex, u32> = obj::load_obj(file).unwrap();
+        assert_eq!(obj.indices.len(), mesh.indices.len());
+        assert_eq!(obj.vertices.len(), mesh.positions.len());
+        assert!(dir.join("export-test.mtl").exists() && dir.join("export-test.png").exists());
+
+        let path = dir.join("export-test.glb");
+        mesh.save(&path).unwrap();
+        let glb = std::fs::read(&path).unwrap();
+        assert_eq!(&glb[..4], b"glTF");
+        assert_eq!(
+            u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize,
+            glb.len()
+        );
+        let json_len = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+        let gltf: serde_json::Value = serde_json::from_slice(&glb[20..20 + json_len]).unwrap();
+        assert_eq!(gltf["accessors"][3]["count"], mesh.indices.len());
+        assert_eq!(gltf["images"][0]["mimeType"], "image/png");
+
+        assert!(mesh.save(dir.join("export-test.stl")).is_err());
+    }
+}
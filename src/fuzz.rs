@@ -0,0 +1,213 @@
+//! Mutation fuzzing of the cell and texture parsers, seeded from the files in
+//! fuzz/corpus. Set FUZZ_ITERATIONS for a longer run than the default.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell as StdCell,
+    fs,
+    io::{BufReader, Cursor},
+    panic::{self, AssertUnwindSafe},
+};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{
+    cell::Cell,
+    texture_quadtree::{Texture, TexturedQuadTree},
+};
+
+/// Keeps count of the bytes each thread has allocated and not freed, and the
+/// most there were, so that tests running in parallel don't see each other's
+struct TrackingAllocator;
+
+thread_local! {
+    static CURRENT: StdCell<isize> = const { StdCell::new(0) };
+    static PEAK: StdCell<isize> = const { StdCell::new(0) };
+}
+
+fn record(grown: isize) {
+    let _ = CURRENT.try_with(|current| {
+        current.set(current.get() + grown);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(current.get())));
+    });
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size() as isize);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // The new block can arrive before the old one goes
+        record(new_size as isize);
+        record(-(layout.size() as isize));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+/// The most bytes `f` has allocated at once, all of its allocations together.
+/// The parsers decode on rayon's threads, so `f` runs in a pool of one, and
+/// that thread is the one watched
+fn peak_allocation(pool: &ThreadPool, f: impl FnOnce() + Send) -> usize {
+    pool.install(|| {
+        let before = CURRENT.with(|current| current.get());
+        PEAK.with(|peak| peak.set(before));
+        f();
+        PEAK.with(|peak| (peak.get() - before) as usize)
+    })
+}
+
+/// xorshift; good enough to mangle files with, and reproducible
+struct Mutator(u64);
+
+impl Mutator {
+    /// Values that tend to hit the edges of size checks
+    const INTERESTING: [u32; 6] = [0, 1, 0x7FFF_FFFF, 0x8000_0000, 0xFFFF_FFFE, 0xFFFF_FFFF];
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    fn mutate(&mut self, data: &mut Vec<u8>) {
+        match self.below(5) {
+            0 => {
+                let i = self.below(data.len());
+                if let Some(byte) = data.get_mut(i) {
+                    *byte ^= 1 << self.below(8);
+                }
+            }
+            1 | 2 => {
+                // Headers, counts and offsets are all 4-byte aligned
+                let i = self.below(data.len() / 4) * 4;
+                let value = if self.below(2) == 0 {
+                    Self::INTERESTING[self.below(Self::INTERESTING.len())]
+                } else {
+                    self.next() as u32
+                };
+                if let Some(word) = data.get_mut(i..i + 4) {
+                    word.copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            3 => {
+                let len = self.below(data.len());
+                data.truncate(len);
+            }
+            _ => {
+                let start = self.below(data.len());
+                let end = start + self.below(data.len() - start);
+                let at = self.below(data.len());
+                let copy = data[start..end].to_vec();
+                data.splice(at..at, copy);
+            }
+        }
+    }
+}
+
+fn corpus(kind: &str) -> Vec<(String, Vec<u8>)> {
+    let mut files: Vec<_> = fs::read_dir(format!("fuzz/corpus/{}", kind))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+
+    files
+        .into_iter()
+        .map(|path| (path.display().to_string(), fs::read(path).unwrap()))
+        .collect()
+}
+
+/// Feed mutations of every file in the corpus to `parse`, which must neither
+/// panic nor hold more than `max_allocation(len)` bytes at once
+fn fuzz(kind: &str, parse: fn(&[u8]), max_allocation: fn(usize) -> usize) {
+    let iterations = std::env::var("FUZZ_ITERATIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(100);
+    let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+    for (name, seed) in corpus(kind) {
+        let mut mutator = Mutator(0x9E37_79B9_7F4A_7C15 ^ seed.len() as u64);
+
+        for i in 0..iterations {
+            // The seed itself goes first, unmutated
+            let mut data = seed.clone();
+            if i > 0 {
+                for _ in 0..1 + mutator.below(4) {
+                    mutator.mutate(&mut data);
+                }
+            }
+
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| peak_allocation(&pool, || parse(&data))));
+
+            let failure = match result {
+                Ok(peak) if peak <= max_allocation(data.len()) => continue,
+                Ok(peak) => format!("held {} bytes at once", peak),
+                Err(_) => "panicked".to_string(),
+            };
+
+            let crash = std::env::temp_dir().join(format!("fuzz-{}-{}.bin", kind, i));
+            fs::write(&crash, &data).unwrap();
+            panic!(
+                "{} mutation {} {}; input saved to {}",
+                name,
+                i,
+                failure,
+                crash.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn cells() {
+    fuzz(
+        "cell",
+        |data| {
+            let _ = Cell::read_from(
+                &mut BufReader::new(Cursor::new(data)),
+                (0, 0),
+                None,
+                None,
+                1024,
+            );
+        },
+        // Tiles and decoded vertices are bigger than their bytes on disk, but
+        // no two chunks share them
+        |len| 64 * len + (1 << 16),
+    );
+}
+
+#[test]
+fn texture_trees() {
+    fuzz(
+        "tqt",
+        |data| {
+            let _ = TexturedQuadTree::read_from(&mut BufReader::new(Cursor::new(data)));
+        },
+        // Every tile's image within the ratio of its own share of the file,
+        // and the tree around them
+        |len| (Texture::MAX_COMPRESSION_RATIO as usize + 64) * len + (1 << 20),
+    );
+}
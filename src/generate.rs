@@ -0,0 +1,358 @@
+//! Synthetic terrain, for trying the LOD out on maps of any size: seeded
+//! noise or midpoint displacement, optionally eroded, as heightfields to
+//! write with `package`.
+
+use rayon::prelude::*;
+
+use crate::heightfield::Heightfield;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Method {
+    /// Octaves of gradient noise; rolling hills
+    #[default]
+    Fbm,
+    /// Octaves of folded noise; sharp ridges and valleys
+    Ridged,
+    /// Midpoint displacement on squares and diamonds
+    DiamondSquare,
+}
+
+#[derive(Debug, Clone)]
+pub struct TerrainOptions {
+    pub seed: u64,
+    pub method: Method,
+    /// Width and height in samples
+    pub size: [u32; 2],
+    /// Samples across the largest features the noise makes
+    pub feature_size: f64,
+    pub octaves: u32,
+    /// How much each octave, or each halving for diamond-square, keeps of
+    /// the one before
+    pub persistence: f64,
+    /// Elevations go from 0 to this
+    pub relief: f32,
+    /// Droplets of hydraulic erosion to run; none if 0
+    pub erosion: u32,
+}
+
+impl Default for TerrainOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            method: Method::Fbm,
+            size: [1025, 1025],
+            feature_size: 512.0,
+            octaves: 8,
+            persistence: 0.5,
+            relief: 1000.0,
+            erosion: 0,
+        }
+    }
+}
+
+/// The same options always give the same terrain
+pub fn generate(options: &TerrainOptions) -> Result<Heightfield, &'static str> {
+    let [width, height] = options.size;
+    if width < 2 || height < 2 {
+        return Err("Terrain has to be at least 2 samples a side");
+    }
+    if options.feature_size.is_nan() || options.feature_size <= 0.0 || options.octaves == 0 {
+        return Err("Feature size and octaves have to be positive");
+    }
+
+    let mut heights = match options.method {
+        Method::Fbm | Method::Ridged => noise_field(options),
+        Method::DiamondSquare => diamond_square(options),
+    };
+
+    // Erosion is tuned for elevations from 0 to 1, so it goes in between
+    normalize(&mut heights, 1.0);
+    if options.erosion > 0 {
+        erode(&mut heights, options.erosion, options.seed);
+        normalize(&mut heights, 1.0);
+    }
+    normalize(&mut heights, options.relief);
+    Ok(heights)
+}
+
+fn normalize(heights: &mut Heightfield, relief: f32) {
+    let (min, max) = heights.range().unwrap_or((0.0, 0.0));
+    let scale = if max > min { relief / (max - min) } else { 0.0 };
+    heights
+        .samples
+        .par_iter_mut()
+        .for_each(|h| *h = (*h - min) * scale);
+}
+
+/// Splitmix64; plenty for terrain, and the same everywhere
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.0)
+    }
+
+    /// In `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Perlin's gradient noise, from -1 to 1 or so, with the gradient at each
+/// lattice point hashed from it and the seed rather than looked up
+fn gradient_noise(seed: u64, x: f64, z: f64) -> f64 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let (fx, fz) = (x - x0, z - z0);
+    let gradient = |i: f64, j: f64| {
+        let hash = mix(seed ^ mix((i as i64 as u64) ^ mix(j as i64 as u64)));
+        let angle = (hash >> 11) as f64 / (1u64 << 53) as f64 * std::f64::consts::TAU;
+        (angle.cos(), angle.sin())
+    };
+    let dot = |di: f64, dj: f64| {
+        let (gx, gz) = gradient(x0 + di, z0 + dj);
+        gx * (fx - di) + gz * (fz - dj)
+    };
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v) = (fade(fx), fade(fz));
+
+    let north = dot(0.0, 0.0) + u * (dot(1.0, 0.0) - dot(0.0, 0.0));
+    let south = dot(0.0, 1.0) + u * (dot(1.0, 1.0) - dot(0.0, 1.0));
+    (north + v * (south - north)) * std::f64::consts::SQRT_2
+}
+
+fn noise_field(options: &TerrainOptions) -> Heightfield {
+    let [width, height] = options.size;
+    let ridged = options.method == Method::Ridged;
+    // Octaves get their own seeds, so they don't line up at the origin
+    let seeds: Vec<u64> = (0..options.octaves as u64)
+        .map(|octave| mix(options.seed.wrapping_add(octave)))
+        .collect();
+
+    let sample = |x: u32, z: u32| {
+        let (mut sum, mut amplitude, mut frequency) = (0.0, 1.0, 1.0 / options.feature_size);
+        // Ridges are sharper where the octaves before were high
+        let mut weight = 1.0;
+        for &seed in &seeds {
+            let n = gradient_noise(seed, x as f64 * frequency, z as f64 * frequency);
+            sum += amplitude
+                * if ridged {
+                    let ridge = (1.0 - n.abs()).powi(2) * weight;
+                    weight = (ridge * 2.0).clamp(0.0, 1.0);
+                    ridge
+                } else {
+                    n
+                };
+            amplitude *= options.persistence;
+            frequency *= 2.0;
+        }
+        sum as f32
+    };
+
+    let samples = (0..height)
+        .into_par_iter()
+        .flat_map_iter(|z| (0..width).map(move |x| sample(x, z)))
+        .collect();
+    Heightfield {
+        width,
+        height,
+        samples,
+    }
+}
+
+/// On the smallest square of a power of two plus one that covers the
+/// terrain, cropped to it after
+fn diamond_square(options: &TerrainOptions) -> Heightfield {
+    let [width, height] = options.size;
+    let n = (width.max(height) - 1).next_power_of_two();
+    let side = n + 1;
+    let mut rng = Rng(options.seed);
+    let mut field = Heightfield::new(side, side, vec![0.0; side as usize * side as usize]).unwrap();
+    let mut offset = |amplitude: f64| ((rng.next_f64() * 2.0 - 1.0) * amplitude) as f32;
+
+    for (x, z) in [(0, 0), (n, 0), (0, n), (n, n)] {
+        field.set(x, z, offset(1.0));
+    }
+
+    let (mut step, mut amplitude) = (n, options.persistence);
+    while step > 1 {
+        let half = step / 2;
+        // Squares: the middle of each from its corners
+        for z in (half..n).step_by(step as usize) {
+            for x in (half..n).step_by(step as usize) {
+                let mean = (field.get(x - half, z - half)
+                    + field.get(x + half, z - half)
+                    + field.get(x - half, z + half)
+                    + field.get(x + half, z + half))
+                    / 4.0;
+                field.set(x, z, mean + offset(amplitude));
+            }
+        }
+        // Diamonds: the middle of each edge from its ends and the squares'
+        // middles either side, fewer at the border
+        for z in (0..=n).step_by(half as usize) {
+            let start = if (z / half) & 1 == 0 { half } else { 0 };
+            for x in (start..=n).step_by(step as usize) {
+                let neighbours = [
+                    (x.checked_sub(half), Some(z)),
+                    ((x + half <= n).then_some(x + half), Some(z)),
+                    (Some(x), z.checked_sub(half)),
+                    (Some(x), (z + half <= n).then_some(z + half)),
+                ];
+                let (sum, count) = neighbours
+                    .iter()
+                    .filter_map(|&(x, z)| Some(field.get(x?, z?)))
+                    .fold((0.0, 0), |(sum, count), h| (sum + h, count + 1));
+                field.set(x, z, sum / count as f32 + offset(amplitude));
+            }
+        }
+        step = half;
+        amplitude *= options.persistence;
+    }
+
+    field.crop(0, 0, width, height)
+}
+
+/// Droplets run downhill from random places, picking sediment up where
+/// they speed up and leaving it where they slow down, after Hans Beyer's
+/// "Implementation of a method for hydraulic erosion"
+fn erode(heights: &mut Heightfield, droplets: u32, seed: u64) {
+    const LIFETIME: u32 = 30;
+    const INERTIA: f64 = 0.05;
+    const CAPACITY: f64 = 4.0;
+    const MIN_CAPACITY: f64 = 0.01;
+    const DEPOSITION: f64 = 0.3;
+    const EROSION: f64 = 0.3;
+    const EVAPORATION: f64 = 0.01;
+    const GRAVITY: f64 = 4.0;
+
+    let (width, height) = (heights.width as f64, heights.height as f64);
+    let mut rng = Rng(mix(seed ^ 0xe705));
+
+    // Elevation and gradient, and the cell's corner and where in it
+    let surface = |heights: &Heightfield, x: f64, z: f64| {
+        let (x0, z0) = (x.floor() as u32, z.floor() as u32);
+        let (fx, fz) = (x - x0 as f64, z - z0 as f64);
+        let h = |dx, dz| heights.get(x0 + dx, z0 + dz) as f64;
+        let (nw, ne, sw, se) = (h(0, 0), h(1, 0), h(0, 1), h(1, 1));
+        let gx = (ne - nw) * (1.0 - fz) + (se - sw) * fz;
+        let gz = (sw - nw) * (1.0 - fx) + (se - ne) * fx;
+        let elevation = nw * (1.0 - fx) * (1.0 - fz)
+            + ne * fx * (1.0 - fz)
+            + sw * (1.0 - fx) * fz
+            + se * fx * fz;
+        (elevation, gx, gz, (x0, z0), (fx, fz))
+    };
+    let spread = |heights: &mut Heightfield, (x0, z0): (u32, u32), (fx, fz): (f64, f64), amount| {
+        for (dx, dz, weight) in [
+            (0, 0, (1.0 - fx) * (1.0 - fz)),
+            (1, 0, fx * (1.0 - fz)),
+            (0, 1, (1.0 - fx) * fz),
+            (1, 1, fx * fz),
+        ] {
+            let h = heights.get(x0 + dx, z0 + dz);
+            heights.set(x0 + dx, z0 + dz, h + (amount * weight) as f32);
+        }
+    };
+
+    for _ in 0..droplets {
+        let (mut x, mut z) = (
+            rng.next_f64() * (width - 1.0),
+            rng.next_f64() * (height - 1.0),
+        );
+        let (mut dir_x, mut dir_z) = (0.0, 0.0);
+        let (mut speed, mut water, mut sediment) = (1.0, 1.0, 0.0);
+
+        for _ in 0..LIFETIME {
+            let (elevation, gx, gz, corner, offset) = surface(heights, x, z);
+            dir_x = dir_x * INERTIA - gx * (1.0 - INERTIA);
+            dir_z = dir_z * INERTIA - gz * (1.0 - INERTIA);
+            let length = (dir_x * dir_x + dir_z * dir_z).sqrt();
+            if length < 1e-9 {
+                break;
+            }
+            (dir_x, dir_z) = (dir_x / length, dir_z / length);
+
+            let (next_x, next_z) = (x + dir_x, z + dir_z);
+            if !(0.0..width - 1.0).contains(&next_x) || !(0.0..height - 1.0).contains(&next_z) {
+                break;
+            }
+            let drop = surface(heights, next_x, next_z).0 - elevation;
+
+            let capacity = (-drop * speed * water * CAPACITY).max(MIN_CAPACITY);
+            if sediment > capacity || drop > 0.0 {
+                // Uphill it fills the pit it's leaving, at most
+                let deposit = if drop > 0.0 {
+                    drop.min(sediment)
+                } else {
+                    (sediment - capacity) * DEPOSITION
+                };
+                sediment -= deposit;
+                spread(heights, corner, offset, deposit);
+            } else {
+                let taken = ((capacity - sediment) * EROSION).min(-drop);
+                sediment += taken;
+                spread(heights, corner, offset, -taken);
+            }
+
+            speed = (speed * speed + drop.abs() * GRAVITY).sqrt();
+            water *= 1.0 - EVAPORATION;
+            (x, z) = (next_x, next_z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heightfield::Heightfield;
+
+    use super::{erode, generate, Method, TerrainOptions};
+
+    #[test]
+    fn terrain_is_seeded() {
+        for method in [Method::Fbm, Method::Ridged, Method::DiamondSquare] {
+            let options = TerrainOptions {
+                method,
+                size: [97, 65],
+                feature_size: 32.0,
+                octaves: 5,
+                ..Default::default()
+            };
+            let heights = generate(&options).unwrap();
+            assert_eq!((heights.width, heights.height), (97, 65));
+            assert!(!heights.has_voids());
+            assert_eq!(heights.range(), Some((0.0, 1000.0)));
+
+            assert_eq!(generate(&options).unwrap(), heights);
+            let reseeded = TerrainOptions { seed: 1, ..options };
+            assert_ne!(generate(&reseeded).unwrap(), heights, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn erosion_carves() {
+        let options = TerrainOptions {
+            size: [65, 65],
+            feature_size: 32.0,
+            relief: 1.0,
+            ..Default::default()
+        };
+        let heights = generate(&options).unwrap();
+        let mut eroded = heights.clone();
+        erode(&mut eroded, 2000, 0);
+
+        assert!(!eroded.has_voids());
+        assert_ne!(eroded, heights);
+        // Material only moves downhill, or leaves with the droplets
+        let volume = |field: &Heightfield| field.samples.iter().map(|&h| h as f64).sum::<f64>();
+        assert!(volume(&eroded) <= volume(&heights) + 1e-3);
+        assert!(eroded.range().unwrap().1 <= heights.range().unwrap().1);
+    }
+}
@@ -0,0 +1,333 @@
+//! Where a map is on Earth, when its map.json says so under `georef`, and
+//! going between world coordinates and latitude, longitude and elevation.
+//! Latitudes and longitudes are on WGS 84, in degrees.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::coords::WorldPos;
+
+/// How the map's world is laid on Earth. World x is east and z is south of
+/// `origin`, the map's north west corner, and y is elevation, all in `units`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Georef {
+    #[serde(flatten)]
+    pub projection: Projection,
+    /// Easting and northing in metres for UTM, longitude and latitude in
+    /// degrees for equirectangular
+    pub origin: [f64; 2],
+    #[serde(default)]
+    pub units: Units,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "projection", rename_all = "kebab-case")]
+pub enum Projection {
+    /// A zone of the Universal Transverse Mercator, north of the equator
+    /// unless `south`
+    Utm {
+        zone: u8,
+        #[serde(default)]
+        south: bool,
+    },
+    /// Plate carrée around the origin; fine for small maps away from the
+    /// poles
+    Equirectangular,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Units {
+    #[default]
+    Metres,
+    Feet,
+}
+
+impl Units {
+    pub fn in_metres(self) -> f64 {
+        match self {
+            Units::Metres => 1.0,
+            Units::Feet => 0.3048,
+        }
+    }
+}
+
+/// A point on Earth, elevation in metres
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPos {
+    pub lat: f64,
+    pub lon: f64,
+    pub elevation: f64,
+}
+
+impl fmt::Display for GeoPos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (ns, ew) = (
+            if self.lat < 0.0 { 'S' } else { 'N' },
+            if self.lon < 0.0 { 'W' } else { 'E' },
+        );
+        write!(
+            f,
+            "{:.5}° {}, {:.5}° {}, {:.0} m",
+            self.lat.abs(),
+            ns,
+            self.lon.abs(),
+            ew,
+            self.elevation
+        )
+    }
+}
+
+/// WGS 84
+const A: f64 = 6378137.0;
+const F: f64 = 1.0 / 298.257223563;
+/// Mean radius, for the equirectangular projection
+const R: f64 = 6371008.8;
+
+const UTM_SCALE: f64 = 0.9996;
+const UTM_EASTING: f64 = 500000.0;
+const UTM_SOUTH_NORTHING: f64 = 10000000.0;
+
+impl Georef {
+    /// Errors name `georef`, as `MapInfo::validate` does its keys
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.origin.iter().all(|c| c.is_finite()) {
+            return Err("`georef` origin has to be two numbers");
+        }
+
+        match self.projection {
+            Projection::Utm { zone, .. } if !(1..=60).contains(&zone) => {
+                Err("`georef` UTM zone has to be between 1 and 60")
+            }
+            Projection::Equirectangular if self.origin[1].abs() >= 90.0 => {
+                Err("`georef` origin latitude has to be between -90 and 90")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The georeference of a map whose north west corner is at `corner` in
+    /// this one's world; exact for UTM, close for equirectangular
+    pub fn moved_to(&self, corner: WorldPos) -> Self {
+        let metres = self.units.in_metres();
+        let origin = match self.projection {
+            Projection::Utm { .. } => {
+                let [easting, northing] = self.origin;
+                [
+                    easting + corner.0.x * metres,
+                    northing - corner.0.z * metres,
+                ]
+            }
+            Projection::Equirectangular => {
+                let geo = self.to_geo(corner);
+                [geo.lon, geo.lat]
+            }
+        };
+
+        Self {
+            origin,
+            ..self.clone()
+        }
+    }
+
+    pub fn to_geo(&self, world: WorldPos) -> GeoPos {
+        let metres = self.units.in_metres();
+        let (east, south) = (world.0.x * metres, world.0.z * metres);
+        let elevation = world.0.y * metres;
+
+        let (lat, lon) = match self.projection {
+            Projection::Utm { zone, south: s } => {
+                let [easting, northing] = self.origin;
+                utm::to_lat_lon(zone, s, easting + east, northing - south)
+            }
+            Projection::Equirectangular => {
+                let [lon0, lat0] = self.origin;
+                let lat = lat0 - (south / R).to_degrees();
+                let lon = lon0 + (east / (R * lat0.to_radians().cos())).to_degrees();
+                (lat, lon)
+            }
+        };
+
+        GeoPos {
+            lat,
+            lon,
+            elevation,
+        }
+    }
+
+    pub fn from_geo(&self, geo: GeoPos) -> WorldPos {
+        let (east, south) = match self.projection {
+            Projection::Utm { zone, south: s } => {
+                let [easting, northing] = self.origin;
+                let (e, n) = utm::from_lat_lon(zone, s, geo.lat, geo.lon);
+                (e - easting, northing - n)
+            }
+            Projection::Equirectangular => {
+                let [lon0, lat0] = self.origin;
+                let east = (geo.lon - lon0).to_radians() * R * lat0.to_radians().cos();
+                (east, (lat0 - geo.lat).to_radians() * R)
+            }
+        };
+
+        let metres = self.units.in_metres();
+        WorldPos::new(east / metres, geo.elevation / metres, south / metres)
+    }
+}
+
+/// Transverse Mercator on the ellipsoid, after Snyder's "Map Projections: A
+/// Working Manual"; good to well under a metre inside a zone
+mod utm {
+    use super::{A, F, UTM_EASTING, UTM_SCALE, UTM_SOUTH_NORTHING};
+
+    const E2: f64 = F * (2.0 - F);
+    const EP2: f64 = E2 / (1.0 - E2);
+
+    fn central_meridian(zone: u8) -> f64 {
+        (zone as f64 * 6.0 - 183.0).to_radians()
+    }
+
+    /// Distance along the meridian from the equator to `lat`
+    fn meridian_arc(lat: f64) -> f64 {
+        let (e4, e6) = (E2 * E2, E2 * E2 * E2);
+        A * ((1.0 - E2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * lat
+            - (3.0 * E2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * lat).sin()
+            + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * lat).sin()
+            - (35.0 * e6 / 3072.0) * (6.0 * lat).sin())
+    }
+
+    /// Easting and northing, in metres, of the point in degrees
+    pub fn from_lat_lon(zone: u8, south: bool, lat: f64, lon: f64) -> (f64, f64) {
+        let (lat, lon) = (lat.to_radians(), lon.to_radians());
+        let n = A / (1.0 - E2 * lat.sin().powi(2)).sqrt();
+        let t = lat.tan().powi(2);
+        let c = EP2 * lat.cos().powi(2);
+        let a = lat.cos() * (lon - central_meridian(zone));
+
+        let easting = UTM_SCALE
+            * n
+            * (a + (1.0 - t + c) * a.powi(3) / 6.0
+                + (5.0 - 18.0 * t + t * t + 72.0 * c - 58.0 * EP2) * a.powi(5) / 120.0)
+            + UTM_EASTING;
+        let northing = UTM_SCALE
+            * (meridian_arc(lat)
+                + n * lat.tan()
+                    * (a * a / 2.0
+                        + (5.0 - t + 9.0 * c + 4.0 * c * c) * a.powi(4) / 24.0
+                        + (61.0 - 58.0 * t + t * t + 600.0 * c - 330.0 * EP2) * a.powi(6) / 720.0));
+
+        (
+            easting,
+            if south {
+                northing + UTM_SOUTH_NORTHING
+            } else {
+                northing
+            },
+        )
+    }
+
+    /// Latitude and longitude of the point, in degrees
+    pub fn to_lat_lon(zone: u8, south: bool, easting: f64, northing: f64) -> (f64, f64) {
+        let northing = if south {
+            northing - UTM_SOUTH_NORTHING
+        } else {
+            northing
+        };
+        let (e4, e6) = (E2 * E2, E2 * E2 * E2);
+        let mu = northing / UTM_SCALE / (A * (1.0 - E2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0));
+        let e1 = (1.0 - (1.0 - E2).sqrt()) / (1.0 + (1.0 - E2).sqrt());
+
+        // The footpoint latitude
+        let lat1 = mu
+            + (3.0 * e1 / 2.0 - 27.0 * e1.powi(3) / 32.0) * (2.0 * mu).sin()
+            + (21.0 * e1 * e1 / 16.0 - 55.0 * e1.powi(4) / 32.0) * (4.0 * mu).sin()
+            + (151.0 * e1.powi(3) / 96.0) * (6.0 * mu).sin()
+            + (1097.0 * e1.powi(4) / 512.0) * (8.0 * mu).sin();
+
+        let sin2 = lat1.sin().powi(2);
+        let c1 = EP2 * lat1.cos().powi(2);
+        let t1 = lat1.tan().powi(2);
+        let n1 = A / (1.0 - E2 * sin2).sqrt();
+        let r1 = A * (1.0 - E2) / (1.0 - E2 * sin2).powf(1.5);
+        let d = (easting - UTM_EASTING) / (n1 * UTM_SCALE);
+
+        let lat = lat1
+            - (n1 * lat1.tan() / r1)
+                * (d * d / 2.0
+                    - (5.0 + 3.0 * t1 + 10.0 * c1 - 4.0 * c1 * c1 - 9.0 * EP2) * d.powi(4) / 24.0
+                    + (61.0 + 90.0 * t1 + 298.0 * c1 + 45.0 * t1 * t1
+                        - 252.0 * EP2
+                        - 3.0 * c1 * c1)
+                        * d.powi(6)
+                        / 720.0);
+        let lon = central_meridian(zone)
+            + (d - (1.0 + 2.0 * t1 + c1) * d.powi(3) / 6.0
+                + (5.0 - 2.0 * c1 + 28.0 * t1 - 3.0 * c1 * c1 + 8.0 * EP2 + 24.0 * t1 * t1)
+                    * d.powi(5)
+                    / 120.0)
+                / lat1.cos();
+
+        (lat.to_degrees(), lon.to_degrees())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::coords::WorldPos;
+
+    use super::{utm, GeoPos, Georef, Projection, Units};
+
+    #[test]
+    fn utm_is_consistent() {
+        // On the central meridian, northing is the scaled meridian arc
+        let (e, n) = utm::from_lat_lon(31, false, 1.0, 3.0);
+        assert!((e - 500000.0).abs() < 1e-6);
+        assert!((n - 0.9996 * 110574.39).abs() < 0.1, "{}", n);
+
+        for (zone, south, lat, lon) in [
+            (31, false, 48.858222, 2.2945),
+            (33, true, -33.9, 18.4),
+            (10, false, 37.8, -122.4),
+            (60, false, 64.2, 179.0),
+        ] {
+            let (e, n) = utm::from_lat_lon(zone, south, lat, lon);
+            let (lat2, lon2) = utm::to_lat_lon(zone, south, e, n);
+            assert!((lat - lat2).abs() < 1e-7 && (lon - lon2).abs() < 1e-7);
+        }
+    }
+
+    #[test]
+    fn world_and_geo() {
+        let georef = Georef {
+            projection: Projection::Utm {
+                zone: 31,
+                south: false,
+            },
+            origin: [440000.0, 5420000.0],
+            units: Units::Feet,
+        };
+        let world = WorldPos::new(1000.0, 300.0, 2500.0);
+        let geo = georef.to_geo(world);
+        assert!((geo.elevation - 91.44).abs() < 1e-9);
+        assert!((45.0..50.0).contains(&geo.lat) && (0.0..3.0).contains(&geo.lon));
+        assert!((georef.from_geo(geo).0 - world.0).norm() < 1e-3);
+
+        let georef = Georef {
+            projection: Projection::Equirectangular,
+            origin: [-70.0, -33.0],
+            units: Units::Metres,
+        };
+        let geo = georef.to_geo(WorldPos::new(0.0, 10.0, 111195.0));
+        assert!((geo.lat + 34.0).abs() < 1e-4 && geo.lon == -70.0);
+        assert_eq!(geo.to_string(), "34.00000° S, 70.00000° W, 10 m");
+        let back = georef.from_geo(GeoPos { lon: -69.0, ..geo });
+        assert!((back.0.x - 111195.0 * 33f64.to_radians().cos()).abs() < 1.0);
+
+        assert!(Georef {
+            origin: [0.0, 90.0],
+            ..georef.clone()
+        }
+        .validate()
+        .is_err());
+    }
+}
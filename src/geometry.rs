@@ -147,55 +147,34 @@ pub struct Frustum {
 }
 
 impl Frustum {
-    /// Make a view frustum given the current camera status
+    /// Make a view frustum given the current camera status. Each face is
+    /// the last row of the view-projection plus or minus one of the others,
+    /// so it bounds exactly what gets drawn, whichever way the camera's
+    /// vectors point
     pub fn new(camera: &Camera) -> Self {
-        let half_h_side = camera.far_z * (camera.fov * 0.5).to_radians().tan();
-        let half_v_side = half_h_side * camera.asepect_ratio;
-
-        let front = camera.front();
-        let right = camera.right();
-        let front_mult_far = camera.far_z * front;
+        let m = camera.proj_transform() * camera.view_transform();
+        let face = |sign: f64, row: usize| {
+            let p = m.row(3) + m.row(row) * sign;
+            let normal = Vector3::new(p[0], p[1], p[2]);
+            let length = normal.norm();
+            Plane {
+                normal: normal / length,
+                point: Point3::from(normal * (-p[3] / (length * length))),
+            }
+        };
 
         Self {
-            near_face: Plane {
-                normal: front,
-                point: camera.pos + camera.near_z * front,
-            },
-            far_face: Plane {
-                normal: -front,
-                point: camera.pos + front_mult_far,
-            },
-            right_face: Plane {
-                normal: -(front_mult_far + right * half_h_side)
-                    .cross(&camera.up())
-                    .normalize(),
-                point: camera.pos,
-            },
-            left_face: Plane {
-                normal: -camera
-                    .up()
-                    .cross(&(front_mult_far - right * half_h_side))
-                    .normalize(),
-                point: camera.pos,
-            },
-            top_face: Plane {
-                normal: (front_mult_far - right * half_v_side)
-                    .cross(&right)
-                    .normalize(),
-                point: camera.pos,
-            },
-            bottom_face: Plane {
-                normal: right
-                    .cross(&(front_mult_far + camera.up() * half_v_side))
-                    .normalize(),
-                point: camera.pos,
-            },
+            left_face: face(1.0, 0),
+            right_face: face(-1.0, 0),
+            bottom_face: face(1.0, 1),
+            top_face: face(-1.0, 1),
+            near_face: face(1.0, 2),
+            far_face: face(-1.0, 2),
         }
     }
 
-    /// Does the frustum intersect a bounding box?
-    /// I spent way too long trying to get this logic right. Didn't
-    /// succeed.
+    /// Does the frustum intersect a bounding box? Boxes near a corner may
+    /// come out as intersecting when they're outside, never the other way
     pub fn intersect(&self, abox: &AABB<f64>) -> IntersectionStatus {
         let planes = [
             &self.far_face,
@@ -230,9 +209,11 @@ impl Frustum {
 
 #[cfg(test)]
 mod test {
-    use nalgebra::Vector3;
+    use nalgebra::{Point3, Vector3};
 
-    use super::{Plane, AABB};
+    use crate::camera::Camera;
+
+    use super::{IntersectionStatus, Plane, AABB};
 
     #[test]
     fn issa_test_flight() {
@@ -255,5 +236,28 @@ mod test {
     }
 
     #[test]
-    fn issa_frustrating_test() {}
+    fn issa_frustrating_test() {
+        // What gets drawn is behind the camera's front
+        let camera = Camera::default();
+        let frustum = camera.frustum();
+        let around = |centre: Point3<f64>, half: f64| {
+            let half = Vector3::repeat(half);
+            frustum.intersect(&AABB::new(centre - half, centre + half))
+        };
+        let seen = camera.pos - camera.front() * 100.0;
+
+        assert!(matches!(around(seen, 1.0), IntersectionStatus::Inside));
+        assert!(matches!(
+            around(camera.pos, 1.0e5),
+            IntersectionStatus::Intersecting
+        ));
+        for away in [
+            camera.pos + camera.front() * 100.0,
+            seen + camera.right() * 1000.0,
+            seen + camera.up() * 1000.0,
+            camera.pos - camera.front() * 2.0 * camera.far_z,
+        ] {
+            assert!(matches!(around(away, 1.0), IntersectionStatus::Outside));
+        }
+    }
 }
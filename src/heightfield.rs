@@ -0,0 +1,232 @@
+//! Grids of elevation samples, the raw material of cells: what DEMs are
+//! read into and what the generator makes, before they're cut into chunks.
+
+/// Elevations row by row, north to south, each row west to east. NaN is a
+/// void, where the source had no data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightfield {
+    pub width: u32,
+    pub height: u32,
+    pub samples: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn new(width: u32, height: u32, samples: Vec<f32>) -> Result<Self, &'static str> {
+        if width == 0 || height == 0 {
+            return Err("Heightfield has no samples");
+        }
+        if samples.len() as u64 != width as u64 * height as u64 {
+            return Err("Heightfield has the wrong number of samples");
+        }
+
+        Ok(Self {
+            width,
+            height,
+            samples,
+        })
+    }
+
+    /// All voids
+    pub fn empty(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            samples: vec![f32::NAN; width as usize * height as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, z: u32) -> f32 {
+        self.samples[z as usize * self.width as usize + x as usize]
+    }
+
+    pub fn set(&mut self, x: u32, z: u32, value: f32) {
+        self.samples[z as usize * self.width as usize + x as usize] = value;
+    }
+
+    /// Bilinear, with `x` and `z` in samples and clamped to the edges.
+    /// Voids spread to whatever they touch
+    pub fn sample(&self, x: f64, z: f64) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f64);
+        let z = z.clamp(0.0, (self.height - 1) as f64);
+        let (x0, z0) = (x.floor() as u32, z.floor() as u32);
+        let (x1, z1) = ((x0 + 1).min(self.width - 1), (z0 + 1).min(self.height - 1));
+        let (fx, fz) = ((x - x0 as f64) as f32, (z - z0 as f64) as f32);
+
+        let north = self.get(x0, z0) * (1.0 - fx) + self.get(x1, z0) * fx;
+        let south = self.get(x0, z1) * (1.0 - fx) + self.get(x1, z1) * fx;
+        north * (1.0 - fz) + south * fz
+    }
+
+    /// Lowest and highest elevation, voids left out; `None` if it's all void
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.samples
+            .iter()
+            .filter(|h| !h.is_nan())
+            .fold(None, |range, &h| match range {
+                None => Some((h, h)),
+                Some((min, max)) => Some((min.min(h), max.max(h))),
+            })
+    }
+
+    pub fn has_voids(&self) -> bool {
+        self.samples.iter().any(|h| h.is_nan())
+    }
+
+    /// The `width` by `height` samples at `(x, z)`, which have to fit
+    pub fn crop(&self, x: u32, z: u32, width: u32, height: u32) -> Self {
+        assert!(x + width <= self.width && z + height <= self.height);
+        let samples = (z..z + height)
+            .flat_map(|row| {
+                let start = row as usize * self.width as usize + x as usize;
+                self.samples[start..start + width as usize].iter().copied()
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            samples,
+        }
+    }
+
+    /// Resampled bilinearly so that its corners stay where they are
+    pub fn resampled(&self, width: u32, height: u32) -> Self {
+        let step = |from: u32, to: u32| match to {
+            1 => 0.0,
+            _ => (from - 1) as f64 / (to - 1) as f64,
+        };
+        let (step_x, step_z) = (step(self.width, width), step(self.height, height));
+
+        let mut samples = Vec::with_capacity(width as usize * height as usize);
+        for z in 0..height {
+            for x in 0..width {
+                samples.push(self.sample(x as f64 * step_x, z as f64 * step_z));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            samples,
+        }
+    }
+
+    /// Fill the voids from the samples around them: each void gets the mean
+    /// of its neighbours that were known before it, nearest voids first, and
+    /// the filled area is smoothed a little after. Errors if there's nothing
+    /// to fill from
+    pub fn fill_voids(&mut self) -> Result<(), &'static str> {
+        if !self.has_voids() {
+            return Ok(());
+        }
+        if self.range().is_none() {
+            return Err("Heightfield is all voids");
+        }
+
+        let (width, height) = (self.width as i64, self.height as i64);
+        let neighbours = |i: usize| {
+            let (x, z) = (i as i64 % width, i as i64 / width);
+            (-1..=1)
+                .flat_map(move |dz| (-1..=1).map(move |dx| (x + dx, z + dz)))
+                .filter(move |&(nx, nz)| {
+                    (nx, nz) != (x, z) && (0..width).contains(&nx) && (0..height).contains(&nz)
+                })
+                .map(move |(nx, nz)| (nz * width + nx) as usize)
+        };
+
+        // Rings of voids, outwards from the known samples
+        let voids: Vec<usize> = (0..self.samples.len())
+            .filter(|&i| self.samples[i].is_nan())
+            .collect();
+        let mut ring: Vec<usize> = voids
+            .iter()
+            .copied()
+            .filter(|&i| neighbours(i).any(|n| !self.samples[n].is_nan()))
+            .collect();
+        let mut queued = vec![false; self.samples.len()];
+        for &i in &ring {
+            queued[i] = true;
+        }
+
+        while !ring.is_empty() {
+            let values: Vec<f32> = ring
+                .iter()
+                .map(|&i| {
+                    let known = neighbours(i)
+                        .map(|n| self.samples[n])
+                        .filter(|h| !h.is_nan());
+                    let (sum, n) = known.fold((0.0, 0), |(sum, n), h| (sum + h, n + 1));
+                    sum / n as f32
+                })
+                .collect();
+            for (&i, &value) in ring.iter().zip(&values) {
+                self.samples[i] = value;
+            }
+
+            let mut next = vec![];
+            for &i in &ring {
+                for n in neighbours(i) {
+                    if self.samples[n].is_nan() && !queued[n] {
+                        queued[n] = true;
+                        next.push(n);
+                    }
+                }
+            }
+            ring = next;
+        }
+
+        // Filling ring by ring leaves terraces; relax them
+        const SMOOTHING_PASSES: usize = 4;
+        for _ in 0..SMOOTHING_PASSES {
+            let values: Vec<f32> = voids
+                .iter()
+                .map(|&i| {
+                    let (sum, n) =
+                        neighbours(i).fold((0.0, 0), |(sum, n), j| (sum + self.samples[j], n + 1));
+                    sum / n as f32
+                })
+                .collect();
+            for (&i, &value) in voids.iter().zip(&values) {
+                self.samples[i] = value;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Heightfield;
+
+    #[test]
+    fn resamples_and_crops() {
+        let field = Heightfield::new(3, 2, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+        assert_eq!(field.sample(0.5, 0.5), 2.0);
+        assert_eq!(field.sample(-3.0, 9.0), 3.0);
+
+        let fine = field.resampled(5, 3);
+        assert_eq!(fine.get(4, 2), 5.0);
+        assert_eq!(fine.get(1, 1), 2.0);
+        assert_eq!(fine.crop(1, 1, 2, 2).samples, [2.0, 2.5, 3.5, 4.0]);
+        assert!(Heightfield::new(3, 3, vec![0.0; 6]).is_err());
+    }
+
+    #[test]
+    fn fills_voids() {
+        let mut field = Heightfield::empty(8, 8);
+        for x in 0..8 {
+            field.set(x, 0, 10.0);
+            field.set(x, 7, 20.0);
+        }
+        field.fill_voids().unwrap();
+
+        assert!(!field.has_voids());
+        let (min, max) = field.range().unwrap();
+        assert!(min >= 10.0 && max <= 20.0);
+        // Rows further south are filled from the higher edge
+        assert!(field.get(4, 1) < field.get(4, 6));
+
+        assert!(Heightfield::empty(2, 2).fill_voids().is_err());
+    }
+}
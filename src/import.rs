@@ -0,0 +1,432 @@
+//! Reading elevation models into map packages: SRTM `.hgt` tiles, ESRI
+//! ASCII grids, PGM images and headerless 16-bit rasters.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use crate::{
+    geo::{Georef, Projection, Units},
+    heightfield::Heightfield,
+    map::MapInfo,
+    package::{write_package, PackageOptions},
+};
+
+/// An elevation model as read, before it's resampled to a map's grid
+#[derive(Debug, Clone)]
+pub struct Dem {
+    pub heights: Heightfield,
+    /// Distance between samples west to east and north to south, in the
+    /// units elevations are in
+    pub spacing: [f64; 2],
+    /// Where the north west sample is, if the format says
+    pub georef: Option<Georef>,
+}
+
+/// How to read formats that don't describe themselves
+#[derive(Debug, Clone, Copy)]
+pub struct RawOptions {
+    /// Only for raw files; PGM headers have their own
+    pub size: Option<[u32; 2]>,
+    /// Raw files are little endian unless this is set; 16-bit PGMs are
+    /// always big endian
+    pub big_endian: bool,
+    /// Elevation of each step of the stored values
+    pub elevation_scale: f32,
+    /// Distance between samples
+    pub spacing: f64,
+    /// Stored value that means there's no data
+    pub nodata: Option<u16>,
+}
+
+impl Default for RawOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            big_endian: false,
+            elevation_scale: 1.0,
+            spacing: 1.0,
+            nodata: None,
+        }
+    }
+}
+
+/// Metres per degree of latitude, on the sphere `Georef` uses
+const METRES_PER_DEGREE: f64 = 6371008.8 * std::f64::consts::PI / 180.0;
+
+impl Dem {
+    /// Read the file, going by its extension: `.hgt`, `.asc`, `.pgm`, or
+    /// `.raw`/`.r16`/`.bin` for raw rasters
+    pub fn open(path: impl AsRef<Path>, raw: &RawOptions) -> Result<Self, &'static str> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let data = read_file(path)?;
+
+        match extension.as_deref() {
+            Some("hgt") => {
+                let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+                Self::from_hgt(&data, name)
+            }
+            Some("asc") => Self::from_asc(&data),
+            Some("pgm") => Self::from_pgm(&data, raw),
+            Some("raw" | "r16" | "bin") => Self::from_raw(&data, raw),
+            _ => Err("Unknown elevation model format, expected .hgt, .asc, .pgm or .raw"),
+        }
+    }
+
+    /// An SRTM tile: a square of big endian 16-bit samples covering a degree,
+    /// named after its south west corner, e.g. `N45E006`
+    pub fn from_hgt(data: &[u8], name: &str) -> Result<Self, &'static str> {
+        const VOID: i16 = i16::MIN;
+
+        let side = ((data.len() / 2) as f64).sqrt() as u32;
+        if side < 2 || (side * side * 2) as usize != data.len() {
+            return Err("HGT file isn't a square of 16-bit samples");
+        }
+        let (lat, lon) = hgt_corner(name).ok_or("HGT file isn't named like N45E006")?;
+
+        let samples = data
+            .chunks_exact(2)
+            .map(|b| match i16::from_be_bytes([b[0], b[1]]) {
+                VOID => f32::NAN,
+                h => h as f32,
+            })
+            .collect();
+
+        // Rows are north to south, so the origin is the north west corner
+        let north = lat + 1.0;
+        let step = 1.0 / (side - 1) as f64;
+        let spacing_z = step * METRES_PER_DEGREE;
+        let spacing_x = spacing_z * north.to_radians().cos();
+
+        Ok(Self {
+            heights: Heightfield::new(side, side, samples)?,
+            spacing: [spacing_x, spacing_z],
+            georef: Some(Georef {
+                projection: Projection::Equirectangular,
+                origin: [lon, north],
+                units: Units::Metres,
+            }),
+        })
+    }
+
+    /// An ESRI ASCII grid. Its projection isn't in the file, so it isn't
+    /// georeferenced
+    pub fn from_asc(data: &[u8]) -> Result<Self, &'static str> {
+        let text = std::str::from_utf8(data).map_err(|_| "ASC file isn't text")?;
+        let mut tokens = text.split_ascii_whitespace().peekable();
+
+        let mut header = |key: &str, optional: bool| -> Result<Option<f64>, &'static str> {
+            match tokens.peek() {
+                Some(token) if token.eq_ignore_ascii_case(key) => {
+                    tokens.next();
+                    let value = tokens.next().ok_or("ASC header is cut short")?;
+                    value
+                        .parse()
+                        .map(Some)
+                        .map_err(|_| "ASC header value isn't a number")
+                }
+                _ if optional => Ok(None),
+                _ => Err("ASC header is missing a key"),
+            }
+        };
+
+        let ncols = header("ncols", false)?.unwrap_or_default();
+        let nrows = header("nrows", false)?.unwrap_or_default();
+        // Corner or center, it doesn't matter without a projection
+        for (corner, center) in [("xllcorner", "xllcenter"), ("yllcorner", "yllcenter")] {
+            if header(corner, true)?.is_none() {
+                header(center, false)?;
+            }
+        }
+        let cellsize = header("cellsize", false)?.unwrap_or_default();
+        let nodata = header("nodata_value", true)?;
+
+        if ncols < 2.0 || nrows < 2.0 || ncols.fract() != 0.0 || nrows.fract() != 0.0 {
+            return Err("ASC grid needs at least two whole rows and columns");
+        }
+        if cellsize.is_nan() || cellsize <= 0.0 {
+            return Err("ASC cell size has to be positive");
+        }
+        let (width, height) = (ncols as u32, nrows as u32);
+
+        let samples = tokens
+            .map(|token| {
+                let value: f64 = token.parse().map_err(|_| "ASC grid value isn't a number")?;
+                Ok(match nodata {
+                    Some(nodata) if value == nodata => f32::NAN,
+                    _ => value as f32,
+                })
+            })
+            .collect::<Result<Vec<_>, &'static str>>()?;
+
+        Ok(Self {
+            heights: Heightfield::new(width, height, samples)?,
+            spacing: [cellsize; 2],
+            georef: None,
+        })
+    }
+
+    /// A binary (P5) or plain (P2) greymap, 8 or 16 bits a sample
+    pub fn from_pgm(data: &[u8], options: &RawOptions) -> Result<Self, &'static str> {
+        let mut header = vec![];
+        let mut at = 0;
+        // Magic, width, height and maximum value, with comments anywhere
+        while header.len() < 4 {
+            while at < data.len() && data[at].is_ascii_whitespace() {
+                at += 1;
+            }
+            if data.get(at) == Some(&b'#') {
+                while at < data.len() && data[at] != b'\n' {
+                    at += 1;
+                }
+                continue;
+            }
+            let start = at;
+            while at < data.len() && !data[at].is_ascii_whitespace() {
+                at += 1;
+            }
+            if start == at {
+                return Err("PGM header is cut short");
+            }
+            header.push(std::str::from_utf8(&data[start..at]).map_err(|_| "Invalid PGM header")?);
+        }
+        let number = |s: &str| s.parse::<u32>().map_err(|_| "Invalid PGM header");
+        let (width, height, max) = (number(header[1])?, number(header[2])?, number(header[3])?);
+        if max == 0 || max > u16::MAX as u32 {
+            return Err("PGM maximum value has to be between 1 and 65535");
+        }
+        let n = width as usize * height as usize;
+
+        let values: Vec<u16> = match header[0] {
+            "P5" => {
+                // A single whitespace after the header
+                let body = data.get(at + 1..).ok_or("PGM file has no samples")?;
+                if max < 256 {
+                    body.iter().take(n).map(|&v| v as u16).collect()
+                } else {
+                    body.chunks_exact(2)
+                        .take(n)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                        .collect()
+                }
+            }
+            "P2" => std::str::from_utf8(&data[at..])
+                .map_err(|_| "Plain PGM isn't text")?
+                .split_ascii_whitespace()
+                .take(n)
+                .map(|v| v.parse().map_err(|_| "PGM sample isn't a number"))
+                .collect::<Result<_, _>>()?,
+            _ => return Err("Only greymaps, P2 or P5, are supported"),
+        };
+        if values.len() != n {
+            return Err("PGM file is cut short");
+        }
+
+        Self::from_values(width, height, values, options)
+    }
+
+    /// Unsigned 16-bit samples with nothing around them
+    pub fn from_raw(data: &[u8], options: &RawOptions) -> Result<Self, &'static str> {
+        let [width, height] = options.size.ok_or("Raw files need their size given")?;
+        if data.len() as u64 != 2 * width as u64 * height as u64 {
+            return Err("Raw file doesn't have the size given");
+        }
+
+        let values = data
+            .chunks_exact(2)
+            .map(|b| {
+                if options.big_endian {
+                    u16::from_be_bytes([b[0], b[1]])
+                } else {
+                    u16::from_le_bytes([b[0], b[1]])
+                }
+            })
+            .collect();
+
+        Self::from_values(width, height, values, options)
+    }
+
+    fn from_values(
+        width: u32,
+        height: u32,
+        values: Vec<u16>,
+        options: &RawOptions,
+    ) -> Result<Self, &'static str> {
+        if options.spacing.is_nan() || options.spacing <= 0.0 || options.elevation_scale <= 0.0 {
+            return Err("Spacing and elevation scale have to be positive");
+        }
+
+        let samples = values
+            .into_iter()
+            .map(|v| match options.nodata {
+                Some(nodata) if v == nodata => f32::NAN,
+                _ => v as f32 * options.elevation_scale,
+            })
+            .collect();
+
+        Ok(Self {
+            heights: Heightfield::new(width, height, samples)?,
+            spacing: [options.spacing; 2],
+            georef: None,
+        })
+    }
+
+    /// Write the model to `dir` as a map package. Samples are resampled to
+    /// a square grid at the finer of the two spacings, which becomes
+    /// `h-scale`, and the grid is padded out to whole cells to the east and
+    /// south, with the padding filled like voids are. `options.h_scale` is
+    /// ignored; its georeference is used when the model has none
+    pub fn to_package(
+        &self,
+        dir: impl AsRef<Path>,
+        options: &PackageOptions,
+    ) -> Result<MapInfo, &'static str> {
+        let [spacing_x, spacing_z] = self.spacing;
+        let h_scale = spacing_x.min(spacing_z);
+        let samples =
+            |n: u32, spacing: f64| (((n - 1) as f64 * spacing / h_scale).round() as u32).max(1) + 1;
+        let (width, height) = (
+            samples(self.heights.width, spacing_x),
+            samples(self.heights.height, spacing_z),
+        );
+        // Voids are filled first, so resampling doesn't spread them
+        let mut filled = self.heights.clone();
+        filled.fill_voids()?;
+        let resampled = filled.resampled(width, height);
+
+        let cells = |n: u32| (n - 1).div_ceil(options.cell_size).max(1);
+        let mut heights = Heightfield::empty(
+            cells(width) * options.cell_size + 1,
+            cells(height) * options.cell_size + 1,
+        );
+        for z in 0..height {
+            for x in 0..width {
+                heights.set(x, z, resampled.get(x, z));
+            }
+        }
+
+        let options = PackageOptions {
+            h_scale: h_scale as f32,
+            georef: self.georef.clone().or(options.georef.clone()),
+            ..options.clone()
+        };
+        write_package(dir, &heights, &options)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, &'static str> {
+    let mut data = vec![];
+    File::open(path)
+        .map(BufReader::new)
+        .and_then(|mut reader| reader.read_to_end(&mut data))
+        .map_err(|_| "Unable to read elevation model")?;
+    Ok(data)
+}
+
+/// Latitude and longitude of the south west corner of an SRTM tile, from a
+/// name like `N45E006` or `s12w077`
+fn hgt_corner(name: &str) -> Option<(f64, f64)> {
+    let name = name.to_ascii_uppercase();
+    let (lat, lon) = name.get(..3).zip(name.get(3..7))?;
+    let signed = |part: &str, positive: char, negative: char| {
+        let value: f64 = part.get(1..)?.parse().ok()?;
+        match part.chars().next()? {
+            c if c == positive => Some(value),
+            c if c == negative => Some(-value),
+            _ => None,
+        }
+    };
+
+    Some((signed(lat, 'N', 'S')?, signed(lon, 'E', 'W')?))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{coords::WorldPos, map::Map, package::PackageOptions, test_util::TempDir};
+
+    use super::{hgt_corner, Dem, RawOptions};
+
+    #[test]
+    fn reads_formats() {
+        assert_eq!(hgt_corner("N45E006"), Some((45.0, 6.0)));
+        assert_eq!(hgt_corner("s12w077"), Some((-12.0, -77.0)));
+        assert_eq!(hgt_corner("X12W077"), None);
+
+        let asc = "ncols 3\nnrows 2\nxllcorner 0\nyllcorner 0\ncellsize 30\n\
+                   NODATA_value -9999\n1 2 3\n4 -9999 6\n";
+        let dem = Dem::from_asc(asc.as_bytes()).unwrap();
+        assert_eq!(dem.spacing, [30.0, 30.0]);
+        assert_eq!(dem.heights.get(2, 0), 3.0);
+        assert!(dem.heights.get(1, 1).is_nan());
+        assert!(Dem::from_asc(b"ncols 3\nnrows 2\n1 2 3 4 5 6").is_err());
+
+        let options = RawOptions {
+            elevation_scale: 0.5,
+            ..Default::default()
+        };
+        let pgm = b"P5\n# made by hand\n2 2\n65535\n\x00\x01\x00\x02\x01\x00\xff\xff";
+        let dem = Dem::from_pgm(pgm, &options).unwrap();
+        assert_eq!(dem.heights.samples, [0.5, 1.0, 128.0, 32767.5]);
+        let dem = Dem::from_pgm(b"P2 2 1 255 7 9", &options).unwrap();
+        assert_eq!(dem.heights.samples, [3.5, 4.5]);
+
+        let raw = [1u8, 0, 2, 0, 3, 0, 0, 0];
+        let options = RawOptions {
+            size: Some([2, 2]),
+            nodata: Some(0),
+            ..Default::default()
+        };
+        let dem = Dem::from_raw(&raw, &options).unwrap();
+        assert_eq!(dem.heights.samples[..3], [1.0, 2.0, 3.0]);
+        assert!(dem.heights.samples[3].is_nan());
+        assert!(Dem::from_raw(&raw[..6], &options).is_err());
+    }
+
+    #[test]
+    fn hgt_tiles_import() {
+        // A small tile, 1/32 of a degree between samples, with a void
+        let side = 33u32;
+        let data: Vec<u8> = (0..side * side)
+            .flat_map(|i| {
+                match i {
+                    100 => i16::MIN,
+                    i => (i % side) as i16 * 10,
+                }
+                .to_be_bytes()
+            })
+            .collect();
+        let dem = Dem::from_hgt(&data, "N45E006").unwrap();
+        assert!(dem.spacing[0] < dem.spacing[1]);
+
+        let dir = TempDir::new("hgt-test-map");
+        let options = PackageOptions {
+            name: "N45E006".into(),
+            cell_size: 64,
+            ..Default::default()
+        };
+        let info = dem.to_package(&dir, &options).unwrap();
+        // Up to the quantization
+        assert!(info.min_elevation.abs() < 0.01 && (info.max_elevation - 320.0).abs() < 0.01);
+        assert!((info.h_scale as f64 - dem.spacing[0]).abs() < 1e-3);
+
+        // Resampled to square samples, and padded to a whole cell
+        let map = Map::new(&dir).unwrap();
+        assert_eq!(map.abstract_size, (1, 1));
+        let corner = map.geo_pos(WorldPos::new(0.0, 0.0, 0.0)).unwrap();
+        assert_eq!((corner.lat, corner.lon), (46.0, 6.0));
+        let se = map
+            .geo_pos(WorldPos::new(
+                32.0 * dem.spacing[0],
+                0.0,
+                32.0 * dem.spacing[1],
+            ))
+            .unwrap();
+        assert!((se.lat - 45.0).abs() < 1e-6 && (se.lon - 7.0).abs() < 1e-6);
+    }
+}
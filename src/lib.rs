@@ -0,0 +1,25 @@
+//! The terrain data model: maps, their cells, the tiles and chunks in those,
+//! texture quadtrees, and the geometry and camera math around them. None of
+//! it needs a GPU; the Vulkan viewer is the binary, behind the `viewer`
+//! feature.
+
+pub mod camera;
+pub mod cell;
+pub mod coords;
+mod disk_util;
+pub mod edit;
+pub mod export;
+#[cfg(test)]
+mod fuzz;
+pub mod generate;
+pub mod geo;
+pub mod geometry;
+pub mod heightfield;
+pub mod import;
+pub mod map;
+pub mod package;
+pub mod quadtree;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
+pub mod texture_quadtree;
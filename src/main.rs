@@ -1,18 +1,26 @@
-use std::sync::Arc;
-mod camera;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 mod app;
-mod cell;
-mod disk_util;
-mod geometry;
-mod map;
-mod quadtree;
-mod texture_quadtree;
+mod cli;
+mod tile_pool;
 mod window_state;
 
 use app::{App, SwapchainState};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use cli::{Cli, Command, TerrainArgs};
+use group_project::{
+    edit::{crop, downsample, mosaic},
+    export::Mesh,
+    generate::generate,
+    import::Dem,
+    package::write_package,
+    stats::StatsReporter,
+};
 use vulkano::{
-    instance::debug::{DebugUtilsMessenger, DebugUtilsMessengerCreateInfo},
+    instance::debug::{DebugUtilsMessageType, DebugUtilsMessenger, DebugUtilsMessengerCreateInfo},
     sync::GpuFuture,
 };
 use window_state::WindowState;
@@ -24,33 +32,245 @@ use winit::{
 };
 
 mod util {
-    use crate::map::Map;
+    use std::{io::IsTerminal, path::Path};
 
-    pub fn get_map() -> Map {
-        let mut args = std::env::args();
-        let _ = args.next();
-        let map_path = args.next().unwrap();
+    use group_project::{
+        coords::WorldPos,
+        map::{Map, MapInfo},
+    };
+
+    /// Load the map, with a progress bar on stderr when it's a terminal, or
+    /// exit with why it couldn't be. `keep_blocks` is passed on to `Map::load`
+    pub fn load_map(path: &Path, keep_blocks: bool) -> Map {
+        let show_progress = std::io::stderr().is_terminal();
+        let progress = |loaded: usize, total: usize| {
+            // Redrawing for every tile would be most of the work; every 64th will do
+            if show_progress && (loaded & 63 == 0 || loaded == total) {
+                const WIDTH: usize = 40;
+                let done = WIDTH * loaded / total.max(1);
+                eprint!(
+                    "\rloading [{}{}] {}/{} tiles",
+                    "#".repeat(done),
+                    " ".repeat(WIDTH - done),
+                    loaded,
+                    total
+                );
+            }
+        };
 
-        Map::new(map_path).unwrap()
+        let map = Map::load(path, keep_blocks, progress);
+        if show_progress {
+            eprintln!();
+        }
+        map.unwrap_or_else(|e| {
+            eprintln!("error: unable to load map {}: {}", path.display(), e);
+            std::process::exit(1)
+        })
+    }
+
+    pub fn print_written(info: &MapInfo, out: &Path) {
+        println!(
+            "wrote {} to {}: {}x{} samples, elevation {}..{}",
+            info.name,
+            out.display(),
+            info.width,
+            info.height,
+            info.min_elevation,
+            info.max_elevation
+        );
+    }
+
+    pub fn print_info(map: &Map) {
+        let info = &map.info;
+        println!("{}", info.name);
+        println!(
+            "  {}x{} samples in {}x{} cells of {}",
+            info.width, info.height, map.abstract_size.1, map.abstract_size.0, info.cell_width
+        );
+        println!(
+            "  scale {} horizontal, {} vertical; elevation {}..{}",
+            info.h_scale, info.v_scale, info.min_elevation, info.max_elevation
+        );
+        if let Some(georef) = &info.georef {
+            let corner = |x, z| {
+                let geo = map.geo_pos(WorldPos::new(x, 0.0, z)).unwrap();
+                format!("{:.5}, {:.5}", geo.lat, geo.lon)
+            };
+            println!(
+                "  {:?} from {:?} in {:?}; north west corner {}, south east corner {}",
+                georef.projection,
+                georef.origin,
+                georef.units,
+                corner(map.west(), map.north()),
+                corner(map.east(), map.south())
+            );
+        }
+        for cell in map.iter_cells() {
+            let texture_size = cell
+                .tree
+                .items_at_level(0)
+                .first()
+                .and_then(|tile| tile.texture.as_ref())
+                .map(|texture| texture.size);
+            println!(
+                "  cell {:?}: depth {}, texture size {}",
+                cell.position,
+                cell.depth,
+                texture_size.map_or("-".to_string(), |s| s.to_string())
+            );
+        }
     }
 }
 
 fn main() {
-    let map = util::get_map();
+    let cli = Cli::parse();
+
+    let (map_path, view, n_frames) = match cli.command {
+        Some(Command::Import {
+            input,
+            out,
+            package,
+            raw,
+        }) => {
+            let info = Dem::open(&input, &raw.options())
+                .and_then(|dem| dem.to_package(&out, &package.options(&out)))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: unable to import {}: {}", input.display(), e);
+                    std::process::exit(1)
+                });
+            util::print_written(&info, &out);
+            return;
+        }
+        Some(Command::Generate {
+            out,
+            terrain,
+            package,
+        }) => {
+            let mut options = package.options(&out);
+            options.h_scale = terrain.h_scale;
+            options.texture_size = options.texture_size.or(Some(TerrainArgs::TEXTURE_SIZE));
+            let info = terrain
+                .options(options.cell_size)
+                .and_then(|terrain| generate(&terrain))
+                .and_then(|heights| write_package(&out, &heights, &options))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: unable to generate {}: {}", out.display(), e);
+                    std::process::exit(1)
+                });
+            util::print_written(&info, &out);
+            return;
+        }
+        Some(Command::Export { map, out, export }) => {
+            let map = util::load_map(&map, false);
+            let mesh = Mesh::extract(&map, &export.options(&map))
+                .and_then(|mesh| mesh.save(&out).map(|_| mesh))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: unable to export to {}: {}", out.display(), e);
+                    std::process::exit(1)
+                });
+            println!(
+                "wrote {}: {} vertices, {} triangles",
+                out.display(),
+                mesh.positions.len(),
+                mesh.indices.len() / 3
+            );
+            return;
+        }
+        Some(Command::Crop {
+            map,
+            out,
+            rows,
+            cols,
+        }) => {
+            let info = crop(&map, &out, rows, cols).unwrap_or_else(|e| {
+                eprintln!("error: unable to crop {}: {}", map.display(), e);
+                std::process::exit(1)
+            });
+            util::print_written(&info, &out);
+            return;
+        }
+        Some(Command::Mosaic { out, maps, name }) => {
+            let name = name.or_else(|| {
+                out.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            });
+            let info =
+                mosaic(&out, &maps, name.as_deref().unwrap_or("Mosaic")).unwrap_or_else(|e| {
+                    eprintln!("error: unable to write {}: {}", out.display(), e);
+                    std::process::exit(1)
+                });
+            util::print_written(&info, &out);
+            return;
+        }
+        Some(Command::Downsample { map, out, depth }) => {
+            let info = downsample(&map, &out, depth).unwrap_or_else(|e| {
+                eprintln!("error: unable to downsample {}: {}", map.display(), e);
+                std::process::exit(1)
+            });
+            util::print_written(&info, &out);
+            return;
+        }
+        Some(Command::Info { map }) => {
+            util::print_info(&util::load_map(&map, false));
+            return;
+        }
+        Some(Command::View { map, view }) => (map, view, None),
+        Some(Command::Bench { map, frames, view }) => (map, view, Some(frames)),
+        None => match cli.map {
+            Some(map) => (map, cli.view, None),
+            None => Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "no map given; pass its directory or its map.json",
+                )
+                .exit(),
+        },
+    };
+
+    // BC tiles stay as they are, to be decoded only if the device can't
+    // sample them
+    let map = util::load_map(&map_path, true);
 
-    let (window_state, event_loop) = WindowState::create(map.info.name.clone());
+    let (window_state, event_loop) =
+        WindowState::create(map.info.name.clone(), &view.window_settings());
 
-    let mut app = App::new(window_state, map);
+    let mut app = App::new(window_state, map, &view.settings());
+
+    app.reporter = if let Some(path) = &view.stats_csv {
+        match StatsReporter::csv(path, Duration::ZERO) {
+            Ok(reporter) => Some(reporter),
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                std::process::exit(1)
+            }
+        }
+    } else if view.print_stats || n_frames.is_some() {
+        Some(StatsReporter::stdout(Duration::from_secs(1)))
+    } else {
+        None
+    };
 
-    let _callback = unsafe {
+    let _callback = view.debug.severity().and_then(|message_severity| unsafe {
         DebugUtilsMessenger::new(
             app.window_state.instance.clone(),
-            DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
-                println!("Debug callback: {:?}", msg.description);
-            })),
+            DebugUtilsMessengerCreateInfo {
+                message_severity,
+                message_type: DebugUtilsMessageType {
+                    general: true,
+                    validation: true,
+                    performance: true,
+                    ..DebugUtilsMessageType::empty()
+                },
+                ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
+                    println!("Debug callback: {:?}", msg.description);
+                }))
+            },
         )
         .ok()
-    };
+    });
+
+    let mut frames_left = n_frames;
+    let start = Instant::now();
 
     let mut swapachain_state = SwapchainState::Good;
 
@@ -96,6 +316,13 @@ fn main() {
                 VirtualKeyCode::Minus => app.camera.move_backward(),
                 VirtualKeyCode::O => app.camera.reset(),
                 VirtualKeyCode::Q => *control_flow = ControlFlow::Exit,
+                VirtualKeyCode::P => {
+                    app.reporter = match app.reporter {
+                        Some(_) => None,
+                        None => Some(StatsReporter::stdout(Duration::from_secs(1))),
+                    };
+                    return;
+                }
                 _k => {}
             }
 
@@ -127,6 +354,21 @@ fn main() {
             }
 
             swapachain_state = app.draw();
+
+            if let Some(frames) = frames_left.as_mut() {
+                *frames -= 1;
+                if *frames == 0 {
+                    let elapsed = start.elapsed().as_secs_f64();
+                    let n_frames = n_frames.unwrap();
+                    println!(
+                        "{} frames in {:.2}s, {:.1} fps on average",
+                        n_frames,
+                        elapsed,
+                        n_frames as f64 / elapsed
+                    );
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
         }
 
         _ => {}
@@ -1,13 +1,34 @@
-use std::{fs::File, io::BufReader, path::Path, vec};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    vec,
+};
 
 use nalgebra::Point3;
-use serde::Deserialize;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::{cell::Cell, disk_util::interlace_alpha, texture_quadtree::TexturedQuadTree};
+use crate::{
+    cell::{
+        tile::{Tile, TileId},
+        Cell,
+    },
+    coords::{Frame, WorldPos},
+    disk_util::interlace_alpha,
+    geo::{GeoPos, Georef},
+    quadtree::Direction,
+    texture_quadtree::TexturedQuadTree,
+};
 
-#[derive(Debug, Deserialize)]
+/// The manifest of a map, its map.json
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MapInfo {
     pub name: String,
+    /// Always `MapInfo::VERSION` once loaded; older manifests are migrated
+    pub version: u32,
     #[serde(rename = "h-scale")]
     pub h_scale: f32,
     #[serde(rename = "v-scale")]
@@ -38,79 +59,454 @@ pub struct MapInfo {
     pub sun_intensity: [f32; 3],
     #[serde(rename = "ambient")]
     pub ambient_intensity: [f32; 3],
-    pub grid: Vec<String>,
-    #[serde(rename = "has-fog")]
+    /// The cells there are; missing ones are holes in the map
+    pub grid: Vec<GridEntry>,
+    #[serde(rename = "has-fog", skip_serializing_if = "Option::is_none")]
     pub has_fog: Option<bool>,
-    #[serde(rename = "fog-color")]
+    #[serde(rename = "fog-color", skip_serializing_if = "Option::is_none")]
     pub fog_color: Option<[f32; 3]>,
-    #[serde(rename = "fog-density")]
+    #[serde(rename = "fog-density", skip_serializing_if = "Option::is_none")]
     pub fog_density: Option<f32>,
+    /// Where the map is on Earth, if it's anywhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub georef: Option<Georef>,
+}
+
+/// A cell of the map and the directory it's in. Plain names say where the
+/// cell is, `RR_CC`; any other directory has to be placed explicitly
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GridEntry {
+    Named(String),
+    Placed { name: String, row: u32, col: u32 },
+}
+
+impl GridEntry {
+    /// The entry for the cell at `row`, `col`, in the directory named after it
+    pub fn at(row: u32, col: u32) -> Self {
+        Self::Named(format!("{:02}_{:02}", row, col))
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Named(name) | Self::Placed { name, .. } => name,
+        }
+    }
+
+    /// Row and column of the cell, `None` if a plain name isn't `RR_CC`
+    pub fn position(&self) -> Option<(u32, u32)> {
+        match self {
+            Self::Named(name) => {
+                let (row, col) = name.split_once('_')?;
+                let parse = |n: &str| {
+                    (!n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                        .then(|| n.parse().ok())
+                        .flatten()
+                };
+                Some((parse(row)?, parse(col)?))
+            }
+            Self::Placed { row, col, .. } => Some((*row, *col)),
+        }
+    }
+}
+
+/// Takes a manifest from one version to the next
+type Migration = fn(&mut serde_json::Map<String, Value>) -> Result<(), &'static str>;
+
+impl MapInfo {
+    /// What gets written, and what older manifests are migrated to
+    pub const VERSION: u32 = 4;
+
+    /// `MIGRATIONS[n - 1]` takes a version n manifest to version n + 1
+    const MIGRATIONS: [Migration; 3] = [
+        Self::sun_dir_normalized,
+        Self::grid_placed,
+        Self::georef_added,
+    ];
+
+    /// Every key a manifest of the current version can have, with what's
+    /// wrong when serde won't take its value
+    const KEYS: [(&'static str, &'static str); 23] = [
+        ("name", "`name` is missing or isn't a string"),
+        ("version", "`version` has to be a positive integer"),
+        ("h-scale", "`h-scale` is missing or isn't a number"),
+        ("v-scale", "`v-scale` is missing or isn't a number"),
+        ("base-elev", "`base-elev` is missing or isn't a number"),
+        ("min-elev", "`min-elev` is missing or isn't a number"),
+        ("max-elev", "`max-elev` is missing or isn't a number"),
+        ("min-sky", "`min-sky` is missing or isn't a number"),
+        ("max-sky", "`max-sky` is missing or isn't a number"),
+        ("width", "`width` is missing or isn't a whole number"),
+        ("height", "`height` is missing or isn't a whole number"),
+        (
+            "cell-size",
+            "`cell-size` is missing or isn't a whole number",
+        ),
+        ("color-map", "`color-map` is missing or isn't true or false"),
+        (
+            "normal-map",
+            "`normal-map` is missing or isn't true or false",
+        ),
+        ("water-map", "`water-map` is missing or isn't true or false"),
+        ("sun-dir", "`sun-dir` is missing or isn't three numbers"),
+        (
+            "sun-intensity",
+            "`sun-intensity` is missing or isn't three numbers",
+        ),
+        ("ambient", "`ambient` is missing or isn't three numbers"),
+        ("grid", "`grid` is missing or isn't a list of cells"),
+        ("has-fog", "`has-fog` isn't true or false"),
+        ("fog-color", "`fog-color` isn't three numbers"),
+        ("fog-density", "`fog-density` isn't a number"),
+        ("georef", "`georef` isn't a projection, an origin and units"),
+    ];
+
+    /// Read, migrate and check the manifest at `path`. Errors are strings
+    /// of their own, since some name what's in the manifest
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let file = File::open(path).map_err(|_| "Unable to open map.json")?;
+        let value =
+            serde_json::from_reader(BufReader::new(file)).map_err(|_| "Invalid map.json file")?;
+
+        Self::from_value(value)
+    }
+
+    /// Migrate and check a manifest
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value = serde_json::from_str(json).map_err(|_| "Invalid map.json file")?;
+
+        Self::from_value(value)
+    }
+
+    fn from_value(mut value: Value) -> Result<Self, String> {
+        let object = value
+            .as_object_mut()
+            .ok_or("map.json has to be an object")?;
+
+        // Version 1 manifests didn't always say so
+        let version = match object.get("version") {
+            None => 1,
+            Some(version) => version
+                .as_u64()
+                .filter(|&v| v > 0)
+                .ok_or("`version` has to be a positive integer")?,
+        };
+        if version > Self::VERSION as u64 {
+            return Err("`version` is newer than this version of the viewer knows".into());
+        }
+        for migrate in &Self::MIGRATIONS[version as usize - 1..] {
+            migrate(object)?;
+        }
+        object.insert("version".into(), Self::VERSION.into());
+
+        let info: Self = serde_json::from_value(Value::Object(object.clone()))
+            .map_err(|e| Self::key_at_fault(object, &e))?;
+        if let Some(key) = object
+            .keys()
+            .find(|key| Self::KEYS.iter().all(|&(known, _)| *key != known))
+        {
+            return Err(format!("Unknown key `{}` in map.json", key));
+        }
+        info.validate()?;
+
+        Ok(info)
+    }
+
+    /// What's wrong with a manifest serde won't take: the missing key it
+    /// names, or else the first key it won't take on its own. Serde checks
+    /// values as it comes to them, and only then whether any are missing, so
+    /// a key on its own is only ever missing the others.
+    fn key_at_fault(
+        object: &serde_json::Map<String, Value>,
+        error: &serde_json::Error,
+    ) -> &'static str {
+        let missing = |error: &serde_json::Error| {
+            let error = error.to_string();
+            Self::KEYS
+                .iter()
+                .find(|(key, _)| error == format!("missing field `{}`", key))
+        };
+        let wrong = || {
+            Self::KEYS.iter().find(|(key, _)| {
+                object.get(*key).is_some_and(|value| {
+                    let alone = serde_json::Map::from_iter([(key.to_string(), value.clone())]);
+                    serde_json::from_value::<Self>(Value::Object(alone))
+                        .is_err_and(|error| missing(&error).is_none())
+                })
+            })
+        };
+
+        missing(error)
+            .or_else(wrong)
+            .map_or("map.json doesn't fit its version", |&(_, message)| message)
+    }
+
+    /// Version 1 viewers normalized `sun-dir` when shading, so manifests
+    /// didn't bother
+    fn sun_dir_normalized(object: &mut serde_json::Map<String, Value>) -> Result<(), &'static str> {
+        let Some(Value::Array(dir)) = object.get_mut("sun-dir") else {
+            return Ok(());
+        };
+        let Some(components) = dir.iter().map(Value::as_f64).collect::<Option<Vec<_>>>() else {
+            return Ok(());
+        };
+
+        let length = components.iter().map(|c| c * c).sum::<f64>().sqrt();
+        if length > 0.0 {
+            *dir = components.iter().map(|c| (c / length).into()).collect();
+        }
+        Ok(())
+    }
+
+    /// Version 2 grids listed every cell, row by row. Names that don't say
+    /// where their cell is get placed explicitly
+    fn grid_placed(object: &mut serde_json::Map<String, Value>) -> Result<(), &'static str> {
+        let cols = match (object.get("width"), object.get("cell-size")) {
+            (Some(width), Some(cell)) => match (width.as_u64(), cell.as_u64()) {
+                (Some(width), Some(cell)) if cell > 0 => width / cell,
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let Some(Value::Array(grid)) = object.get_mut("grid") else {
+            return Ok(());
+        };
+        if cols == 0 {
+            return Ok(());
+        }
+
+        for (idx, entry) in grid.iter_mut().enumerate() {
+            let Value::String(name) = entry else {
+                continue;
+            };
+            let (row, col) = ((idx as u64 / cols) as u32, (idx as u64 % cols) as u32);
+            if GridEntry::Named(name.clone()).position() != Some((row, col)) {
+                *entry = serde_json::json!({ "name": name, "row": row, "col": col });
+            }
+        }
+        Ok(())
+    }
+
+    /// Version 4 added `georef`, which older manifests don't have
+    fn georef_added(_: &mut serde_json::Map<String, Value>) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Rows and columns of cells the map is divided into
+    pub fn grid_size(&self) -> (usize, usize) {
+        (
+            (self.height / self.cell_width) as usize,
+            (self.width / self.cell_width) as usize,
+        )
+    }
+
+    /// Check everything the loader and the viewer count on; errors name the
+    /// key at fault
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.cell_width == 0 {
+            return Err("`cell-size` has to be positive");
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return Err("`width` and `height` have to be positive");
+        }
+
+        if !self.width.is_multiple_of(self.cell_width)
+            || !self.height.is_multiple_of(self.cell_width)
+        {
+            return Err("`width` and `height` have to be multiples of `cell-size`");
+        }
+
+        if self.grid.is_empty() {
+            return Err("`grid` has no cells");
+        }
+
+        let (rows, cols) = self.grid_size();
+        let mut positions = Vec::with_capacity(self.grid.len());
+        for entry in &self.grid {
+            let name = entry.name();
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                return Err("`grid` names have to be plain directory names");
+            }
+            let (row, col) = entry
+                .position()
+                .ok_or("`grid` names have to be RR_CC, or come with a row and column")?;
+            if row as usize >= rows || col as usize >= cols {
+                return Err("`grid` has a cell outside the map");
+            }
+            positions.push((row, col));
+        }
+
+        let mut names: Vec<_> = self.grid.iter().map(GridEntry::name).collect();
+        names.sort();
+        if names.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err("`grid` names a cell twice");
+        }
+
+        positions.sort();
+        if positions.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err("`grid` places two cells at the same spot");
+        }
+
+        if self.h_scale <= 0.0 {
+            return Err("`h-scale` has to be positive");
+        }
+
+        if self.v_scale <= 0.0 {
+            return Err("`v-scale` has to be positive");
+        }
+
+        if self.min_elevation > self.max_elevation {
+            return Err("`min-elev` can't be above `max-elev`");
+        }
+
+        if self.min_sky >= self.max_sky {
+            return Err("`min-sky` has to be below `max-sky`");
+        }
+
+        let length = self.sun_dir.iter().map(|c| c * c).sum::<f32>().sqrt();
+        if (length - 1.0).abs() > 1e-3 {
+            return Err("`sun-dir` has to be a unit vector");
+        }
+
+        let in_range = |color: &[f32; 3]| color.iter().all(|c| (0.0..=1.0).contains(c));
+        if !in_range(&self.sun_intensity) {
+            return Err("`sun-intensity` values have to be between 0 and 1");
+        }
+        if !in_range(&self.ambient_intensity) {
+            return Err("`ambient` values have to be between 0 and 1");
+        }
+        if !self.fog_color.as_ref().is_none_or(in_range) {
+            return Err("`fog-color` values have to be between 0 and 1");
+        }
+
+        if self.fog_density.is_some_and(|density| density < 0.0) {
+            return Err("`fog-density` can't be negative");
+        }
+
+        if let Some(georef) = &self.georef {
+            georef.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// The manifest as it would be written to map.json
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("MapInfo is always valid JSON")
+    }
+
+    /// Check the manifest and write it to `path`
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), &'static str> {
+        self.validate()?;
+        std::fs::write(path, self.to_json()).map_err(|_| "Unable to write map.json")
+    }
 }
 
 #[derive(Debug)]
 pub struct Map {
     pub info: MapInfo,
+    /// Rows and columns of cells
     pub abstract_size: (usize, usize),
     pub world_size: (f64, f64),
-    pub cells: Vec<Vec<Cell>>,
+    /// `cells[row][col]`, `None` where the grid has a hole
+    pub cells: Vec<Vec<Option<Cell>>>,
     pub objects: Vec<Vec<()>>,
 }
 
 impl Map {
-    pub fn new(map_dir: impl AsRef<Path>) -> Result<Self, &'static str> {
-        let map_path = map_dir.as_ref().join("map.json");
+    /// Load the map from its directory, or from its manifest, map.json or
+    /// otherwise
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, String> {
+        Self::load(path, false, |_, _| ())
+    }
 
-        let info_file = File::open(map_path).map_err(|_| "Unable to open map.json")?;
-        let info_reader = BufReader::new(info_file);
-        let info: MapInfo =
-            serde_json::from_reader(info_reader).map_err(|_| "Invalid map.json file")?;
+    /// Like `new`, calling `progress(loaded, total)` as tiles are decoded.
+    /// Cells and the tiles in them are decoded on rayon's thread pool, so
+    /// `progress` is called from those threads. With `keep_blocks`, BC1 and
+    /// BC3 textures are left as their blocks, see `TexturedQuadTree::load`
+    pub fn load(
+        path: impl AsRef<Path>,
+        keep_blocks: bool,
+        progress: impl Fn(usize, usize) + Sync,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        // Cells are beside the manifest, whatever it's called
+        let (map_dir, map_path) = if path.is_file() {
+            (path.parent().ok_or("Invalid map path")?, path.to_path_buf())
+        } else {
+            (path, path.join("map.json"))
+        };
 
-        if info.width % info.cell_width != 0 || info.height % info.cell_width != 0 {
-            return Err("width and height have to be a multiple of cell_size");
-        }
+        let info = MapInfo::load(map_path)?;
 
-        let abstract_size = (
-            (info.width / info.cell_width) as usize,
-            (info.height / info.cell_width) as usize,
-        );
+        let abstract_size = info.grid_size();
         let world_size = (info.width as f64, info.height as f64);
 
-        if abstract_size.0 * abstract_size.1 != info.grid.len() {
-            return Err("No. of cells does not match the cells in grid");
-        }
-
-        let mut cells = Vec::with_capacity(abstract_size.0 as usize);
-        for row in 0..abstract_size.0 {
-            let mut cell_row = Vec::with_capacity(abstract_size.1 as usize);
-            for col in 0..abstract_size.1 {
-                let idx = row * abstract_size.1 + col;
-                let grid_name = &info.grid[idx as usize];
-                let cell_dir = map_dir.as_ref().join(grid_name);
-                let color_tqt = if info.has_color {
-                    let mut tree = TexturedQuadTree::new(cell_dir.join("color.tqt"))?;
-                    for image in tree.lod.mut_view() {
-                        interlace_alpha(&mut image.image)
-                    }
-                    Some(tree)
-                } else {
-                    None
-                };
+        // Every entry has a position once the manifest is validated
+        let cell_dirs = info
+            .grid
+            .iter()
+            .filter_map(|entry| Some((entry.position()?, map_dir.join(entry.name()))))
+            .collect::<Vec<_>>();
 
-                let normal_tqt = if info.has_normals {
-                    Some(TexturedQuadTree::new(cell_dir.join("norm.tqt"))?)
-                } else {
-                    None
-                };
-                cell_row.push(Cell::new(
+        // Everything that gets decoded, counted up front from the offsets
+        let total = cell_dirs
+            .par_iter()
+            .map(|(_, dir)| {
+                let mut n = Cell::count_tiles(dir.join("hf.cell"), info.cell_width)?;
+                if info.has_color {
+                    n += TexturedQuadTree::count_tiles(dir.join("color.tqt"))?;
+                }
+                if info.has_normals {
+                    n += TexturedQuadTree::count_tiles(dir.join("norm.tqt"))?;
+                }
+                Ok(n)
+            })
+            .sum::<Result<usize, &'static str>>()?;
+
+        let loaded = AtomicUsize::new(0);
+        let on_tile = || progress(loaded.fetch_add(1, Ordering::Relaxed) + 1, total);
+        let load_texture = |path: PathBuf| -> Result<TexturedQuadTree, &'static str> {
+            let mut tree = TexturedQuadTree::load(path, keep_blocks, &on_tile)?;
+            // The viewer wants RGBA; some codecs already give that
+            tree.lod
+                .par_iter_mut()
+                .filter(|texture| texture.blocks.is_none() && texture.channels() == 3)
+                .for_each(|texture| interlace_alpha(&mut texture.image));
+            Ok(tree)
+        };
+
+        let cells = cell_dirs
+            .par_iter()
+            .map(|&(position, ref cell_dir)| {
+                let color_tqt = info
+                    .has_color
+                    .then(|| load_texture(cell_dir.join("color.tqt")))
+                    .transpose()?;
+                let normal_tqt = info
+                    .has_normals
+                    .then(|| load_texture(cell_dir.join("norm.tqt")))
+                    .transpose()?;
+
+                Cell::load(
                     cell_dir.join("hf.cell"),
-                    (row as u32, col as u32),
+                    position,
                     color_tqt,
                     normal_tqt,
                     info.cell_width,
-                )?);
-            }
-            cells.push(cell_row);
+                    &on_tile,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut grid: Vec<Vec<Option<Cell>>> = (0..abstract_size.0)
+            .map(|_| (0..abstract_size.1).map(|_| None).collect())
+            .collect();
+        for (cell, &((row, col), _)) in cells.into_iter().zip(&cell_dirs) {
+            grid[row as usize][col as usize] = Some(cell);
         }
+        let cells = grid;
 
         let mut map = Map {
             info,
@@ -120,18 +516,13 @@ impl Map {
             objects: vec![],
         };
 
-        let mut cells = Vec::new();
-
-        for cell_row in map.cells.iter() {
-            let mut row = Vec::new();
-            for cell in cell_row.clone().iter() {
-                let mut new_cell = (*cell).clone();
-                new_cell.put_in_map(&map);
-                row.push(new_cell);
-            }
-            cells.push(row);
-        }
-
+        // Out of the map for a moment, since placing them needs the map
+        let mut cells = std::mem::take(&mut map.cells);
+        cells
+            .par_iter_mut()
+            .flatten()
+            .flatten()
+            .for_each(|cell| cell.put_in_map(&map));
         map.cells = cells;
 
         Ok(map)
@@ -153,44 +544,214 @@ impl Map {
         self.world_size.0 as f64 * self.info.h_scale as f64
     }
 
-    pub fn scale(&self) -> [f32; 3] {
-        let h_scale = self.info.h_scale;
-        let v_scale = self.info.v_scale;
+    /// What it takes to go between the map's coordinate spaces
+    pub fn frame(&self) -> Frame {
+        Frame::new(&self.info)
+    }
 
-        [h_scale, v_scale, h_scale]
+    /// Where `pos` is on Earth, if the map is georeferenced
+    pub fn geo_pos(&self, pos: WorldPos) -> Option<GeoPos> {
+        Some(self.info.georef.as_ref()?.to_geo(pos))
     }
 
     pub fn world_cell_width(&self) -> f64 {
-        self.info.cell_width as f64 * self.info.h_scale as f64
+        self.frame().world_cell_width()
     }
 
+    /// The cell under `(x, z)`; `None` off the map or over a hole
     pub fn cell_at_world_pos(&self, (x, z): (f64, f64)) -> Option<&Cell> {
-        if x < 0f64 || z < 0f64 {
-            return None;
-        }
+        let cell = self.frame().cell_at(WorldPos::new(x, 0.0, z))?;
+        self.cell(cell.into())
+    }
 
-        Some(
-            &self.cells[(z / self.world_cell_width()) as usize]
-                [(x / self.world_cell_width()) as usize],
-        )
+    pub fn cell_world_pos(&self, position: (u32, u32)) -> Option<Point3<f64>> {
+        self.cell(position).map(Cell::corner_world_position)
+    }
+
+    pub fn cell(&self, (row, col): (u32, u32)) -> Option<&Cell> {
+        self.cells.get(row as usize)?.get(col as usize)?.as_ref()
     }
 
-    pub fn cell_world_pos(&self, (row, col): (usize, usize)) -> Point3<f64> {
-        self.cells[row][col].corner_world_position()
+    /// Every cell there is, row by row
+    pub fn iter_cells(&self) -> impl Iterator<Item = &Cell> + Clone {
+        self.cells.iter().flatten().flatten()
+    }
+
+    pub fn tile(&self, id: TileId) -> Option<&Tile> {
+        self.cell(id.cell)?.tree.get(id.level, id.row, id.col)
+    }
+
+    /// The tile next to `id` in `direction`, on the same level, or on a
+    /// coarser one where the tree there doesn't go as deep. Crosses into the
+    /// next cell at the edge of a cell; `None` at the edge of the map
+    pub fn neighbour(&self, id: TileId, direction: Direction) -> Option<TileId> {
+        let cell = self.cell(id.cell)?;
+        let node = id.node();
+        if let Some(neighbour) = node.neighbour(direction) {
+            return cell
+                .tree
+                .covering(neighbour)
+                .map(|n| TileId::in_cell(id.cell, n));
+        }
+
+        let (d_row, d_col) = direction.offset();
+        let (row, col) = (id.cell.0 as i64 + d_row, id.cell.1 as i64 + d_col);
+        let next_cell = (u32::try_from(row).ok()?, u32::try_from(col).ok()?);
+
+        let neighbour = node.wrapped_neighbour(direction);
+        self.cell(next_cell)?
+            .tree
+            .covering(neighbour)
+            .map(|n| TileId::in_cell(next_cell, n))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Map, MapInfo};
+    use std::sync::Mutex;
+
+    use crate::{
+        cell::tile::TileId,
+        geo::{Georef, Projection, Units},
+        quadtree::Direction,
+        test_util::TempDir,
+    };
+
+    use super::{GridEntry, Map, MapInfo};
 
     #[test]
     fn can_read_json() {
         let content1 = include_str!("../maps/test-map1/map.json");
         let content2 = include_str!("../maps/test-map2/map.json");
-        let d1: MapInfo = serde_json::from_str(content1).unwrap();
-        let d2: MapInfo = serde_json::from_str(content2).unwrap();
+        let d1 = MapInfo::from_json(content1).unwrap();
+        let d2 = MapInfo::from_json(content2).unwrap();
         println!("{d1:?}\n{d2:?}");
+
+        // Both are version 1, whose sun didn't have to be normalized
+        assert_eq!(d1.version, MapInfo::VERSION);
+        let [x, y, z] = d1.sun_dir;
+        assert!((x * x + y * y + z * z - 1.0).abs() < 1e-6);
+        assert!(x < 0.0 && y > 0.0 && z < 0.0);
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let mut info = MapInfo::from_json(include_str!("../maps/test-map1/map.json")).unwrap();
+        info.fog_color = Some([0.5, 0.5, 0.6]);
+        info.fog_density = Some(0.01);
+
+        let json = info.to_json();
+        assert!(!json.contains("has-fog") && !json.contains("georef"));
+        assert_eq!(MapInfo::from_json(&json), Ok(info.clone()));
+
+        info.georef = Some(Georef {
+            projection: Projection::Utm {
+                zone: 32,
+                south: false,
+            },
+            origin: [500000.0, 5200000.0],
+            units: Units::Metres,
+        });
+        let json = info.to_json();
+        assert!(json.contains(r#""projection": "utm""#));
+        assert_eq!(MapInfo::from_json(&json), Ok(info.clone()));
+
+        info.georef = Some(Georef {
+            projection: Projection::Utm {
+                zone: 61,
+                south: false,
+            },
+            ..info.georef.unwrap()
+        });
+        assert!(info.validate().unwrap_err().contains("`georef`"));
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let json = include_str!("../maps/test-map1/map.json");
+        let error = |from: &str, to: &str| {
+            assert!(json.contains(from), "{}", from);
+            MapInfo::from_json(&json.replace(from, to)).unwrap_err()
+        };
+
+        assert!(error(r#""h-scale" : 1"#, r#""h-scale" : 0"#).contains("`h-scale`"));
+        assert!(error(r#""max-elev" : 40"#, r#""max-elev" : -40"#).contains("`min-elev`"));
+        assert!(error(r#""max-sky" : 500"#, r#""max-sky" : -1"#).contains("`min-sky`"));
+        assert!(error(r#""cell-size" : 1024"#, r#""cell-size" : 0"#).contains("`cell-size`"));
+        assert!(error(r#""ambient" : [ 0.2"#, r#""ambient" : [ 2"#).contains("`ambient`"));
+        assert!(error(r#""name""#, r#""nmae""#).contains("`name`"));
+        assert!(error(r#""name""#, r#""nmae" : "", "name""#).contains("Unknown key `nmae`"));
+        assert!(error(r#""h-scale" : 1"#, r#""h-scale" : "1""#).contains("`h-scale`"));
+        assert!(
+            error(r#""sun-dir" : [ -0.5, 1, -0.2 ]"#, r#""sun-dir" : 1"#).contains("`sun-dir`")
+        );
+        assert!(error(r#""version" : 1"#, r#""version" : 5"#).contains("`version`"));
+        let georef = r#""georef" : { "projection" : "equirectangular" }, "name""#;
+        assert!(error(r#""name""#, georef).contains("`georef`"));
+        assert!(error(r#""name" : "Synthetic map 1","#, "").contains("`name`"));
+
+        // Without a version it's version 1
+        assert!(MapInfo::from_json(&json.replace(r#""version" : 1,"#, "")).is_ok());
+
+        // Version 2 manifests get no help with their sun
+        let v2 = error(r#""version" : 1"#, r#""version" : 2"#);
+        assert!(v2.contains("`sun-dir`"));
+
+        let info = MapInfo::from_json(json).unwrap();
+        let twice = MapInfo {
+            width: 2048,
+            grid: vec![GridEntry::at(0, 0), GridEntry::at(0, 0)],
+            ..info.clone()
+        };
+        assert_eq!(twice.validate(), Err("`grid` names a cell twice"));
+
+        let grid = |grid| {
+            MapInfo {
+                grid,
+                ..info.clone()
+            }
+            .validate()
+        };
+        let placed = |name: &str, row, col| GridEntry::Placed {
+            name: name.into(),
+            row,
+            col,
+        };
+        assert_eq!(grid(vec![]), Err("`grid` has no cells"));
+        assert!(grid(vec![GridEntry::Named("cell".into())])
+            .unwrap_err()
+            .contains("RR_CC"));
+        assert!(grid(vec![placed("../00_00", 0, 0)])
+            .unwrap_err()
+            .contains("directory"));
+        assert_eq!(
+            grid(vec![GridEntry::at(0, 1)]),
+            Err("`grid` has a cell outside the map")
+        );
+        assert_eq!(
+            grid(vec![GridEntry::at(0, 0), placed("elsewhere", 0, 0)]),
+            Err("`grid` places two cells at the same spot")
+        );
+    }
+
+    #[test]
+    fn dense_grids_migrate() {
+        // Version 2 grids were rows of cells, whatever their names
+        let json = include_str!("../maps/test-map1/map.json")
+            .replace(r#""version" : 1"#, r#""version" : 2"#)
+            .replace(
+                r#""sun-dir" : [ -0.5, 1, -0.2 ]"#,
+                r#""sun-dir" : [ 0, 1, 0 ]"#,
+            )
+            .replace(r#""width" : 1024"#, r#""width" : 3072"#)
+            .replace(r#"[ "00_00" ]"#, r#"[ "00_00", "a", "00_05" ]"#);
+        let info = MapInfo::from_json(&json).unwrap();
+
+        let positions: Vec<_> = info.grid.iter().map(|e| e.position().unwrap()).collect();
+        assert_eq!(positions, [(0, 0), (0, 1), (0, 2)]);
+        assert_eq!(info.grid[0], GridEntry::at(0, 0));
+        assert_eq!(info.grid_size(), (1, 3));
+        assert!(info.to_json().contains(r#""name": "00_05""#));
     }
 
     #[test]
@@ -199,11 +760,194 @@ mod test {
         let m2 = Map::new("maps/test-map2/map.json");
         assert!(m1.is_ok());
         assert!(m2.is_ok());
+
+        // The file given is read as the manifest, whatever it's called
+        assert_eq!(
+            Map::new("maps/test-map1/00_00/hf.cell").unwrap_err(),
+            "Invalid map.json file"
+        );
+    }
+
+    #[test]
+    fn loads_without_color_or_normals() {
+        let dir = TempDir::new("bare-test-map2");
+        std::fs::create_dir_all(dir.join("00_00")).unwrap();
+        std::fs::copy("maps/test-map2/00_00/hf.cell", dir.join("00_00/hf.cell")).unwrap();
+
+        let json = include_str!("../maps/test-map2/map.json")
+            .replace(r#""color-map" : true"#, r#""color-map" : false"#)
+            .replace(r#""normal-map" : true"#, r#""normal-map" : false"#);
+        std::fs::write(dir.join("map.json"), json).unwrap();
+
+        let map = Map::new(&dir).unwrap();
+        assert!(!map.info.has_color && !map.info.has_normals);
+        for tile in map.cell((0, 0)).unwrap().tree.items_at_level(1) {
+            assert!(tile.texture.is_none() && tile.normals.is_none());
+        }
+    }
+
+    #[test]
+    fn neighbours_across_cells() {
+        // A 2x2 map where the bottom left cell is only one level deep
+        let dir = TempDir::new("neighbour-test-map");
+        for (cell, from) in [
+            ("00_00", "test-map2"),
+            ("00_01", "test-map2"),
+            ("01_00", "test-map1"),
+            ("01_01", "test-map2"),
+        ] {
+            std::fs::create_dir_all(dir.join(cell)).unwrap();
+            std::fs::copy(
+                format!("maps/{}/00_00/hf.cell", from),
+                dir.join(cell).join("hf.cell"),
+            )
+            .unwrap();
+        }
+        let json = include_str!("../maps/test-map2/map.json")
+            .replace(r#""color-map" : true"#, r#""color-map" : false"#)
+            .replace(r#""normal-map" : true"#, r#""normal-map" : false"#)
+            .replace(r#""width" : 1024"#, r#""width" : 2048"#)
+            .replace(r#""height" : 1024"#, r#""height" : 2048"#)
+            .replace(
+                r#"[ "00_00" ]"#,
+                r#"[ "00_00", "00_01", "01_00", "01_01" ]"#,
+            );
+        std::fs::write(dir.join("map.json"), json).unwrap();
+
+        let map = Map::new(&dir).unwrap();
+        let tile = |cell, level, row, col| TileId {
+            cell,
+            level,
+            row,
+            col,
+        };
+
+        // Inside a cell
+        assert_eq!(
+            map.neighbour(tile((0, 0), 2, 1, 1), Direction::North),
+            Some(tile((0, 0), 2, 0, 1))
+        );
+        // Into the cell to the east, on the same level
+        assert_eq!(
+            map.neighbour(tile((0, 0), 4, 3, 15), Direction::East),
+            Some(tile((0, 1), 4, 3, 0))
+        );
+        assert_eq!(
+            map.neighbour(tile((0, 1), 4, 3, 0), Direction::West),
+            Some(tile((0, 0), 4, 3, 15))
+        );
+        // Into the shallow cell, whose root is all there is
+        assert_eq!(
+            map.neighbour(tile((0, 0), 4, 15, 2), Direction::South),
+            Some(tile((1, 0), 0, 0, 0))
+        );
+        assert_eq!(
+            map.neighbour(tile((1, 0), 0, 0, 0), Direction::East),
+            Some(tile((1, 1), 0, 0, 0))
+        );
+        // Off the map
+        assert_eq!(map.neighbour(tile((0, 0), 3, 2, 0), Direction::West), None);
+        assert_eq!(map.neighbour(tile((1, 1), 1, 1, 1), Direction::South), None);
+        // Deeper than any tree, and too deep to shift by
+        assert_eq!(
+            map.neighbour(tile((0, 0), 40, 0, 0), Direction::East),
+            Some(tile((0, 0), 4, 0, 0))
+        );
+        for direction in Direction::ALL {
+            assert_eq!(
+                map.neighbour(tile((0, 0), u32::MAX, 0, 0), direction)
+                    .is_some(),
+                matches!(direction, Direction::East | Direction::South)
+            );
+        }
+
+        for id in [tile((0, 0), 4, 3, 15), tile((1, 0), 0, 0, 0)] {
+            assert!(map.tile(id).is_some());
+        }
+    }
+
+    #[test]
+    fn sparse_rectangular_grids() {
+        // Two rows of three cells, three of them holes
+        let dir = TempDir::new("sparse-test-map");
+        for cell in ["00_00", "00_02", "elsewhere"] {
+            std::fs::create_dir_all(dir.join(cell)).unwrap();
+            std::fs::copy(
+                "maps/test-map2/00_00/hf.cell",
+                dir.join(cell).join("hf.cell"),
+            )
+            .unwrap();
+        }
+        // Older manifests list every cell, so this has to be a current one
+        let json = include_str!("../maps/test-map2/map.json")
+            .replace(r#""version" : 1"#, r#""version" : 3"#)
+            .replace(
+                r#""sun-dir" : [ -0.5, 1, -0.2 ]"#,
+                r#""sun-dir" : [ 0, 1, 0 ]"#,
+            )
+            .replace(r#""color-map" : true"#, r#""color-map" : false"#)
+            .replace(r#""normal-map" : true"#, r#""normal-map" : false"#)
+            .replace(r#""width" : 1024"#, r#""width" : 3072"#)
+            .replace(r#""height" : 1024"#, r#""height" : 2048"#)
+            .replace(
+                r#"[ "00_00" ]"#,
+                r#"[ "00_02", "00_00", { "name": "elsewhere", "row": 1, "col": 1 } ]"#,
+            );
+        std::fs::write(dir.join("map.json"), json).unwrap();
+
+        let map = Map::new(&dir).unwrap();
+        assert_eq!(map.abstract_size, (2, 3));
+        assert_eq!(map.iter_cells().count(), 3);
+        for position in [(0, 0), (0, 2), (1, 1)] {
+            assert_eq!(map.cell(position).unwrap().position, position);
+        }
+        for position in [(0, 1), (1, 0), (1, 2), (2, 0), (0, 3)] {
+            assert!(map.cell(position).is_none());
+        }
+
+        let width = map.world_cell_width();
+        let at = |x: f64, z: f64| map.cell_at_world_pos((x * width, z * width));
+        assert_eq!(at(2.5, 0.5).unwrap().position, (0, 2));
+        assert_eq!(at(1.5, 1.5).unwrap().position, (1, 1));
+        assert!(at(1.5, 0.5).is_none());
+        assert!(at(-0.5, 0.5).is_none());
+        assert!(at(3.5, 0.5).is_none());
+        assert!(at(0.5, 2.5).is_none());
+        assert!(at(f64::NAN, 0.5).is_none());
+        assert!(map.cell_world_pos((1, 0)).is_none());
+
+        // Holes are edges too
+        let root = |cell| TileId {
+            cell,
+            level: 0,
+            row: 0,
+            col: 0,
+        };
+        assert_eq!(map.neighbour(root((0, 0)), Direction::East), None);
+        assert_eq!(map.neighbour(root((1, 1)), Direction::North), None);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let calls = Mutex::new(vec![]);
+        Map::load("maps/test-map2", false, |loaded, total| {
+            calls.lock().unwrap().push((loaded, total))
+        })
+        .unwrap();
+
+        // Chunks, colors and normals of a five level tree
+        let total = 3 * 341;
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, (1..=total).map(|n| (n, total)).collect::<Vec<_>>());
     }
 
     #[test]
     fn testing() {
         let m1 = Map::new("maps/test-map2/map.json").unwrap();
-        println!("{:?}", m1.cells[0][0].tree.items_at_level(0)[0].chunk)
+        println!(
+            "{:?}",
+            m1.cell((0, 0)).unwrap().tree.items_at_level(0)[0].chunk
+        )
     }
 }
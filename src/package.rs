@@ -0,0 +1,633 @@
+//! Writing map packages: a map.json and a directory of cells for every
+//! cell of the grid, cut from a heightfield into chunked LOD trees, with
+//! color and normal maps and a water map if asked for.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use rayon::prelude::*;
+
+use crate::{
+    cell::{
+        chunk::{Chunk, HFVertex},
+        tile::Tile,
+        Cell,
+    },
+    coords::CellPos,
+    geo::Georef,
+    heightfield::Heightfield,
+    map::{GridEntry, MapInfo},
+    quadtree::{
+        util::{full_size, node_position},
+        NodeId, QuadTree,
+    },
+    texture_quadtree::{Codec, Texture, TexturedQuadTree},
+};
+
+/// What a package gets that its heightfield doesn't say
+#[derive(Debug, Clone)]
+pub struct PackageOptions {
+    pub name: String,
+    /// Samples along a cell's side; a power of two
+    pub cell_size: u32,
+    /// World units between samples
+    pub h_scale: f32,
+    pub georef: Option<Georef>,
+    /// Texels along the side of every color and normal map tile, a power of
+    /// two; no textures are written without it
+    pub texture_size: Option<u32>,
+    /// Water wherever the terrain is below this elevation, in a water map
+    /// and the color map's shores
+    pub water_level: Option<f32>,
+}
+
+impl Default for PackageOptions {
+    fn default() -> Self {
+        Self {
+            name: "Untitled".into(),
+            cell_size: 1024,
+            h_scale: 1.0,
+            georef: None,
+            texture_size: None,
+            water_level: None,
+        }
+    }
+}
+
+/// Quads along the side of every chunk
+const CHUNK_QUADS: u32 = 32;
+/// The largest tiles texture files take
+const MAX_TEXTURE_SIZE: u32 = 4096;
+
+/// Write the heightfield to `dir` as a map package. The heightfield has a
+/// sample more than the map is wide and high, since cells share their
+/// edges; it's `cell_size * columns + 1` by `cell_size * rows + 1`. Voids
+/// are filled, and `v-scale`, `base-elev`, `min-elev` and `max-elev` are
+/// worked out from the elevations
+pub fn write_package(
+    dir: impl AsRef<Path>,
+    heights: &Heightfield,
+    options: &PackageOptions,
+) -> Result<MapInfo, &'static str> {
+    let dir = dir.as_ref();
+    let cell_size = options.cell_size;
+    if !cell_size.is_power_of_two() || cell_size > i16::MAX as u32 {
+        return Err("Cell size has to be a power of two, up to 16384");
+    }
+    let (width, height) = (heights.width - 1, heights.height - 1);
+    if width == 0 || height == 0 || width & (cell_size - 1) != 0 || height & (cell_size - 1) != 0 {
+        return Err("Heightfield has to be a multiple of the cell size, plus one, on each side");
+    }
+    if options
+        .texture_size
+        .is_some_and(|size| !size.is_power_of_two() || size > MAX_TEXTURE_SIZE)
+    {
+        return Err("Texture size has to be a power of two, up to 4096");
+    }
+
+    let mut heights = heights.clone();
+    heights.fill_voids()?;
+    let (min, max) = heights.range().ok_or("Heightfield is all voids")?;
+    let quantizer = Quantizer::new(min, max);
+
+    let info = MapInfo {
+        name: options.name.clone(),
+        version: MapInfo::VERSION,
+        h_scale: options.h_scale,
+        v_scale: quantizer.v_scale,
+        base_elevation: quantizer.base,
+        min_elevation: min,
+        max_elevation: max,
+        // Some room under the terrain, and plenty above it
+        min_sky: min - 1.0,
+        max_sky: max + 10.0 * (max - min).max(50.0),
+        width,
+        height,
+        cell_width: cell_size,
+        has_color: options.texture_size.is_some(),
+        has_normals: options.texture_size.is_some(),
+        has_water: options.water_level.is_some(),
+        sun_dir: {
+            let dir = [-0.5f32, 1.0, -0.2];
+            let length = dir.iter().map(|c| c * c).sum::<f32>().sqrt();
+            dir.map(|c| c / length)
+        },
+        sun_intensity: [0.8; 3],
+        ambient_intensity: [0.2; 3],
+        grid: (0..height / cell_size)
+            .flat_map(|row| (0..width / cell_size).map(move |col| GridEntry::at(row, col)))
+            .collect(),
+        has_fog: None,
+        fog_color: None,
+        fog_density: None,
+        georef: options.georef.clone(),
+    };
+    info.validate()?;
+
+    std::fs::create_dir_all(dir).map_err(|_| "Unable to create map directory")?;
+    info.grid.par_iter().try_for_each(|entry| {
+        let (row, col) = entry.position().ok_or("Cell without a position")?;
+        let position = CellPos::new(row, col);
+        let cell = build_cell(&heights, position, cell_size, &quantizer);
+        let cell_dir = dir.join(entry.name());
+        std::fs::create_dir_all(&cell_dir).map_err(|_| "Unable to create cell directory")?;
+        cell.save(cell_dir.join("hf.cell"))?;
+
+        if let Some(size) = options.texture_size {
+            let painter = Painter {
+                heights: &heights,
+                cell: position,
+                cell_size,
+                h_scale: options.h_scale as f64,
+                range: (min, max),
+                water_level: options.water_level,
+            };
+            let (color, normals) = painter.textures(cell.depth, size);
+            color.save(cell_dir.join("color.tqt"), Codec::Png)?;
+            normals.save(cell_dir.join("norm.tqt"), Codec::Png)?;
+        }
+        if let Some(level) = options.water_level {
+            write_water(
+                &heights,
+                position,
+                cell_size,
+                level,
+                cell_dir.join("water.png"),
+            )?;
+        }
+        Ok(())
+    })?;
+
+    info.save(dir.join("map.json"))?;
+    Ok(info)
+}
+
+/// Elevations to vertex units
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Quantizer {
+    pub base: f32,
+    pub v_scale: f32,
+}
+
+impl Quantizer {
+    /// Leave room under the lowest point for skirts
+    const TOP: f32 = 30000.0;
+
+    pub fn new(min: f32, max: f32) -> Self {
+        let range = max - min;
+        Self {
+            base: min,
+            v_scale: if range > 0.0 { range / Self::TOP } else { 1.0 },
+        }
+    }
+
+    pub fn quantize(&self, elevation: f32) -> i16 {
+        ((elevation - self.base) / self.v_scale)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+/// A cell's LOD tree from its part of the heightfield. Every chunk is a
+/// regular grid of `CHUNK_QUADS` quads a side, with skirts around it to
+/// hide cracks against coarser neighbours; the tree goes down until chunks
+/// have a vertex per sample
+fn build_cell(heights: &Heightfield, cell: CellPos, cell_size: u32, quantizer: &Quantizer) -> Cell {
+    let quads = CHUNK_QUADS.min(cell_size);
+    let depth = ((cell_size / quads).trailing_zeros() + 1).min(Cell::MAX_DEPTH);
+    let (x0, z0) = (cell.col * cell_size, cell.row * cell_size);
+    let y_at = |x: u32, z: u32| quantizer.quantize(heights.get(x0 + x, z0 + z));
+    let v_scale = quantizer.v_scale as f64;
+
+    // Errors first, as skirts hang from the parent's
+    let mut errors = (0..full_size(depth))
+        .into_par_iter()
+        .map(|index| {
+            let (level, row, col) = node_position(index);
+            let grid = Grid::new(NodeId::new(level, row, col), cell_size, quads);
+            grid.max_error(&y_at) * v_scale
+        })
+        .collect::<Vec<_>>();
+    // A tile is never better than its children
+    for index in (1..full_size(depth)).rev() {
+        let (level, row, col) = node_position(index);
+        let parent = NodeId::new(level, row, col).parent().unwrap().index();
+        errors[parent] = errors[parent].max(errors[index as usize]);
+    }
+
+    let tiles = (0..full_size(depth))
+        .into_par_iter()
+        .map(|index| {
+            let (level, row, col) = node_position(index);
+            let node = NodeId::new(level, row, col);
+            let grid = Grid::new(node, cell_size, quads);
+            let hang = node
+                .parent()
+                .map_or(errors[index as usize], |p| errors[p.index()]);
+            let skirt = (hang / v_scale).ceil() as i32 + 1;
+
+            Tile {
+                chunk: grid.chunk(&y_at, errors[index as usize] as f32, skirt),
+                position: (row, col),
+                level,
+                size: cell_size >> level,
+                texture: None,
+                normals: None,
+                bbox: None,
+            }
+        })
+        .collect();
+
+    Cell {
+        position: (cell.row, cell.col),
+        depth,
+        tree: QuadTree::from_levels(tiles, depth),
+        frame: None,
+    }
+}
+
+/// Colors and normals of a cell's tiles, from the heightfield under them
+struct Painter<'a> {
+    heights: &'a Heightfield,
+    cell: CellPos,
+    cell_size: u32,
+    h_scale: f64,
+    range: (f32, f32),
+    water_level: Option<f32>,
+}
+
+impl Painter<'_> {
+    /// Color and normal map trees as deep as the cell's, every tile `size`
+    /// texels a side whatever its level
+    fn textures(&self, depth: u32, size: u32) -> (TexturedQuadTree, TexturedQuadTree) {
+        let (color, normals): (Vec<_>, Vec<_>) = (0..full_size(depth))
+            .into_par_iter()
+            .map(|index| {
+                let (level, row, col) = node_position(index);
+                self.tile(NodeId::new(level, row, col), size)
+            })
+            .unzip();
+        let tree = |tiles| TexturedQuadTree {
+            lod: QuadTree::from_levels(tiles, depth),
+            depth,
+            tile_size: size,
+            codec: Codec::Png,
+        };
+
+        (tree(color), tree(normals))
+    }
+
+    fn tile(&self, node: NodeId, size: u32) -> (Texture, Texture) {
+        let samples = (self.cell_size >> node.level) as f64;
+        let (x0, z0) = (
+            (self.cell.col * self.cell_size) as f64 + node.col as f64 * samples,
+            (self.cell.row * self.cell_size) as f64 + node.row as f64 * samples,
+        );
+        // Coarse tiles take their normals from as far apart as their texels
+        let step = samples / size as f64;
+        let d = step.max(1.0);
+
+        let n = size as usize * size as usize * 3;
+        let (mut color, mut normals) = (Vec::with_capacity(n), Vec::with_capacity(n));
+        for i in 0..size {
+            for j in 0..size {
+                // Texel centers
+                let (x, z) = (x0 + (j as f64 + 0.5) * step, z0 + (i as f64 + 0.5) * step);
+                let h = |x, z| self.heights.sample(x, z) as f64;
+                let dx = (h(x + d, z) - h(x - d, z)) / (2.0 * d * self.h_scale);
+                let dz = (h(x, z + d) - h(x, z - d)) / (2.0 * d * self.h_scale);
+                let length = (dx * dx + 1.0 + dz * dz).sqrt();
+                let normal = [-dx / length, 1.0 / length, -dz / length];
+
+                color.extend(self.color(h(x, z) as f32, normal[1] as f32));
+                // East, south and up, as the shader reads them
+                normals.extend(
+                    [normal[0], normal[2], normal[1]]
+                        .map(|c| ((c * 0.5 + 0.5) * 255.0).round() as u8),
+                );
+            }
+        }
+
+        (
+            Texture {
+                image: color,
+                size,
+                blocks: None,
+            },
+            Texture {
+                image: normals,
+                size,
+                blocks: None,
+            },
+        )
+    }
+
+    /// Grass low down, then dry ground, rock and snow, with rock on steep
+    /// slopes and sand along the water
+    fn color(&self, elevation: f32, up: f32) -> [u8; 3] {
+        const RAMP: [(f32, [f32; 3]); 5] = [
+            (0.0, [70.0, 105.0, 55.0]),
+            (0.45, [120.0, 115.0, 75.0]),
+            (0.75, [125.0, 115.0, 105.0]),
+            (0.9, [235.0, 235.0, 240.0]),
+            (1.0, [250.0, 250.0, 252.0]),
+        ];
+        const ROCK: [f32; 3] = [105.0, 100.0, 95.0];
+        const SAND: [f32; 3] = [190.0, 175.0, 130.0];
+        let lerp =
+            |a: [f32; 3], b: [f32; 3], t: f32| std::array::from_fn(|c| a[c] + (b[c] - a[c]) * t);
+
+        let (min, max) = self.range;
+        let range = (max - min).max(f32::EPSILON);
+        let t = ((elevation - min) / range).clamp(0.0, 1.0);
+        let stop = RAMP
+            .iter()
+            .rposition(|&(at, _)| at <= t)
+            .unwrap_or(0)
+            .min(RAMP.len() - 2);
+        let ((from, a), (to, b)) = (RAMP[stop], RAMP[stop + 1]);
+        let mut color = lerp(a, b, (t - from) / (to - from));
+
+        if self
+            .water_level
+            .is_some_and(|level| elevation < level + 0.01 * range)
+        {
+            color = SAND;
+        }
+        let steep = ((1.0 - up) * 4.0).clamp(0.0, 1.0);
+        lerp(color, ROCK, steep).map(|c| c.round() as u8)
+    }
+}
+
+/// The cell's water map: a byte per sample, 255 under water and 0 above
+fn write_water(
+    heights: &Heightfield,
+    cell: CellPos,
+    cell_size: u32,
+    level: f32,
+    path: impl AsRef<Path>,
+) -> Result<(), &'static str> {
+    let side = cell_size + 1;
+    let water = heights
+        .crop(cell.col * cell_size, cell.row * cell_size, side, side)
+        .samples
+        .iter()
+        .map(|&h| if h < level { 255 } else { 0 })
+        .collect::<Vec<u8>>();
+
+    let file = File::create(path).map_err(|_| "Unable to create water map")?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), side, side);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&water))
+        .map_err(|_| "Unable to write water map")
+}
+
+/// The vertices of a tile's chunk, in samples from the cell's corner
+struct Grid {
+    node: NodeId,
+    quads: u32,
+    step: u32,
+    x0: u32,
+    z0: u32,
+}
+
+impl Grid {
+    fn new(node: NodeId, cell_size: u32, quads: u32) -> Self {
+        let size = cell_size >> node.level;
+        let quads = quads.min(size);
+        Self {
+            node,
+            quads,
+            step: size / quads,
+            x0: node.col * size,
+            z0: node.row * size,
+        }
+    }
+
+    /// The surface the chunk's triangles make at `(x, z)`, in the cell's
+    /// samples. Quads are split from south west to north east, as the strips
+    /// in `chunk` go
+    fn surface(&self, y_at: &impl Fn(u32, u32) -> i16, x: u32, z: u32) -> f64 {
+        let (u, w) = (x - self.x0, z - self.z0);
+        let (j, i) = (
+            (u / self.step).min(self.quads - 1),
+            (w / self.step).min(self.quads - 1),
+        );
+        let (fu, fw) = (
+            (u - j * self.step) as f64 / self.step as f64,
+            (w - i * self.step) as f64 / self.step as f64,
+        );
+        let corner = |di: u32, dj: u32| {
+            y_at(
+                self.x0 + (j + dj) * self.step,
+                self.z0 + (i + di) * self.step,
+            ) as f64
+        };
+
+        if fu + fw <= 1.0 {
+            let h00 = corner(0, 0);
+            h00 + fu * (corner(0, 1) - h00) + fw * (corner(1, 0) - h00)
+        } else {
+            let h11 = corner(1, 1);
+            h11 + (1.0 - fu) * (corner(1, 0) - h11) + (1.0 - fw) * (corner(0, 1) - h11)
+        }
+    }
+
+    /// Largest gap between the chunk and the samples under it, in vertex
+    /// units
+    fn max_error(&self, y_at: &impl Fn(u32, u32) -> i16) -> f64 {
+        if self.step == 1 {
+            return 0.0;
+        }
+        let size = self.quads * self.step;
+        (self.z0..=self.z0 + size)
+            .flat_map(|z| (self.x0..=self.x0 + size).map(move |x| (x, z)))
+            .map(|(x, z)| (self.surface(y_at, x, z) - y_at(x, z) as f64).abs())
+            .fold(0.0, f64::max)
+    }
+
+    fn chunk(&self, y_at: &impl Fn(u32, u32) -> i16, max_error: f32, skirt: i32) -> Chunk {
+        let side = self.quads + 1;
+        let parent = self
+            .node
+            .parent()
+            .map(|p| Grid::new(p, (self.quads * self.step) << self.node.level, self.quads));
+        let vertex = |x: u32, z: u32, drop: i32| {
+            let y = y_at(x, z);
+            let morph = parent
+                .as_ref()
+                .map_or(0.0, |p| (p.surface(y_at, x, z) - y as f64).round());
+            HFVertex {
+                position: [
+                    x as f32,
+                    (y as i32 - drop).max(i16::MIN as i32) as f32,
+                    z as f32,
+                ],
+                color: [1.0, 0.0, 0.0],
+                txt_coord: [0.0; 2],
+                morph_delta: morph.clamp(i16::MIN as f64, i16::MAX as f64) as f32,
+            }
+        };
+
+        let mut vertices = Vec::with_capacity((side * side + 4 * side) as usize);
+        for i in 0..side {
+            for j in 0..side {
+                vertices.push(vertex(self.x0 + j * self.step, self.z0 + i * self.step, 0));
+            }
+        }
+
+        let mut indices = vec![];
+        for i in 0..self.quads {
+            if i > 0 {
+                indices.push(Chunk::RESTART_INDEX);
+            }
+            for j in 0..side {
+                indices.extend([i * side + j, (i + 1) * side + j]);
+            }
+        }
+
+        // North, east, south and west edges, each with a skirt under it
+        let edges: [Vec<u32>; 4] = [
+            (0..side).collect(),
+            (0..side).map(|i| i * side + self.quads).collect(),
+            (0..side).map(|j| self.quads * side + j).collect(),
+            (0..side).map(|i| i * side).collect(),
+        ];
+        for edge in edges {
+            indices.push(Chunk::RESTART_INDEX);
+            for index in edge {
+                let top = vertices[index as usize];
+                let [x, _, z] = top.position;
+                let bottom = HFVertex {
+                    morph_delta: top.morph_delta,
+                    ..vertex(x as u32, z as u32, skirt)
+                };
+                indices.extend([index, vertices.len() as u32]);
+                vertices.push(bottom);
+            }
+        }
+
+        let mut min = [i16::MAX; 3];
+        let mut max = [i16::MIN; 3];
+        for v in &vertices {
+            for c in 0..3 {
+                min[c] = min[c].min(v.position[c] as i16);
+                max[c] = max[c].max(v.position[c] as i16);
+            }
+        }
+
+        Chunk {
+            max_error,
+            min,
+            max,
+            vertices,
+            normals: None,
+            indices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{heightfield::Heightfield, map::Map, test_util::TempDir};
+
+    use super::{write_package, PackageOptions};
+
+    #[test]
+    fn packages_load() {
+        // A 2x1 map of a tilted plane with a bump, and a void
+        let (width, height) = (129, 65);
+        let mut samples = vec![];
+        for z in 0..height {
+            for x in 0..width {
+                let bump = if (x as i32 - 40).abs() < 5 && (z as i32 - 30).abs() < 5 {
+                    25.0
+                } else {
+                    0.0
+                };
+                samples.push(100.0 + x as f32 * 0.5 + bump);
+            }
+        }
+        samples[10] = f32::NAN;
+        let heights = Heightfield::new(width, height, samples).unwrap();
+
+        let dir = TempDir::new("package-test-map");
+        let options = PackageOptions {
+            name: "Package test".into(),
+            cell_size: 64,
+            h_scale: 10.0,
+            ..Default::default()
+        };
+        let info = write_package(&dir, &heights, &options).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.min_elevation, 100.0);
+        assert_eq!(info.max_elevation, 164.0);
+
+        let map = Map::new(&dir).unwrap();
+        assert_eq!(map.abstract_size, (1, 2));
+        for cell in map.iter_cells() {
+            assert_eq!(cell.depth, 2);
+            let root = cell.tree.root();
+            for tile in cell.tree.iter() {
+                assert!(tile.chunk.max_error <= root.chunk.max_error);
+                assert!(tile.chunk.min[1] < tile.chunk.max[1]);
+            }
+            // Leaves have a vertex per sample
+            for tile in cell.tree.items_at_level(1) {
+                assert_eq!(tile.chunk.max_error, 0.0);
+            }
+        }
+        // The bump is in the west cell and its root can't quite show it
+        let west = map.cell((0, 0)).unwrap().tree.root();
+        let east = map.cell((0, 1)).unwrap().tree.root();
+        assert!(west.chunk.max_error > 1.0);
+        assert!(east.chunk.max_error < 1e-2);
+
+        // Vertices sit on the samples, in world units once placed
+        let y = |x: f32| (100.0 + x * 0.5 - map.info.base_elevation) / map.info.v_scale;
+        let vertex = east.chunk.vertices[3];
+        assert!((vertex.position[1] - y(64.0 + vertex.position[0])).abs() <= 1.0);
+    }
+
+    #[test]
+    fn textures_and_water() {
+        // A valley running north to south, flooded along its floor
+        let heights = Heightfield::new(
+            65,
+            65,
+            (0..65 * 65)
+                .map(|i| ((i % 65) as f32 - 32.0).abs() * 2.0)
+                .collect(),
+        )
+        .unwrap();
+        let dir = TempDir::new("package-texture-test-map");
+        let options = PackageOptions {
+            cell_size: 64,
+            texture_size: Some(16),
+            water_level: Some(5.0),
+            ..Default::default()
+        };
+        let info = write_package(&dir, &heights, &options).unwrap();
+        assert!(info.has_color && info.has_normals && info.has_water);
+
+        let map = Map::new(&dir).unwrap();
+        let cell = map.cell((0, 0)).unwrap();
+        for tile in cell.tree.iter() {
+            assert_eq!(tile.texture.as_ref().unwrap().size, 16);
+            assert_eq!(tile.normals.as_ref().unwrap().size, 16);
+        }
+        // The valley's west side faces east, down to its floor
+        let normals = &cell.tree.root().normals.as_ref().unwrap().image;
+        let texel = &normals[(8 * 16 + 2) * 4..][..3];
+        assert!(texel[0] > 200 && (120..136).contains(&texel[1]) && texel[2] > 150);
+
+        let decoder = png::Decoder::new(std::fs::File::open(dir.join("00_00/water.png")).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut water = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut water).unwrap();
+        assert_eq!(water.len(), 65 * 65);
+        assert_eq!((water[32], water[0]), (255, 0));
+    }
+}
@@ -1,128 +1,632 @@
-/// A quad tree either is a leaf or a node with a value
-/// and four children
+use rayon::prelude::*;
+
+use self::util::{full_size, node_index, tree_position};
+
+/// The sides of a node; north is row 0, west is column 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Direction {
+    pub const ALL: [Self; 4] = [Self::North, Self::South, Self::East, Self::West];
+
+    /// How many rows and columns a step this way moves
+    pub fn offset(self) -> (i64, i64) {
+        match self {
+            Direction::North => (-1, 0),
+            Direction::South => (1, 0),
+            Direction::East => (0, 1),
+            Direction::West => (0, -1),
+        }
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Direction::North => Direction::South,
+            Direction::South => Direction::North,
+            Direction::East => Direction::West,
+            Direction::West => Direction::East,
+        }
+    }
+}
+
+/// Where a node is in a quadtree: its level, and its row and column among
+/// the `2^level x 2^level` nodes of that level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId {
+    pub level: u32,
+    pub row: u32,
+    pub col: u32,
+}
+
+impl NodeId {
+    pub const ROOT: Self = Self::new(0, 0, 0);
+
+    #[inline]
+    pub const fn new(level: u32, row: u32, col: u32) -> Self {
+        Self { level, row, col }
+    }
+
+    /// Where the node lives in a `QuadTree`
+    #[inline]
+    pub fn index(self) -> usize {
+        node_index(self.level, self.row, self.col) as usize
+    }
+
+    /// `None` for the root
+    #[inline]
+    pub fn parent(self) -> Option<Self> {
+        (self.level > 0).then(|| Self::new(self.level - 1, self.row >> 1, self.col >> 1))
+    }
+
+    /// In nw, ne, se, sw order, the order children are stored in a .cell
+    #[inline]
+    pub fn children(self) -> [Self; 4] {
+        let (level, row, col) = (self.level + 1, self.row << 1, self.col << 1);
+        [
+            Self::new(level, row, col),
+            Self::new(level, row, col + 1),
+            Self::new(level, row + 1, col + 1),
+            Self::new(level, row + 1, col),
+        ]
+    }
+
+    /// The other three children of the parent, going round from this one
+    /// in the order of `children`; `None` for the root
+    pub fn siblings(self) -> Option<[Self; 3]> {
+        let quadrant = self.quadrant();
+        self.parent()
+            .map(|parent| parent.children())
+            .map(|c| [1, 2, 3].map(|i| c[(quadrant + i) % 4]))
+    }
+
+    /// Which of its parent's children this is, as an index into `children`
+    #[inline]
+    fn quadrant(self) -> usize {
+        match (self.row & 1, self.col & 1) {
+            (0, 0) => 0,
+            (0, _) => 1,
+            (_, 1) => 2,
+            _ => 3,
+        }
+    }
+
+    /// The node next to this one on the same level; `None` past the edge
+    /// of the tree
+    pub fn neighbour(self, direction: Direction) -> Option<Self> {
+        let (d_row, d_col) = direction.offset();
+        // Rows and columns are u32, so every one of them is on levels past 32
+        let side = 1i64 << self.level.min(32);
+        let (row, col) = (self.row as i64 + d_row, self.col as i64 + d_col);
+
+        ((0..side).contains(&row) && (0..side).contains(&col))
+            .then(|| Self::new(self.level, row as u32, col as u32))
+    }
+
+    /// The node on the same level across the edge of the tree, in the
+    /// tree next door: the north neighbour of a node on the top row is on
+    /// the bottom row of the tree to the north
+    pub fn wrapped_neighbour(self, direction: Direction) -> Self {
+        let (d_row, d_col) = direction.offset();
+        let mask = (1i64 << self.level.min(32)) - 1;
+        Self::new(
+            self.level,
+            ((self.row as i64 + d_row) & mask) as u32,
+            ((self.col as i64 + d_col) & mask) as u32,
+        )
+    }
+
+    /// The ancestor of this node at `level`, or itself if it's at that level
+    /// or above
+    pub fn ancestor(self, level: u32) -> Self {
+        let up = self.level.saturating_sub(level);
+        let shift = |c: u32| c.checked_shr(up).unwrap_or(0);
+        Self::new(self.level - up, shift(self.row), shift(self.col))
+    }
+}
+
+/// A quad tree, kept flat in a single `Vec`: level by level, and row by row
+/// within a level, so that in a complete tree a node lives at
+/// `util::node_index(level, row, col)`. A sparse tree, where some subtrees
+/// stop early, keeps the nodes it has in that same order
 #[derive(Debug, Clone)]
-pub enum QuadTree<T>
-where
-    T: Clone,
-{
-    Leaf(T),
-    Node(T, Box<Children<T>>),
+pub struct QuadTree<T> {
+    nodes: Vec<T>,
+    depth: u32,
+    /// `None` for a complete tree
+    sparse: Option<Sparse>,
 }
 
-/// The four children of a node of a quad tree are
-/// quadtrees
+/// Where the nodes of a sparse tree are
 #[derive(Debug, Clone)]
-pub struct Children<T>
-where
-    T: Clone,
-{
-    pub nw: QuadTree<T>,
-    pub ne: QuadTree<T>,
-    pub se: QuadTree<T>,
-    pub sw: QuadTree<T>,
+struct Sparse {
+    /// Indexed by `node_index`, where the node is in `nodes`, or `ABSENT`
+    slots: Vec<u32>,
+    /// Where each level starts in `nodes`, and where the last one ends
+    level_starts: Vec<usize>,
+}
 
-    depth_of_child: u32,
+impl Sparse {
+    const ABSENT: u32 = u32::MAX;
 }
 
-impl<T: Clone> QuadTree<T> {
-    /// Go from a flat represention of a quadtree to
-    /// pointer form
-    pub fn build_complete_tree(elements: Vec<T>, depth: u32) -> Self {
-        let n = util::full_size(depth) as usize;
-        assert_eq!(elements.len(), n);
+impl<T> QuadTree<T> {
+    /// Take the nodes already laid out by `util::node_index`
+    pub fn from_levels(nodes: Vec<T>, depth: u32) -> Self {
+        assert!(depth > 0, "a quad tree has at least a root");
+        assert_eq!(nodes.len(), full_size(depth) as usize);
 
-        Self::build_node(&elements, 0, 0, depth)
+        Self {
+            nodes,
+            depth,
+            sparse: None,
+        }
     }
 
-    /// Given all the elements, build the node at a given index at given level for the full
-    /// depth of depth
-    fn build_node(all_elements: &[T], index: usize, level: u32, depth: u32) -> Self {
-        if level == depth - 1 {
-            return Self::Leaf(all_elements[index].clone());
+    /// Take the nodes laid out by `util::node_index`, `None` where there is
+    /// nothing. A node has either all four children or none, and is a leaf
+    /// when it has none
+    pub fn from_sparse(nodes: Vec<Option<T>>, depth: u32) -> Result<Self, &'static str> {
+        assert!(depth > 0, "a quad tree has at least a root");
+        assert_eq!(nodes.len(), full_size(depth) as usize);
+
+        if nodes[0].is_none() {
+            return Err("Quad tree has no root");
+        }
+
+        let mut slots = Vec::with_capacity(nodes.len());
+        let mut level_starts = vec![0];
+        let mut n_present = 0;
+        for level in 0..depth {
+            let side = 1 << level;
+            for row in 0..side {
+                for col in 0..side {
+                    let id = NodeId::new(level, row, col);
+                    let has_parent = match id.parent() {
+                        Some(parent) => slots[parent.index()] != Sparse::ABSENT,
+                        None => true,
+                    };
+                    let has_siblings = id
+                        .siblings()
+                        .is_none_or(|s| s.iter().all(|s| nodes[s.index()].is_some()));
+
+                    match nodes[id.index()] {
+                        Some(_) if !has_parent => return Err("Quad tree node without a parent"),
+                        Some(_) if !has_siblings => {
+                            return Err("Quad tree node without all of its siblings")
+                        }
+                        Some(_) => {
+                            slots.push(n_present);
+                            n_present += 1;
+                        }
+                        None => slots.push(Sparse::ABSENT),
+                    }
+                }
+            }
+            level_starts.push(n_present as usize);
         }
 
-        let children_index = index << 2;
-        Self::Node(
-            all_elements[index].clone(),
-            Box::new(Children {
-                nw: Self::build_node(all_elements, children_index + 1, level + 1, depth),
-                ne: Self::build_node(all_elements, children_index + 2, level + 1, depth),
-                se: Self::build_node(all_elements, children_index + 3, level + 1, depth),
-                sw: Self::build_node(all_elements, children_index + 4, level + 1, depth),
-                depth_of_child: depth - level - 1,
+        let complete = n_present as usize == nodes.len();
+        let nodes = nodes.into_iter().flatten().collect();
+        Ok(Self {
+            nodes,
+            depth,
+            sparse: (!complete).then_some(Sparse {
+                slots,
+                level_starts,
             }),
-        )
+        })
     }
 
+    /// Take the nodes in tree order, where the children of node `i` are
+    /// `4i + 1..=4i + 4`, in nw, ne, se, sw order
+    pub fn build_complete_tree(elements: Vec<T>, depth: u32) -> Self {
+        assert_eq!(elements.len(), full_size(depth) as usize);
+
+        // Swap every element into place, keeping track of where each one
+        // came from
+        let mut elements = elements;
+        let mut origins = (0..elements.len() as u32).collect::<Vec<_>>();
+        for i in 0..elements.len() {
+            loop {
+                let (level, row, col) = tree_position(origins[i]);
+                let target = node_index(level, row, col) as usize;
+                if target == i {
+                    break;
+                }
+                elements.swap(i, target);
+                origins.swap(i, target);
+            }
+        }
+
+        Self::from_levels(elements, depth)
+    }
+
+    /// How many levels the deepest subtree has
     pub fn depth(&self) -> u32 {
-        match self {
-            QuadTree::Leaf(_) => 1,
-            QuadTree::Node(_, q) => q.depth_of_child + 1,
+        self.depth
+    }
+
+    /// How many nodes there are, over all levels
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Whether some subtrees stop before the deepest level
+    pub fn is_sparse(&self) -> bool {
+        self.sparse.is_some()
+    }
+
+    /// Where the node is in `nodes`
+    #[inline]
+    fn position(&self, id: NodeId) -> Option<usize> {
+        if id.level >= self.depth || id.row >> id.level != 0 || id.col >> id.level != 0 {
+            return None;
+        }
+
+        match &self.sparse {
+            None => Some(id.index()),
+            Some(sparse) => match sparse.slots[id.index()] {
+                Sparse::ABSENT => None,
+                slot => Some(slot as usize),
+            },
         }
     }
 
-    /// Return mutable access to the inside of the tree elements
-    pub fn mut_view(&mut self) -> Vec<&mut T> {
-        let mut flattened = Vec::with_capacity(util::full_size(self.depth()) as usize);
-        match self {
-            QuadTree::Leaf(v) => {
-                flattened.push(v);
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.position(id).is_some()
+    }
+
+    pub fn get(&self, level: u32, row: u32, col: u32) -> Option<&T> {
+        let position = self.position(NodeId::new(level, row, col))?;
+        Some(&self.nodes[position])
+    }
+
+    pub fn get_mut(&mut self, level: u32, row: u32, col: u32) -> Option<&mut T> {
+        let position = self.position(NodeId::new(level, row, col))?;
+        Some(&mut self.nodes[position])
+    }
+
+    pub fn root(&self) -> &T {
+        &self.nodes[0]
+    }
+
+    /// `None` for the root, or a node not in the tree
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        id.parent().filter(|_| self.contains(id))
+    }
+
+    /// `None` for leaves, or a node not in the tree
+    pub fn children(&self, id: NodeId) -> Option<[NodeId; 4]> {
+        let children = id.children();
+        (self.contains(id) && self.contains(children[0])).then_some(children)
+    }
+
+    pub fn is_leaf(&self, id: NodeId) -> bool {
+        self.contains(id) && !self.contains(id.children()[0])
+    }
+
+    /// `None` for the root, or a node not in the tree
+    pub fn siblings(&self, id: NodeId) -> Option<[NodeId; 3]> {
+        id.siblings().filter(|_| self.contains(id))
+    }
+
+    /// The finest node of the tree covering the area of `id`: `id` itself if
+    /// it's in the tree, an ancestor if the tree doesn't go that deep there
+    pub fn covering(&self, id: NodeId) -> Option<NodeId> {
+        let off_level = |c: u32| c.checked_shr(id.level).unwrap_or(0) != 0;
+        if off_level(id.row) || off_level(id.col) {
+            return None;
+        }
+
+        let mut covering = id.ancestor(self.depth - 1);
+        while !self.contains(covering) {
+            covering = covering.parent()?;
+        }
+        Some(covering)
+    }
+
+    /// The node next to `id` in `direction`, on the same level or, where the
+    /// tree doesn't go that deep, the coarser node covering it; `None` at the
+    /// edge of the tree
+    pub fn neighbour(&self, id: NodeId, direction: Direction) -> Option<NodeId> {
+        self.covering(id.neighbour(direction)?)
+    }
+
+    fn level_range(&self, level: u32) -> std::ops::Range<usize> {
+        assert!(level < self.depth, "not enough levels");
+        match &self.sparse {
+            None => full_size(level) as usize..full_size(level + 1) as usize,
+            Some(sparse) => {
+                sparse.level_starts[level as usize]..sparse.level_starts[level as usize + 1]
             }
-            QuadTree::Node(e, q) => {
-                flattened.push(e);
-                let Children { nw, ne, se, sw, .. } = q.as_mut();
-                for t in [nw, ne, se, sw] {
-                    flattened.extend(t.mut_view());
-                }
+        }
+    }
+
+    /// All the elements at a given level of the tree, row by row, skipping
+    /// the ones a sparse tree doesn't have
+    pub fn items_at_level(&self, level: u32) -> &[T] {
+        &self.nodes[self.level_range(level)]
+    }
+
+    pub fn items_at_level_mut(&mut self, level: u32) -> &mut [T] {
+        let range = self.level_range(level);
+        &mut self.nodes[range]
+    }
+
+    /// Every element, breadth first
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.nodes.iter()
+    }
+
+    /// Every element, breadth first
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+        self.nodes.iter_mut()
+    }
+
+    /// Every node with where it is, level by level, giving up the tree
+    pub fn into_nodes(self) -> impl Iterator<Item = (NodeId, T)> {
+        let ids = self.bfs().map(|(id, _)| id).collect::<Vec<_>>();
+        ids.into_iter().zip(self.nodes)
+    }
+
+    /// The tree without its levels from `depth` down; all of it if it's no
+    /// deeper than that
+    pub fn pruned(self, depth: u32) -> Self {
+        let depth = depth.clamp(1, self.depth);
+        let mut nodes: Vec<Option<T>> = (0..full_size(depth)).map(|_| None).collect();
+        for (id, node) in self.into_nodes() {
+            if id.level < depth {
+                nodes[id.index()] = Some(node);
             }
         }
-        flattened
+
+        Self::from_sparse(nodes, depth).expect("the top of a tree is a tree")
     }
 
-    /// Get all the elements at a given level of the tree
-    pub fn items_at_level(&self, level: u32) -> Vec<&T> {
-        let mut items = Vec::with_capacity(4usize.pow(level));
+    /// Every node with where it is, level by level
+    pub fn bfs(&self) -> Bfs<'_, T> {
+        Bfs {
+            tree: self,
+            next: Some(NodeId::ROOT),
+        }
+    }
 
-        match self {
-            QuadTree::Leaf(e) => {
-                if level != 0 {
-                    panic!("not enough levels")
+    /// Every node with where it is, each one followed by its children's
+    /// subtrees in nw, ne, se, sw order
+    pub fn dfs(&self) -> Dfs<'_, T> {
+        Dfs {
+            tree: self,
+            next: Some(NodeId::ROOT),
+        }
+    }
+
+    /// Walk down from the root, going into the children of a node only when
+    /// `refine` asks for it; returns the nodes where the walk stopped, which
+    /// includes every leaf it gets to
+    pub fn select<F: FnMut(&T) -> bool>(&self, refine: F) -> Vec<&T> {
+        match &self.sparse {
+            None => self.select_with(refine, |north, south| Some((north as u32, south as u32))),
+            Some(sparse) => self.select_with(refine, |north, south| match sparse.slots[north] {
+                Sparse::ABSENT => None,
+                slot => Some((slot, sparse.slots[south])),
+            }),
+        }
+    }
+
+    /// `select`, with `positions` taking the `node_index` of a node's nw and
+    /// sw children to where they are in `nodes`, `None` if it has none. The
+    /// ne and se children are right after them; the two of a row are next to
+    /// each other in any tree, so children are found from the layout rather
+    /// than looked up one by one
+    #[inline]
+    fn select_with<F, P>(&self, mut refine: F, positions: P) -> Vec<&T>
+    where
+        F: FnMut(&T) -> bool,
+        P: Fn(usize, usize) -> Option<(u32, u32)>,
+    {
+        let mut selected = Vec::new();
+
+        // Each node to visit with where it is in `nodes`, next one on top;
+        // a node's children replace it, so there are never more than three
+        // waiting a level
+        let mut stack = Vec::with_capacity(3 * self.depth as usize + 1);
+        stack.push((NodeId::ROOT, 0u32));
+        while let Some((id, position)) = stack.pop() {
+            let node = &self.nodes[position as usize];
+            let (level, row, col) = (id.level + 1, id.row << 1, id.col << 1);
+            let children = match level < self.depth {
+                true => {
+                    let north = node_index(level, row, col) as usize;
+                    positions(north, north + (1 << level))
                 }
-                items.push(e)
+                false => None,
+            };
+
+            match children {
+                // Children on the last level are leaves, so they're
+                // selected without going through the stack
+                Some((north, south)) if refine(node) => match level + 1 < self.depth {
+                    true => stack.extend([
+                        (NodeId::new(level, row + 1, col), south),
+                        (NodeId::new(level, row + 1, col + 1), south + 1),
+                        (NodeId::new(level, row, col + 1), north + 1),
+                        (NodeId::new(level, row, col), north),
+                    ]),
+                    false => {
+                        let (north, south) = (north as usize, south as usize);
+                        selected.extend([
+                            &self.nodes[north],
+                            &self.nodes[north + 1],
+                            &self.nodes[south + 1],
+                            &self.nodes[south],
+                        ])
+                    }
+                },
+                _ => selected.push(node),
             }
+        }
+
+        selected
+    }
+}
+
+impl<T: Send> QuadTree<T> {
+    /// Every element, in no particular order, on rayon's threads
+    pub fn par_iter_mut(&mut self) -> rayon::slice::IterMut<'_, T> {
+        self.nodes.par_iter_mut()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a QuadTree<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Breadth first traversal of a `QuadTree`, see `QuadTree::bfs`
+pub struct Bfs<'a, T> {
+    tree: &'a QuadTree<T>,
+    next: Option<NodeId>,
+}
+
+impl<'a, T> Iterator for Bfs<'a, T> {
+    type Item = (NodeId, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.next?;
+        let position = self.tree.position(id)?;
 
-            QuadTree::Node(e, boxed_children) => {
-                if level == 0 {
-                    items.push(e)
-                } else {
-                    let Children { nw, ne, se, sw, .. } = &**boxed_children;
-                    items.extend(
-                        [nw, ne, se, sw]
-                            .map(|q| q.items_at_level(level - 1))
-                            .into_iter()
-                            .flatten(),
-                    )
+        // The next node in `node_index` order the tree has, if any
+        let mut next = id;
+        self.next = loop {
+            let last = (1 << next.level) - 1;
+            next = match (next.row, next.col) {
+                (row, col) if col < last => NodeId::new(next.level, row, col + 1),
+                (row, _) if row < last => NodeId::new(next.level, row + 1, 0),
+                _ if next.level + 1 < self.tree.depth => NodeId::new(next.level + 1, 0, 0),
+                _ => break None,
+            };
+            if self.tree.contains(next) {
+                break Some(next);
+            }
+        };
+
+        Some((id, &self.tree.nodes[position]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self
+            .next
+            .and_then(|id| self.tree.position(id))
+            .map_or(0, |position| self.tree.len() - position);
+        (left, Some(left))
+    }
+}
+
+/// Depth first, pre-order traversal of a `QuadTree`, see `QuadTree::dfs`
+pub struct Dfs<'a, T> {
+    tree: &'a QuadTree<T>,
+    next: Option<NodeId>,
+}
+
+impl<'a, T> Iterator for Dfs<'a, T> {
+    type Item = (NodeId, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.next?;
+
+        // Go down if there are children, otherwise on to the next
+        // sibling, climbing up for as long as we were the last one
+        self.next = match self.tree.children(id) {
+            Some(children) => Some(children[0]),
+            None => {
+                let mut at = id;
+                loop {
+                    match at.parent() {
+                        None => break None,
+                        Some(parent) if at.quadrant() == 3 => at = parent,
+                        Some(parent) => break Some(parent.children()[at.quadrant() + 1]),
+                    }
                 }
             }
-        }
+        };
 
-        items
+        Some((id, &self.tree.nodes[self.tree.position(id)?]))
     }
 }
 
 pub mod util {
+    #[inline]
     pub fn full_size(depth: u32) -> u32 {
-        4u32.pow(depth) / 3
+        (1 << (2 * depth)) / 3
     }
 
+    #[inline]
     pub fn node_index(level: u32, row: u32, col: u32) -> u32 {
         full_size(level) + (row << level) + col
     }
+
+    /// The inverse of `node_index`
+    pub fn node_position(index: u32) -> (u32, u32, u32) {
+        let level = (0..).find(|&level| full_size(level + 1) > index).unwrap();
+        let in_level = index - full_size(level);
+        (level, in_level >> level, in_level & ((1 << level) - 1))
+    }
+
+    /// The (level, row, col) of the node at `index` of a tree laid out
+    /// the way `build_complete_tree` expects it: the children of node `i`
+    /// live at `4i + 1..=4i + 4`, in nw, ne, se, sw order
+    pub fn tree_position(index: u32) -> (u32, u32, u32) {
+        let (mut level, mut row, mut col) = (0, 0, 0);
+        let mut index = index;
+        while index > 0 {
+            let (d_row, d_col) = match (index - 1) % 4 {
+                0 => (0, 0),
+                1 => (0, 1),
+                2 => (1, 1),
+                _ => (1, 0),
+            };
+            row |= d_row << level;
+            col |= d_col << level;
+            level += 1;
+            index = (index - 1) / 4;
+        }
+
+        (level, row, col)
+    }
+
+    /// The inverse of `tree_position`
+    pub fn tree_index(level: u32, row: u32, col: u32) -> u32 {
+        (0..level).rev().fold(0, |index, bit| {
+            let quadrant = match ((row >> bit) & 1, (col >> bit) & 1) {
+                (0, 0) => 1,
+                (0, _) => 2,
+                (_, 1) => 3,
+                _ => 4,
+            };
+            4 * index + quadrant
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::QuadTree;
+    use super::{
+        util::{node_index, node_position, tree_index, tree_position},
+        Direction, NodeId, QuadTree,
+    };
 
     #[test]
     fn tree_makes_sense_three_levels() {
@@ -132,25 +636,255 @@ mod test {
 
     #[test]
     fn at_level() {
-        let q = QuadTree::build_complete_tree((0..21).collect(), 3);
-        assert_eq!(q.items_at_level(0), vec![&0]);
-        assert_eq!(q.items_at_level(1), vec![&1, &2, &3, &4]);
-        assert_eq!(
-            q.items_at_level(2)
-                .into_iter()
-                .map(|i| *i)
-                .collect::<Vec<_>>(),
-            (5..21).collect::<Vec<_>>()
-        );
+        let q = QuadTree::from_levels((0..21).collect(), 3);
+        assert_eq!(q.items_at_level(0), [0]);
+        assert_eq!(q.items_at_level(1), [1, 2, 3, 4]);
+        assert_eq!(q.items_at_level(2), (5..21).collect::<Vec<_>>());
     }
 
     #[test]
-    fn mut_view() {
+    fn iter_mut() {
         let mut q = QuadTree::build_complete_tree((0..21).collect(), 3);
-        let view = q.mut_view();
-        for i in view {
+        for i in q.iter_mut() {
             *i += 1;
         }
-        assert_eq!(dbg!(q).items_at_level(0), vec![&1])
+        assert_eq!(dbg!(q).items_at_level(0), [1])
+    }
+
+    #[test]
+    fn tree_positions() {
+        assert_eq!(tree_position(0), (0, 0, 0));
+        assert_eq!(
+            (1..5).map(tree_position).collect::<Vec<_>>(),
+            vec![(1, 0, 0), (1, 0, 1), (1, 1, 1), (1, 1, 0)]
+        );
+        // the se child of the ne child of the root
+        assert_eq!(tree_position(4 * 2 + 3), (2, 1, 3));
+
+        let mut seen = (0..21)
+            .map(|i| {
+                let (level, row, col) = tree_position(i);
+                node_index(level, row, col)
+            })
+            .collect::<Vec<_>>();
+        seen.sort();
+        assert_eq!(seen, (0..21).collect::<Vec<_>>());
+
+        for i in 0..85 {
+            let (level, row, col) = tree_position(i);
+            assert_eq!(tree_index(level, row, col), i);
+            let (level, row, col) = node_position(i);
+            assert_eq!(node_index(level, row, col), i);
+        }
+    }
+
+    #[test]
+    fn indexed_access() {
+        // Values are the tree order index, so they can be checked against
+        // `tree_position`
+        let mut q = QuadTree::build_complete_tree((0..85).collect::<Vec<u32>>(), 4);
+        for i in 0..85 {
+            let (level, row, col) = tree_position(i);
+            assert_eq!(q.get(level, row, col), Some(&i));
+        }
+        assert_eq!(q.get(1, 2, 0), None);
+        assert_eq!(q.get(4, 0, 0), None);
+
+        *q.get_mut(2, 1, 3).unwrap() = 100;
+        assert_eq!(q.get(2, 1, 3), Some(&100));
+    }
+
+    #[test]
+    fn navigation() {
+        let q = QuadTree::from_levels(vec![(); 21], 3);
+        let node = NodeId::new(2, 1, 2);
+
+        assert_eq!(q.parent(node), Some(NodeId::new(1, 0, 1)));
+        assert_eq!(q.parent(NodeId::ROOT), None);
+        assert_eq!(q.children(node), None);
+        assert_eq!(
+            q.children(NodeId::ROOT),
+            Some(
+                [(1, 0, 0), (1, 0, 1), (1, 1, 1), (1, 1, 0)].map(|(l, r, c)| NodeId::new(l, r, c))
+            )
+        );
+        assert_eq!(
+            q.siblings(node),
+            Some([(2, 0, 2), (2, 0, 3), (2, 1, 3)].map(|(l, r, c)| NodeId::new(l, r, c)))
+        );
+        assert_eq!(q.siblings(NodeId::ROOT), None);
+
+        for id in NodeId::ROOT.children() {
+            for child in id.children() {
+                assert_eq!(child.parent(), Some(id));
+            }
+        }
+    }
+
+    #[test]
+    fn neighbours() {
+        let q = QuadTree::from_levels(vec![(); 21], 3);
+        let node = NodeId::new(2, 1, 3);
+
+        assert_eq!(
+            q.neighbour(node, Direction::North),
+            Some(NodeId::new(2, 0, 3))
+        );
+        assert_eq!(
+            q.neighbour(node, Direction::South),
+            Some(NodeId::new(2, 2, 3))
+        );
+        assert_eq!(
+            q.neighbour(node, Direction::West),
+            Some(NodeId::new(2, 1, 2))
+        );
+        assert_eq!(q.neighbour(node, Direction::East), None);
+        assert_eq!(
+            node.wrapped_neighbour(Direction::East),
+            NodeId::new(2, 1, 0)
+        );
+        assert_eq!(
+            NodeId::ROOT.wrapped_neighbour(Direction::North),
+            NodeId::ROOT
+        );
+
+        // A level the tree doesn't have is covered by a leaf
+        let deep = NodeId::new(4, 5, 9);
+        assert_eq!(q.covering(deep), Some(NodeId::new(2, 1, 2)));
+        assert_eq!(
+            q.neighbour(deep, Direction::East),
+            Some(NodeId::new(2, 1, 2))
+        );
+        assert_eq!(
+            q.neighbour(deep, Direction::West),
+            Some(NodeId::new(2, 1, 2))
+        );
+        assert_eq!(
+            q.neighbour(deep, Direction::North),
+            Some(NodeId::new(2, 1, 2))
+        );
+        assert_eq!(
+            q.neighbour(deep, Direction::South),
+            Some(NodeId::new(2, 1, 2))
+        );
+
+        // Levels too deep to shift by still find their leaf, or the edge
+        let deepest = NodeId::new(40, u32::MAX, 0);
+        assert_eq!(q.covering(deepest), Some(NodeId::new(2, 0, 0)));
+        assert_eq!(
+            q.neighbour(deepest, Direction::North),
+            Some(NodeId::new(2, 0, 0))
+        );
+        assert_eq!(q.neighbour(deepest, Direction::West), None);
+        assert_eq!(
+            NodeId::new(u32::MAX, 0, 0).wrapped_neighbour(Direction::North),
+            NodeId::new(u32::MAX, u32::MAX, 0)
+        );
+        assert_eq!(q.covering(NodeId::new(3, 8, 0)), None);
+
+        for direction in Direction::ALL {
+            for (id, _) in q.bfs() {
+                if let Some(n) = q.neighbour(id, direction) {
+                    assert_eq!(q.neighbour(n, direction.opposite()), Some(id));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sparse() {
+        // The ne quarter of a three level tree has no children
+        let ne = NodeId::new(1, 0, 1);
+        let nodes = (0..21)
+            .map(|i| {
+                let (level, row, col) = node_position(i);
+                let id = NodeId::new(level, row, col);
+                (id.ancestor(1) != ne || level < 2).then_some(i)
+            })
+            .collect();
+        let q = QuadTree::from_sparse(nodes, 3).unwrap();
+
+        assert!(q.is_sparse());
+        assert_eq!(q.len(), 17);
+        assert!(q.is_leaf(ne));
+        assert_eq!(q.children(ne), None);
+        assert_eq!(q.get(2, 0, 2), None);
+        assert_eq!(q.get(2, 2, 2), Some(&15));
+        assert_eq!(q.items_at_level(2).len(), 12);
+        assert_eq!(q.covering(NodeId::new(2, 1, 3)), Some(ne));
+        assert_eq!(q.neighbour(NodeId::new(2, 0, 1), Direction::East), Some(ne));
+
+        assert_eq!(q.select(|_| true).len(), 13);
+        assert_eq!(q.select(|_| true)[4], &2);
+        assert_eq!(q.bfs().count(), 17);
+        assert_eq!(q.dfs().count(), 17);
+        assert!(q.bfs().all(|(id, &i)| id.index() == i as usize));
+        assert!(q.into_nodes().all(|(id, i)| id.index() == i as usize));
+
+        // Complete after all
+        let q = QuadTree::from_sparse((0..21).map(Some).collect(), 3).unwrap();
+        assert!(!q.is_sparse());
+
+        let without = |missing: &[u32]| {
+            let nodes = (0..21).map(|i| (!missing.contains(&i)).then_some(i));
+            QuadTree::from_sparse(nodes.collect(), 3)
+        };
+        assert!(without(&[0]).is_err());
+        assert!(without(&[2]).is_err());
+        assert!(without(&[9, 10]).is_err());
+        assert!(without(&[7, 8, 11, 12]).is_ok());
+
+        let pruned = without(&[7, 8, 11, 12]).unwrap().pruned(2);
+        assert_eq!((pruned.depth(), pruned.len()), (2, 5));
+        assert!(!pruned.is_sparse());
+        assert_eq!(without(&[]).unwrap().pruned(9).depth(), 3);
+    }
+
+    #[test]
+    fn traversals() {
+        let q = QuadTree::build_complete_tree((0..85).collect::<Vec<u32>>(), 4);
+
+        let bfs = q.bfs().collect::<Vec<_>>();
+        assert_eq!(bfs.len(), 85);
+        assert_eq!(q.bfs().size_hint(), (85, Some(85)));
+        for (index, (id, node)) in bfs.into_iter().enumerate() {
+            assert_eq!(id.index(), index);
+            assert_eq!(q.get(id.level, id.row, id.col), Some(node));
+        }
+
+        fn pre_order(id: NodeId, depth: u32, into: &mut Vec<NodeId>) {
+            into.push(id);
+            if id.level + 1 < depth {
+                for child in id.children() {
+                    pre_order(child, depth, into);
+                }
+            }
+        }
+        let mut expected = vec![];
+        pre_order(NodeId::ROOT, 4, &mut expected);
+        assert_eq!(q.dfs().map(|(id, _)| id).collect::<Vec<_>>(), expected);
+        for (id, &i) in q.dfs() {
+            assert_eq!(tree_position(i), (id.level, id.row, id.col));
+        }
+    }
+
+    #[test]
+    fn select() {
+        let q = QuadTree::build_complete_tree((0..21).collect(), 3);
+        assert_eq!(q.select(|_| false), vec![&0]);
+        assert_eq!(q.select(|i| *i < 2), vec![&5, &6, &7, &8, &2, &3, &4]);
+        assert_eq!(q.select(|_| true).len(), 16);
+        assert_eq!(
+            q.select(|i| *i == 0 || *i == 3),
+            vec![&1, &2, &13, &14, &15, &16, &4]
+        );
+
+        // Only nodes with children are asked about, once each
+        let mut asked = Vec::new();
+        q.select(|i| {
+            asked.push(*i);
+            *i != 2
+        });
+        assert_eq!(asked, vec![0, 1, 2, 3, 4]);
     }
 }
@@ -0,0 +1,216 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// What the LOD selection did at one level of the trees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// Tiles picked and drawn
+    pub selected: u32,
+    /// Tiles left out for being outside the view frustum
+    pub culled: u32,
+    /// Tiles picked but left out, for lack of room in the tile pool
+    pub dropped: u32,
+}
+
+/// Counters for the last frame; the selection ones only change when the
+/// camera does
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    /// Indexed by tree level
+    pub levels: Vec<LevelStats>,
+    /// Triangles in the drawn tiles
+    pub triangles: u64,
+    pub resident_vertex_bytes: u64,
+    pub resident_index_bytes: u64,
+    pub resident_texture_bytes: u64,
+    /// Bytes recorded for upload since the previous frame
+    pub upload_bytes: u64,
+    /// CPU time spent on the last LOD selection
+    pub selection_time: Duration,
+    /// Time between the starts of the last two frames
+    pub frame_time: Duration,
+}
+
+impl FrameStats {
+    pub const CSV_HEADER: &'static str = "frame_ms,selection_ms,tiles_selected,tiles_culled,\
+        tiles_dropped,triangles,vertex_bytes,index_bytes,texture_bytes,upload_bytes,selected_per_level";
+
+    pub fn level_mut(&mut self, level: u32) -> &mut LevelStats {
+        let level = level as usize;
+        if self.levels.len() <= level {
+            self.levels.resize(level + 1, LevelStats::default());
+        }
+        &mut self.levels[level]
+    }
+
+    /// Forget what the previous selection did
+    pub fn clear_selection(&mut self) {
+        self.levels.clear();
+        self.triangles = 0;
+    }
+
+    pub fn tiles_selected(&self) -> u32 {
+        self.levels.iter().map(|l| l.selected).sum()
+    }
+
+    pub fn tiles_culled(&self) -> u32 {
+        self.levels.iter().map(|l| l.culled).sum()
+    }
+
+    pub fn tiles_dropped(&self) -> u32 {
+        self.levels.iter().map(|l| l.dropped).sum()
+    }
+
+    /// The stats as a line matching `CSV_HEADER`; the per-level counts are
+    /// space separated in the last column
+    pub fn csv_row(&self) -> String {
+        let per_level = self
+            .levels
+            .iter()
+            .map(|l| l.selected.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!(
+            "{:.3},{:.3},{},{},{},{},{},{},{},{},{}",
+            self.frame_time.as_secs_f64() * 1000.0,
+            self.selection_time.as_secs_f64() * 1000.0,
+            self.tiles_selected(),
+            self.tiles_culled(),
+            self.tiles_dropped(),
+            self.triangles,
+            self.resident_vertex_bytes,
+            self.resident_index_bytes,
+            self.resident_texture_bytes,
+            self.upload_bytes,
+            per_level,
+        )
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MB: f64 = (1 << 20) as f64;
+
+        writeln!(
+            f,
+            "frame {:.2}ms, selection {:.2}ms",
+            self.frame_time.as_secs_f64() * 1000.0,
+            self.selection_time.as_secs_f64() * 1000.0,
+        )?;
+        writeln!(
+            f,
+            "{} tiles ({} culled, {} dropped), {} triangles",
+            self.tiles_selected(),
+            self.tiles_culled(),
+            self.tiles_dropped(),
+            self.triangles,
+        )?;
+        for (level, stats) in self.levels.iter().enumerate() {
+            writeln!(
+                f,
+                "  level {}: {} selected, {} culled, {} dropped",
+                level, stats.selected, stats.culled, stats.dropped
+            )?;
+        }
+        write!(
+            f,
+            "resident: {:.1}MB vertices, {:.1}MB indices, {:.1}MB textures; uploaded {:.1}MB",
+            self.resident_vertex_bytes as f64 / MB,
+            self.resident_index_bytes as f64 / MB,
+            self.resident_texture_bytes as f64 / MB,
+            self.upload_bytes as f64 / MB,
+        )
+    }
+}
+
+enum ReportFormat {
+    Text,
+    Csv,
+}
+
+/// Writes the frame stats out every so often
+pub struct StatsReporter {
+    out: Box<dyn Write>,
+    format: ReportFormat,
+    every: Duration,
+    last: Option<Instant>,
+}
+
+impl StatsReporter {
+    /// Print a readable summary to stdout every `every`
+    pub fn stdout(every: Duration) -> Self {
+        Self {
+            out: Box::new(std::io::stdout()),
+            format: ReportFormat::Text,
+            every,
+            last: None,
+        }
+    }
+
+    /// Write a CSV row to the file every `every`; a zero duration writes
+    /// every frame
+    pub fn csv<P: AsRef<Path>>(path: P, every: Duration) -> Result<Self, &'static str> {
+        let file = File::create(path).map_err(|_| "Unable to create stats file")?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "{}", FrameStats::CSV_HEADER).map_err(|_| "Unable to write stats file")?;
+
+        Ok(Self {
+            out: Box::new(out),
+            format: ReportFormat::Csv,
+            every,
+            last: None,
+        })
+    }
+
+    /// Write the stats if the last report is old enough
+    pub fn report(&mut self, stats: &FrameStats) {
+        let now = Instant::now();
+        if matches!(self.last, Some(last) if now - last < self.every) {
+            return;
+        }
+        self.last = Some(now);
+
+        // Stats aren't worth stopping the viewer over
+        let _ = match self.format {
+            ReportFormat::Text => writeln!(self.out, "{}\n", stats),
+            ReportFormat::Csv => writeln!(self.out, "{}", stats.csv_row()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::FrameStats;
+
+    #[test]
+    fn csv_row_matches_header() {
+        let mut stats = FrameStats {
+            frame_time: Duration::from_millis(16),
+            triangles: 1000,
+            ..Default::default()
+        };
+        stats.level_mut(2).selected = 3;
+        stats.level_mut(0).dropped = 1;
+        stats.level_mut(1).culled = 2;
+        stats.level_mut(2).culled = 4;
+
+        let row = stats.csv_row();
+        assert_eq!(
+            row.split(',').count(),
+            FrameStats::CSV_HEADER.split(',').count()
+        );
+        assert_eq!(row, "16.000,0.000,3,6,1,1000,0,0,0,0,0 0 3");
+        assert!(stats.to_string().contains("3 tiles (6 culled, 1 dropped)"));
+        assert!(stats
+            .to_string()
+            .contains("level 1: 0 selected, 2 culled, 0 dropped"));
+    }
+}
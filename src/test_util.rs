@@ -0,0 +1,43 @@
+//! What the tests share that isn't a test. tests/memory.rs has it too, by
+//! path, since it can't see the crate's own test modules.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A directory of a test's own under the system's temporary one, removed
+/// with everything in it when dropped, whether or not the test passed
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// An empty directory; `name` keeps it apart from other tests', and the
+    /// process id from other runs'
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
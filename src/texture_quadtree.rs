@@ -1,45 +1,223 @@
 use std::{
+    borrow::Cow,
     fs::File,
-    io::{BufReader, Read, Seek, SeekFrom},
+    io::{BufReader, BufWriter, Read, Seek, Write},
     path::Path,
+    sync::Mutex,
 };
 
-use crate::quadtree::{util::full_size, QuadTree};
-use crate::{disk_util::read_value, quadtree::util::node_index};
+use rayon::prelude::*;
+
+use crate::disk_util::{interlace_alpha, read_value, stream_len, Extents, ReadAt, ReaderAt};
+use crate::quadtree::{
+    util::{full_size, node_position},
+    QuadTree,
+};
+
+pub use self::codec::Codec;
 
 /// A texture is the flat image and supriously its size
 #[derive(Debug, Clone)]
 pub struct Texture {
+    /// Empty while the texture is kept as `blocks`
     pub image: Vec<u8>,
     pub size: u32,
+    /// The BC1 or BC3 blocks the tile was stored as, for a tile loaded to be
+    /// uploaded as it is rather than decoded; see `TexturedQuadTree::load`
+    pub blocks: Option<Blocks>,
+}
+
+/// A tile's BC1 or BC3 blocks, row by row, as they were in the file
+#[derive(Debug, Clone)]
+pub struct Blocks {
+    pub codec: Codec,
+    pub data: Vec<u8>,
 }
 
 impl Texture {
-    fn read_from<R: Seek + Read>(
-        reader: &mut BufReader<R>,
+    /// Neither deflate nor JPEG can do better than about this, so a tile
+    /// whose image would be larger than its share of the file this many
+    /// times over is bogus
+    pub const MAX_COMPRESSION_RATIO: u64 = 1032;
+
+    /// Read the tile at `offset` of `source` and decode it; its data ends
+    /// by `end`, where the next tile starts or the file ends. With
+    /// `keep_blocks`, BC1 and BC3 tiles are only checked and kept
+    fn read_at(
+        source: &impl ReadAt,
+        codec: Codec,
         tile_size: u32,
         offset: u64,
+        end: u64,
+        keep_blocks: bool,
     ) -> Result<Self, &'static str> {
-        reader
-            .seek(SeekFrom::Start(offset))
-            .map_err(|_| "Unable to seek to tile")?;
-        let decoder = png::Decoder::new(reader);
-        let mut png_reader = decoder.read_info().map_err(|_| "Unable to read png")?;
+        if offset >= end {
+            return Err("Tile offset out of the file");
+        }
 
-        let mut image = vec![0; png_reader.output_buffer_size()];
-        let r = png_reader
-            .next_frame(image.as_mut_slice())
-            .map_err(|_| "Unable to read tile")?;
+        let image_len = codec.decoded_len(tile_size);
+        let len = match codec.encoded_len(tile_size) {
+            Some(len) if len > end - offset => {
+                return Err("Tile runs into the next one or past the end of the file")
+            }
+            Some(len) => len,
+            None if image_len > Self::MAX_COMPRESSION_RATIO * (end - offset) => {
+                return Err("Tile too large for the file")
+            }
+            None => end - offset,
+        };
 
-        if r.width != tile_size || r.height != tile_size {
-            return Err("Invalid tile size??");
+        if keep_blocks && matches!(codec, Codec::Bc1 | Codec::Bc3) {
+            let mut data = vec![0; len as usize];
+            source
+                .read_exact_at(&mut data, offset)
+                .map_err(|_| "Unable to read tile")?;
+            return Ok(Self {
+                image: Vec::new(),
+                size: tile_size,
+                blocks: Some(Blocks { codec, data }),
+            });
         }
 
+        // Straight from the file, however much of it the codec needs
+        let reader = BufReader::new(ReaderAt::new(source, offset, offset + len));
         Ok(Self {
-            image,
+            image: codec.decode(reader, tile_size)?,
             size: tile_size,
+            blocks: None,
         })
     }
+
+    /// The texture with its image, decoded from its blocks if it was kept
+    /// as them: RGB for BC1, RGBA for BC3
+    pub fn decoded(&self) -> Cow<'_, Self> {
+        match &self.blocks {
+            None => Cow::Borrowed(self),
+            Some(blocks) => Cow::Owned(Self {
+                image: blocks
+                    .codec
+                    .decode(blocks.data.as_slice(), self.size)
+                    .expect("blocks are checked when they're loaded"),
+                size: self.size,
+                blocks: None,
+            }),
+        }
+    }
+
+    /// The texture as RGBA, decoded from its blocks if it was kept as them
+    pub fn rgba(&self) -> Cow<'_, Self> {
+        let mut texture = self.decoded();
+        if texture.channels() == 3 {
+            interlace_alpha(&mut texture.to_mut().image);
+        }
+        texture
+    }
+
+    /// Bytes per texel; 3 for RGB, 4 for RGBA
+    pub fn channels(&self) -> usize {
+        self.image.len() / (self.size as usize * self.size as usize).max(1)
+    }
+
+    /// The texture scaled to `size`, halving it while it's at least twice
+    /// as big and filtering bilinearly from there
+    pub fn resized(&self, size: u32) -> Self {
+        let channels = self.channels();
+        let chain = self.mip_chain(false);
+
+        let (mut start, mut from) = (0, self.size as usize);
+        while from >= 2 * size as usize {
+            start += from * from * channels;
+            from /= 2;
+        }
+        let source = &chain[start..start + from * from * channels];
+
+        let scale = from as f32 / size as f32;
+        let mut image = Vec::with_capacity(size as usize * size as usize * channels);
+        for y in 0..size {
+            for x in 0..size {
+                // Texel centers line up at the edges of both images
+                let u = ((x as f32 + 0.5) * scale - 0.5).clamp(0.0, (from - 1) as f32);
+                let v = ((y as f32 + 0.5) * scale - 0.5).clamp(0.0, (from - 1) as f32);
+                let (x0, y0) = (u as usize, v as usize);
+                let (x1, y1) = ((x0 + 1).min(from - 1), (y0 + 1).min(from - 1));
+                let (fx, fy) = (u.fract(), v.fract());
+
+                for c in 0..channels {
+                    let texel = |x: usize, y: usize| source[(y * from + x) * channels + c] as f32;
+                    let top = texel(x0, y0) * (1.0 - fx) + texel(x1, y0) * fx;
+                    let bottom = texel(x0, y1) * (1.0 - fx) + texel(x1, y1) * fx;
+                    image.push((top * (1.0 - fy) + bottom * fy).round() as u8);
+                }
+            }
+        }
+
+        Self {
+            image,
+            size,
+            blocks: None,
+        }
+    }
+
+    /// Levels in a full mip chain of the texture, down to 1x1
+    pub fn mip_levels(&self) -> u32 {
+        u32::BITS - self.size.leading_zeros()
+    }
+
+    /// The image followed by each of its mip levels, every one a 2x2 box
+    /// filter of the one before. With `srgb` the color channels are averaged
+    /// as linear light, so distant terrain doesn't darken.
+    pub fn mip_chain(&self, srgb: bool) -> Vec<u8> {
+        let channels = self.channels();
+        let to_linear: Vec<f32> = (0..=255u8)
+            .map(|v| {
+                let v = v as f32 / 255.0;
+                if v <= 0.04045 {
+                    v / 12.92
+                } else {
+                    ((v + 0.055) / 1.055).powf(2.4)
+                }
+            })
+            .collect();
+        let from_linear = |l: f32| {
+            let v = if l <= 0.0031308 {
+                l * 12.92
+            } else {
+                1.055 * l.powf(1.0 / 2.4) - 0.055
+            };
+            (v * 255.0).round() as u8
+        };
+
+        let mut chain = Vec::with_capacity(self.image.len() * 4 / 3 + channels);
+        chain.extend_from_slice(&self.image);
+
+        let (mut start, mut size) = (0, self.size as usize);
+        while size > 1 {
+            let half = size / 2;
+            let end = chain.len();
+            for y in 0..half {
+                for x in 0..half {
+                    let texel = |dx, dy| start + ((2 * y + dy) * size + 2 * x + dx) * channels;
+                    let corners = [texel(0, 0), texel(1, 0), texel(0, 1), texel(1, 1)];
+                    for c in 0..channels {
+                        let value = if srgb && c < 3 {
+                            let sum: f32 = corners
+                                .iter()
+                                .map(|&i| to_linear[chain[i + c] as usize])
+                                .sum();
+                            from_linear(sum / 4.0)
+                        } else {
+                            let sum: u32 = corners.iter().map(|&i| chain[i + c] as u32).sum();
+                            ((sum + 2) / 4) as u8
+                        };
+                        chain.push(value);
+                    }
+                }
+            }
+            (start, size) = (end, half);
+        }
+
+        chain
+    }
 }
 
 #[derive(Debug)]
@@ -71,83 +249,680 @@ impl Header {
     }
 }
 
+/// How the tiles of a file are stored, from its header
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    depth: u32,
+    codec: Codec,
+    /// The size of the tiles on each level, root first
+    level_sizes: Vec<u32>,
+}
+
 /// A unique version of a quadtree that is for textures
 #[derive(Debug, Clone)]
 pub struct TexturedQuadTree {
     pub lod: QuadTree<Texture>,
     pub depth: u32,
+    /// The size of the root's tiles; finer levels can have their own
     pub tile_size: u32,
+    /// How the tiles were stored in the file
+    pub codec: Codec,
 }
 
 impl QuadTree<Texture> {
-    fn read_from<R: Read + Seek>(
-        reader: &mut BufReader<R>,
-        depth: u32,
-        tile_size: u32,
+    /// Read and decode the tiles of a file `file_len` bytes long, in
+    /// parallel
+    fn decode(
+        source: &impl ReadAt,
+        file_len: u64,
+        layout: &Layout,
         offsets: &[u64],
+        keep_blocks: bool,
+        on_tile: &(dyn Fn() + Sync),
     ) -> Result<Self, &'static str> {
-        let mut tiles = Vec::with_capacity(full_size(depth) as usize);
+        let extents = Extents::new(offsets, file_len)?;
 
-        for level in 0..depth {
-            let n = 2usize.pow(level);
-            for row in 0..n as u32 {
-                for col in 0..n as u32 {
-                    tiles.push(Texture::read_from(
-                        reader,
-                        tile_size,
-                        offsets[node_index(level, row, col) as usize],
-                    )?)
+        // Tiles are stored row by row, just like the tree keeps them; an
+        // offset of 0 means there is no finer data there
+        let tiles = offsets
+            .par_iter()
+            .enumerate()
+            .map(|(index, &offset)| {
+                if offset == 0 {
+                    return Ok(None);
                 }
-            }
-        }
+                let (level, _, _) = node_position(index as u32);
+                let tile_size = layout.level_sizes[level as usize];
+                let texture = Texture::read_at(
+                    source,
+                    layout.codec,
+                    tile_size,
+                    offset,
+                    extents.end(offset),
+                    keep_blocks,
+                )?;
+                on_tile();
+                Ok(Some(texture))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(QuadTree::build_complete_tree(tiles, depth))
+        QuadTree::from_sparse(tiles, layout.depth)
     }
 }
 
 impl TexturedQuadTree {
     const MAGIC: u32 = 0x00545154;
-    const VERSION: u32 = 1;
+    /// What gets written. Version 1 files, PNG with one tile size
+    /// throughout, still load.
+    const VERSION: u32 = 2;
+    const HEADER_SIZE: u64 = 16;
+    /// Codec and flags, after the version 1 header
+    const V2_HEADER_SIZE: u64 = 24;
+    const MAX_DEPTH: u32 = 12;
+    const MAX_TILE_SIZE: u32 = 4096;
+
+    /// Flag for a tile size per level following the header
+    const PER_LEVEL_SIZES: u32 = 1;
 
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, &'static str> {
+        Self::load(path, false, &|| ())
+    }
+
+    /// Like `new`, calling `on_tile` after each tile is decoded, from
+    /// whichever thread decoded it. With `keep_blocks`, BC1 and BC3 tiles
+    /// aren't decoded but kept as their `blocks`, for a GPU that samples
+    /// them as they are
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        keep_blocks: bool,
+        on_tile: &(dyn Fn() + Sync),
+    ) -> Result<Self, &'static str> {
         let file = File::open(path).map_err(|_| "Error while opening texture file")?;
-        let mut reader = BufReader::new(file);
+        let mut reader = BufReader::new(&file);
+        let file_len = stream_len(&mut reader)?;
+        let (layout, offsets) = Self::read_offsets(&mut reader)?;
+
+        // The tiles are read straight from the file, each at its offset
+        Self::read_tiles(&file, file_len, layout, &offsets, keep_blocks, on_tile)
+    }
+
+    /// How many tiles the file at `path` has, from its offsets alone
+    pub fn count_tiles<P: AsRef<Path>>(path: P) -> Result<usize, &'static str> {
+        let file = File::open(path).map_err(|_| "Error while opening texture file")?;
+        let (_, offsets) = Self::read_offsets(&mut BufReader::new(file))?;
+
+        Ok(offsets.iter().filter(|&&offset| offset != 0).count())
+    }
+
+    /// Read a texture tree from anything; nothing in it is trusted, so a
+    /// broken or hostile file gives an error rather than a panic or a huge
+    /// allocation
+    pub fn read_from<R: Read + Seek + Send>(
+        reader: &mut BufReader<R>,
+    ) -> Result<Self, &'static str> {
+        Self::read_with_progress(reader, false, &|| ())
+    }
+
+    fn read_with_progress<R: Read + Seek + Send>(
+        reader: &mut BufReader<R>,
+        keep_blocks: bool,
+        on_tile: &(dyn Fn() + Sync),
+    ) -> Result<Self, &'static str> {
+        let file_len = stream_len(reader)?;
+        let (layout, offsets) = Self::read_offsets(reader)?;
+
+        Self::read_tiles(
+            &Mutex::new(reader),
+            file_len,
+            layout,
+            &offsets,
+            keep_blocks,
+            on_tile,
+        )
+    }
+
+    /// The tree, its tiles read from `source` once its layout and offsets
+    /// are known
+    fn read_tiles(
+        source: &impl ReadAt,
+        file_len: u64,
+        layout: Layout,
+        offsets: &[u64],
+        keep_blocks: bool,
+        on_tile: &(dyn Fn() + Sync),
+    ) -> Result<Self, &'static str> {
+        let lod =
+            QuadTree::<Texture>::decode(source, file_len, &layout, offsets, keep_blocks, on_tile)?;
+
+        Ok(Self {
+            lod,
+            depth: layout.depth,
+            tile_size: layout.level_sizes[0],
+            codec: layout.codec,
+        })
+    }
+
+    /// The checked layout and tile offsets
+    fn read_offsets<R: Read + Seek>(
+        reader: &mut BufReader<R>,
+    ) -> Result<(Layout, Vec<u64>), &'static str> {
+        let file_len = stream_len(reader)?;
 
         let Header {
             magic,
             version,
             depth,
             tile_size,
-        } = Header::read_from(&mut reader)?;
+        } = Header::read_from(reader)?;
 
         if magic != Self::MAGIC {
             return Err("Invalid magic no.");
         }
 
-        if version != Self::VERSION {
+        if version != 1 && version != 2 {
             return Err("Invalid version no.");
         }
 
+        if depth == 0 || depth > Self::MAX_DEPTH {
+            return Err("Depth out of supported range.");
+        }
+
+        if tile_size == 0 || tile_size > Self::MAX_TILE_SIZE {
+            return Err("Tile size out of supported range.");
+        }
+
+        let mut layout = Layout {
+            depth,
+            codec: Codec::Png,
+            level_sizes: vec![tile_size; depth as usize],
+        };
+        let mut header_size = Self::HEADER_SIZE;
+
+        if version == 2 {
+            let mut codec: u32 = 0;
+            let mut flags: u32 = 0;
+            read_value(reader, &mut codec, "Unable to read codec")?;
+            read_value(reader, &mut flags, "Unable to read flags")?;
+            layout.codec = Codec::from_u32(codec)?;
+            header_size = Self::V2_HEADER_SIZE;
+
+            if flags & !Self::PER_LEVEL_SIZES != 0 {
+                return Err("Unknown texture file flags");
+            }
+
+            if flags & Self::PER_LEVEL_SIZES != 0 {
+                for size in layout.level_sizes.iter_mut() {
+                    read_value(reader, size, "Unable to read level tile size")?;
+                    if *size == 0 || *size > Self::MAX_TILE_SIZE {
+                        return Err("Tile size out of supported range.");
+                    }
+                }
+                if layout.level_sizes[0] != tile_size {
+                    return Err("Tile size doesn't match the root level's");
+                }
+                header_size += 4 * depth as u64;
+            }
+        }
+
         let n_tiles = full_size(depth) as usize;
+        if header_size + 8 * n_tiles as u64 > file_len {
+            return Err("Texture file too short for its offsets");
+        }
+
         let mut offsets: Vec<u64> = vec![0; n_tiles];
+        for offset in offsets.iter_mut() {
+            read_value(reader, offset, "Unable to read offset")?;
+        }
+
+        Ok((layout, offsets))
+    }
+
+    /// Save the tree to `path` as a version 2 file, its tiles encoded with
+    /// `codec`
+    pub fn save<P: AsRef<Path>>(&self, path: P, codec: Codec) -> Result<(), &'static str> {
+        let file = File::create(path).map_err(|_| "Error while creating texture file")?;
+        let mut writer = BufWriter::new(file);
+
+        self.write_to(&mut writer, codec)?;
+        writer.flush().map_err(|_| "Unable to write texture file")
+    }
 
-        for i in 0..n_tiles {
-            read_value(&mut reader, &mut offsets[i], "Unable to read offset")?;
+    /// Write the tree as a version 2 file. Tile sizes are only written per
+    /// level if they differ from the root's; every tile on a level has to be
+    /// the same size.
+    pub fn write_to<W: Write>(&self, writer: &mut W, codec: Codec) -> Result<(), &'static str> {
+        let depth = self.lod.depth();
+
+        let mut level_sizes: Vec<u32> = Vec::with_capacity(depth as usize);
+        for level in 0..depth {
+            let tiles = self.lod.items_at_level(level);
+            let size = match (tiles.first(), level_sizes.last()) {
+                (Some(tile), _) => tile.size,
+                (None, Some(&size)) => size,
+                (None, None) => self.tile_size,
+            };
+            if tiles.iter().any(|tile| tile.size != size) {
+                return Err("Tiles on a level have to be the same size");
+            }
+            level_sizes.push(size);
         }
 
-        let lod = QuadTree::<Texture>::read_from(&mut reader, depth, tile_size, &offsets)?;
+        // Encoded in parallel, laid out in the order of their offsets
+        let tiles = (0..full_size(depth))
+            .into_par_iter()
+            .map(|index| {
+                let (level, row, col) = node_position(index);
+                self.lod
+                    .get(level, row, col)
+                    .map(|texture| match &texture.blocks {
+                        // Already encoded, as it happens
+                        Some(blocks) if blocks.codec == codec => Ok(blocks.data.clone()),
+                        _ => codec.encode(&texture.decoded().image, texture.size),
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(Self {
-            lod,
+        let per_level = level_sizes.iter().any(|&size| size != level_sizes[0]);
+        let mut header = vec![
+            Self::MAGIC,
+            Self::VERSION,
             depth,
-            tile_size,
-        })
+            level_sizes[0],
+            codec as u32,
+            if per_level { Self::PER_LEVEL_SIZES } else { 0 },
+        ];
+        if per_level {
+            header.extend(&level_sizes);
+        }
+
+        let mut offset = 4 * header.len() as u64 + 8 * tiles.len() as u64;
+        let offsets = tiles.iter().map(|tile| match tile {
+            Some(data) => {
+                let at = offset;
+                offset += data.len() as u64;
+                at
+            }
+            None => 0,
+        });
+
+        let bytes = header
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .chain(offsets.flat_map(u64::to_le_bytes))
+            .collect::<Vec<_>>();
+        writer
+            .write_all(&bytes)
+            .map_err(|_| "Unable to write texture file")?;
+        for data in tiles.iter().flatten() {
+            writer
+                .write_all(data)
+                .map_err(|_| "Unable to write texture file")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub mod codec {
+    //! How the tiles of a texture file are encoded. PNG and JPEG are decoded
+    //! with their own crates; raw and block-compressed tiles are a fixed
+    //! number of bytes for their size, and the BC formats are encoded and
+    //! decoded here, on the CPU, for when a GPU can't sample their blocks.
+
+    use std::io::Read;
+
+    /// The codec of every tile in a version 2 file, stored as its `u32`
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[repr(u32)]
+    pub enum Codec {
+        /// 8-bit RGB or RGBA; the only codec of version 1 files
+        #[default]
+        Png = 0,
+        /// 8-bit RGB, lossy
+        Jpeg = 1,
+        /// 8-bit RGBA texels, row by row
+        Raw = 2,
+        /// 4x4 blocks of two RGB565 endpoints and 2-bit indices; opaque
+        Bc1 = 3,
+        /// BC1 color with interpolated 8-bit alpha
+        Bc3 = 4,
+    }
+
+    impl Codec {
+        /// Quality JPEG tiles are written at
+        const JPEG_QUALITY: u8 = 90;
+
+        pub fn from_u32(value: u32) -> Result<Self, &'static str> {
+            match value {
+                0 => Ok(Self::Png),
+                1 => Ok(Self::Jpeg),
+                2 => Ok(Self::Raw),
+                3 => Ok(Self::Bc1),
+                4 => Ok(Self::Bc3),
+                _ => Err("Unknown texture codec"),
+            }
+        }
+
+        /// 4x4 blocks across a tile of `size`
+        fn blocks(size: u32) -> u64 {
+            (size as u64).div_ceil(4)
+        }
+
+        /// Bytes a tile of `size` takes in the file, for codecs where that's
+        /// fixed
+        pub fn encoded_len(self, size: u32) -> Option<u64> {
+            let blocks = Self::blocks(size) * Self::blocks(size);
+            match self {
+                Self::Png | Self::Jpeg => None,
+                Self::Raw => Some(4 * size as u64 * size as u64),
+                Self::Bc1 => Some(8 * blocks),
+                Self::Bc3 => Some(16 * blocks),
+            }
+        }
+
+        /// Bytes of the image a tile of `size` decodes to, at most
+        pub fn decoded_len(self, size: u32) -> u64 {
+            let channels = match self {
+                Self::Jpeg | Self::Bc1 => 3,
+                Self::Png | Self::Raw | Self::Bc3 => 4,
+            };
+            channels * size as u64 * size as u64
+        }
+
+        /// Encode an RGB or RGBA image of `size` by `size` texels
+        pub fn encode(self, image: &[u8], size: u32) -> Result<Vec<u8>, &'static str> {
+            let n_texels = size as usize * size as usize;
+            let channels = match image.len().checked_div(n_texels) {
+                Some(channels @ (3 | 4)) if channels * n_texels == image.len() => channels,
+                _ => return Err("Tiles have to be 8-bit RGB or RGBA"),
+            };
+            let texel = |x: u32, y: u32| {
+                let i = (y.min(size - 1) * size + x.min(size - 1)) as usize * channels;
+                let alpha = if channels == 4 { image[i + 3] } else { 255 };
+                [image[i], image[i + 1], image[i + 2], alpha]
+            };
+
+            let mut out = Vec::new();
+            match self {
+                Self::Png => {
+                    let mut encoder = png::Encoder::new(&mut out, size, size);
+                    encoder.set_color(if channels == 4 {
+                        png::ColorType::Rgba
+                    } else {
+                        png::ColorType::Rgb
+                    });
+                    encoder.set_depth(png::BitDepth::Eight);
+                    encoder
+                        .write_header()
+                        .and_then(|mut writer| writer.write_image_data(image))
+                        .map_err(|_| "Unable to encode png")?;
+                }
+                Self::Jpeg => {
+                    let rgb: Vec<u8> = (0..n_texels)
+                        .flat_map(|i| &image[i * channels..i * channels + 3])
+                        .copied()
+                        .collect();
+                    jpeg_encoder::Encoder::new(&mut out, Self::JPEG_QUALITY)
+                        .encode(&rgb, size as u16, size as u16, jpeg_encoder::ColorType::Rgb)
+                        .map_err(|_| "Unable to encode jpeg")?;
+                }
+                Self::Raw => {
+                    for y in 0..size {
+                        for x in 0..size {
+                            out.extend(texel(x, y));
+                        }
+                    }
+                }
+                Self::Bc1 | Self::Bc3 => {
+                    // Blocks hanging off the edge repeat the last row and
+                    // column
+                    for by in 0..Self::blocks(size) as u32 {
+                        for bx in 0..Self::blocks(size) as u32 {
+                            let block: [[u8; 4]; 16] = std::array::from_fn(|i| {
+                                texel(4 * bx + i as u32 % 4, 4 * by + i as u32 / 4)
+                            });
+                            if self == Self::Bc3 {
+                                out.extend(bc::encode_alpha(&block));
+                            }
+                            out.extend(bc::encode_color(&block));
+                        }
+                    }
+                }
+            }
+
+            Ok(out)
+        }
+
+        /// Decode a tile of `size` from `reader`, into RGB for JPEG and BC1
+        /// and RGBA for raw and BC3; PNG stays as it was stored
+        pub(super) fn decode<R: Read>(
+            self,
+            mut reader: R,
+            size: u32,
+        ) -> Result<Vec<u8>, &'static str> {
+            let image_len = self.decoded_len(size) as usize;
+
+            match self {
+                Self::Png => {
+                    let decoder =
+                        png::Decoder::new_with_limits(reader, png::Limits { bytes: image_len });
+                    let mut png_reader = decoder.read_info().map_err(|_| "Unable to read png")?;
+
+                    // Checked before the image is allocated
+                    let info = png_reader.info();
+                    if info.width != size || info.height != size {
+                        return Err("Invalid tile size??");
+                    }
+                    if !matches!(
+                        png_reader.output_color_type(),
+                        (
+                            png::ColorType::Rgb | png::ColorType::Rgba,
+                            png::BitDepth::Eight
+                        )
+                    ) {
+                        return Err("Tiles have to be 8-bit RGB or RGBA");
+                    }
+
+                    let mut image = vec![0; png_reader.output_buffer_size()];
+                    png_reader
+                        .next_frame(image.as_mut_slice())
+                        .map_err(|_| "Unable to read tile")?;
+                    Ok(image)
+                }
+                Self::Jpeg => {
+                    let mut decoder = jpeg_decoder::Decoder::new(reader);
+                    decoder.set_max_decoding_buffer_size(image_len);
+                    decoder.read_info().map_err(|_| "Unable to read jpeg")?;
+
+                    let info = decoder.info().ok_or("Unable to read jpeg")?;
+                    if info.width as u32 != size || info.height as u32 != size {
+                        return Err("Invalid tile size??");
+                    }
+                    if info.pixel_format != jpeg_decoder::PixelFormat::RGB24 {
+                        return Err("Tiles have to be 8-bit RGB or RGBA");
+                    }
+
+                    decoder.decode().map_err(|_| "Unable to read tile")
+                }
+                Self::Raw => {
+                    let mut image = vec![0; image_len];
+                    reader
+                        .read_exact(&mut image)
+                        .map_err(|_| "Unable to read tile")?;
+                    Ok(image)
+                }
+                Self::Bc1 | Self::Bc3 => {
+                    let channels = if self == Self::Bc3 { 4 } else { 3 };
+                    let block_len = if self == Self::Bc3 { 16 } else { 8 };
+                    let blocks = Self::blocks(size) as usize;
+                    let size = size as usize;
+
+                    let mut data = vec![0; blocks * blocks * block_len];
+                    reader
+                        .read_exact(&mut data)
+                        .map_err(|_| "Unable to read tile")?;
+
+                    let mut image = vec![0; image_len];
+                    for (i, block) in data.chunks_exact(block_len).enumerate() {
+                        let (bx, by) = (i % blocks, i / blocks);
+                        let mut texels =
+                            bc::decode_color(&block[block_len - 8..], self == Self::Bc3);
+                        if self == Self::Bc3 {
+                            let alphas = bc::decode_alpha(&block[..8]);
+                            for (texel, alpha) in texels.iter_mut().zip(alphas) {
+                                texel[3] = alpha;
+                            }
+                        }
+
+                        for (j, texel) in texels.iter().enumerate() {
+                            let (x, y) = (4 * bx + j % 4, 4 * by + j / 4);
+                            if x < size && y < size {
+                                let at = (y * size + x) * channels;
+                                image[at..at + channels].copy_from_slice(&texel[..channels]);
+                            }
+                        }
+                    }
+                    Ok(image)
+                }
+            }
+        }
+    }
+
+    /// BC1 and BC3 blocks. Endpoints are the corners of the block's bounding
+    /// box, pulled in a little, which is quick and good enough for terrain.
+    mod bc {
+        fn to_565([r, g, b, _]: [u8; 4]) -> u16 {
+            (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+        }
+
+        fn from_565(c: u16) -> [u8; 4] {
+            let (r, g, b) = ((c >> 11) as u8, (c >> 5 & 63) as u8, (c & 31) as u8);
+            [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2, 255]
+        }
+
+        fn mix(a: [u8; 4], b: [u8; 4], wa: u32, wb: u32) -> [u8; 4] {
+            std::array::from_fn(|i| ((a[i] as u32 * wa + b[i] as u32 * wb) / (wa + wb)) as u8)
+        }
+
+        /// The four colors a block's indices pick from. BC1 blocks with
+        /// c0 <= c1 have three and black; BC3 blocks always have four.
+        fn palette(c0: u16, c1: u16, four: bool) -> [[u8; 4]; 4] {
+            let (a, b) = (from_565(c0), from_565(c1));
+            if four || c0 > c1 {
+                [a, b, mix(a, b, 2, 1), mix(a, b, 1, 2)]
+            } else {
+                [a, b, mix(a, b, 1, 1), [0, 0, 0, 255]]
+            }
+        }
+
+        pub fn encode_color(block: &[[u8; 4]; 16]) -> [u8; 8] {
+            let mut min = [255u8; 3];
+            let mut max = [0u8; 3];
+            for texel in block {
+                for c in 0..3 {
+                    min[c] = min[c].min(texel[c]);
+                    max[c] = max[c].max(texel[c]);
+                }
+            }
+            for c in 0..3 {
+                let inset = (max[c] - min[c]) / 16;
+                min[c] += inset;
+                max[c] -= inset;
+            }
+
+            let (mut c0, mut c1) = (
+                to_565([max[0], max[1], max[2], 255]),
+                to_565([min[0], min[1], min[2], 255]),
+            );
+            // Four colors need c0 > c1; a flat block uses c0 alone
+            if c0 < c1 {
+                std::mem::swap(&mut c0, &mut c1);
+            }
+
+            let mut indices = 0u32;
+            if c0 != c1 {
+                let colors = palette(c0, c1, true);
+                for (i, texel) in block.iter().enumerate() {
+                    let distance = |color: &[u8; 4]| -> u32 {
+                        (0..3)
+                            .map(|c| (texel[c] as i32 - color[c] as i32).pow(2) as u32)
+                            .sum()
+                    };
+                    let best = (0..4).min_by_key(|&j| distance(&colors[j])).unwrap();
+                    indices |= (best as u32) << (2 * i);
+                }
+            }
+
+            let mut out = [0; 8];
+            out[..2].copy_from_slice(&c0.to_le_bytes());
+            out[2..4].copy_from_slice(&c1.to_le_bytes());
+            out[4..].copy_from_slice(&indices.to_le_bytes());
+            out
+        }
+
+        pub fn decode_color(block: &[u8], four: bool) -> [[u8; 4]; 16] {
+            let c0 = u16::from_le_bytes([block[0], block[1]]);
+            let c1 = u16::from_le_bytes([block[2], block[3]]);
+            let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+            let colors = palette(c0, c1, four);
+
+            std::array::from_fn(|i| colors[(indices >> (2 * i) & 3) as usize])
+        }
+
+        /// The eight alphas a block's indices pick from, with a0 > a1
+        fn alphas(a0: u8, a1: u8) -> [u8; 8] {
+            std::array::from_fn(|i| match i {
+                0 => a0,
+                1 => a1,
+                _ if a0 > a1 => {
+                    ((a0 as u32 * (8 - i as u32) + a1 as u32 * (i as u32 - 1)) / 7) as u8
+                }
+                6 => 0,
+                7 => 255,
+                _ => ((a0 as u32 * (6 - i as u32) + a1 as u32 * (i as u32 - 1)) / 5) as u8,
+            })
+        }
+
+        pub fn encode_alpha(block: &[[u8; 4]; 16]) -> [u8; 8] {
+            let a0 = block.iter().map(|texel| texel[3]).max().unwrap();
+            let a1 = block.iter().map(|texel| texel[3]).min().unwrap();
+
+            let mut indices = 0u64;
+            if a0 != a1 {
+                let levels = alphas(a0, a1);
+                for (i, texel) in block.iter().enumerate() {
+                    let best = (0..8)
+                        .min_by_key(|&j| (texel[3] as i32 - levels[j] as i32).abs())
+                        .unwrap();
+                    indices |= (best as u64) << (3 * i);
+                }
+            }
+
+            let mut out = [0; 8];
+            out[0] = a0;
+            out[1] = a1;
+            out[2..].copy_from_slice(&indices.to_le_bytes()[..6]);
+            out
+        }
+
+        pub fn decode_alpha(block: &[u8]) -> [u8; 16] {
+            let levels = alphas(block[0], block[1]);
+            let mut bytes = [0; 8];
+            bytes[..6].copy_from_slice(&block[2..8]);
+            let indices = u64::from_le_bytes(bytes);
+
+            std::array::from_fn(|i| levels[(indices >> (3 * i) & 7) as usize])
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::TexturedQuadTree;
+    use std::io::{BufReader, Cursor};
+
+    use super::{Codec, Texture, TexturedQuadTree};
+    use crate::quadtree::QuadTree;
 
     #[test]
     fn can_read_file() {
@@ -161,4 +936,124 @@ mod test {
             println!("{n:?}")
         }
     }
+
+    #[test]
+    fn mip_chain() {
+        // A 4x4 RGBA checkerboard of black and white texels
+        let image = (0..16)
+            .flat_map(|i| {
+                let v = if (i ^ (i >> 2)) & 1 == 0 { 255 } else { 0 };
+                [v, v, v, 255]
+            })
+            .collect();
+        let texture = Texture {
+            image,
+            size: 4,
+            blocks: None,
+        };
+        assert_eq!(texture.mip_levels(), 3);
+
+        let chain = texture.mip_chain(false);
+        assert_eq!(chain.len(), (16 + 4 + 1) * 4);
+        assert_eq!(&chain[64..72], [128, 128, 128, 255, 128, 128, 128, 255]);
+        assert_eq!(&chain[80..], [128, 128, 128, 255]);
+
+        // Half white is a lighter grey once it's averaged as light
+        let chain = texture.mip_chain(true);
+        assert_eq!(&chain[64..68], [188, 188, 188, 255]);
+        assert_eq!(&chain[80..], [188, 188, 188, 255]);
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        // A smooth RGBA gradient, with a root twice the size of its children
+        let gradient = |size: u32, seed: u32| Texture {
+            image: (0..size * size)
+                .flat_map(|i| {
+                    let (x, y) = (i % size * 255 / size, i / size * 255 / size);
+                    [x as u8, y as u8, (x + seed * 40) as u8 / 2, 255 - y as u8]
+                })
+                .collect(),
+            size,
+            blocks: None,
+        };
+        let tree = TexturedQuadTree {
+            lod: QuadTree::from_levels(
+                (0..5)
+                    .map(|i| gradient(if i == 0 { 64 } else { 32 }, i))
+                    .collect(),
+                2,
+            ),
+            depth: 2,
+            tile_size: 64,
+            codec: Codec::Png,
+        };
+
+        for (codec, channels, tolerance) in [
+            (Codec::Png, 4, 0),
+            (Codec::Raw, 4, 0),
+            (Codec::Jpeg, 3, 16),
+            (Codec::Bc1, 3, 24),
+            (Codec::Bc3, 4, 24),
+        ] {
+            let mut data = Vec::new();
+            tree.write_to(&mut data, codec).unwrap();
+            let read = TexturedQuadTree::read_from(&mut BufReader::new(Cursor::new(data))).unwrap();
+            assert_eq!(read.codec, codec);
+            assert_eq!(read.tile_size, 64);
+
+            for (original, texture) in tree.lod.iter().zip(read.lod.iter()) {
+                assert_eq!(texture.size, original.size);
+                assert_eq!(texture.channels(), channels, "{:?}", codec);
+                let worst = (0..original.image.len() / 4)
+                    .flat_map(|i| (0..channels).map(move |c| (i, c)))
+                    .map(|(i, c)| {
+                        original.image[4 * i + c].abs_diff(texture.image[channels * i + c])
+                    })
+                    .max();
+                assert!(
+                    worst <= Some(tolerance),
+                    "{:?} is off by {:?}",
+                    codec,
+                    worst
+                );
+            }
+        }
+
+        // Block compressed tiles can stay as they are, and decode the same
+        for codec in [Codec::Bc1, Codec::Bc3] {
+            let mut data = Vec::new();
+            tree.write_to(&mut data, codec).unwrap();
+            let read = |keep_blocks| {
+                let mut reader = BufReader::new(Cursor::new(&data));
+                TexturedQuadTree::read_with_progress(&mut reader, keep_blocks, &|| ()).unwrap()
+            };
+            let (decoded, kept) = (read(false), read(true));
+            for (decoded, kept) in decoded.lod.iter().zip(kept.lod.iter()) {
+                assert!(kept.image.is_empty());
+                assert_eq!(kept.blocks.as_ref().unwrap().codec, codec);
+                assert_eq!(kept.decoded().image, decoded.image);
+                assert_eq!(kept.rgba().channels(), 4);
+            }
+
+            let mut rewritten = Vec::new();
+            kept.write_to(&mut rewritten, codec).unwrap();
+            assert_eq!(rewritten, data);
+        }
+    }
+
+    #[test]
+    fn version_1_files_rewrite() {
+        let v1 = TexturedQuadTree::new("maps/test-map1/00_00/color.tqt").unwrap();
+        let mut data = Vec::new();
+        v1.write_to(&mut data, Codec::Png).unwrap();
+        let v2 = TexturedQuadTree::read_from(&mut BufReader::new(Cursor::new(data))).unwrap();
+
+        assert_eq!(v2.depth, v1.depth);
+        assert!(v1
+            .lod
+            .iter()
+            .zip(v2.lod.iter())
+            .all(|(a, b)| a.size == b.size && a.image == b.image));
+    }
 }
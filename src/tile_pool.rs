@@ -0,0 +1,959 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::Arc,
+};
+
+use vulkano::{
+    buffer::{BufferContents, BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer},
+    command_buffer::{
+        allocator::CommandBufferAllocator, AutoCommandBufferBuilder, BufferCopy, BufferImageCopy,
+        CopyBufferInfoTyped, CopyBufferToImageInfo,
+    },
+    device::{Device, DeviceOwned},
+    format::Format,
+    image::{
+        sys::{Image, ImageCreateInfo, RawImage},
+        view::ImageView,
+        ImageAccess, ImageDescriptorLayouts, ImageDimensions, ImageInner, ImageLayout,
+        ImageSubresourceLayers, ImageUsage,
+    },
+    memory::{
+        allocator::{
+            AllocationCreateInfo, AllocationType, MemoryAllocatePreference, MemoryAllocator,
+            MemoryUsage,
+        },
+        DedicatedAllocation,
+    },
+    pipeline::graphics::vertex_input::{
+        VertexInputAttributeDescription, VertexInputBindingDescription, VertexInputRate,
+        VertexInputState,
+    },
+    sync::Sharing,
+};
+
+use group_project::{
+    cell::{
+        chunk::{Chunk, HFVertex, QuantizedVertex},
+        tile::TileId,
+    },
+    texture_quadtree::{Codec, Texture},
+};
+
+/// How the pool's vertex arena and per-vertex normals are read, from
+/// bindings 0 and 1. `impl_vertex!` only matches 32-bit shader inputs, so
+/// the layout of the `ivec4` position and the normalized `vec4` normal is
+/// spelled out by hand.
+pub fn vertex_input_state() -> VertexInputState {
+    VertexInputState::new()
+        .binding(
+            0,
+            VertexInputBindingDescription {
+                stride: std::mem::size_of::<QuantizedVertex>() as u32,
+                input_rate: VertexInputRate::Vertex,
+            },
+        )
+        .attribute(
+            0,
+            VertexInputAttributeDescription {
+                binding: 0,
+                format: Format::R16G16B16A16_SINT,
+                offset: 0,
+            },
+        )
+        .binding(
+            1,
+            VertexInputBindingDescription {
+                stride: std::mem::size_of::<VertexNormal>() as u32,
+                input_rate: VertexInputRate::Vertex,
+            },
+        )
+        .attribute(
+            1,
+            VertexInputAttributeDescription {
+                binding: 1,
+                format: Format::R8G8B8A8_SNORM,
+                offset: 0,
+            },
+        )
+}
+
+/// x, y and z of a normal in world space, scaled to `i8::MAX`; w is padding
+type VertexNormal = [i8; 4];
+
+/// First-fit suballocator over `0..capacity`. Free ranges are kept sorted
+/// and merged with their neighbours when freed.
+#[derive(Debug)]
+pub struct RangeAllocator {
+    free: Vec<Range<u32>>,
+}
+
+impl RangeAllocator {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            free: std::iter::once(0..capacity).collect(),
+        }
+    }
+
+    /// Carve `len` elements out of the first free range big enough
+    pub fn allocate(&mut self, len: u32) -> Option<Range<u32>> {
+        let i = self.free.iter().position(|r| r.len() as u32 >= len)?;
+        let start = self.free[i].start;
+        self.free[i].start += len;
+        if self.free[i].is_empty() {
+            self.free.remove(i);
+        }
+
+        Some(start..start + len)
+    }
+
+    pub fn free(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let i = self.free.partition_point(|r| r.start < range.start);
+        let merges_prev = i > 0 && self.free[i - 1].end == range.start;
+        let merges_next = i < self.free.len() && self.free[i].start == range.end;
+
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.free[i - 1].end = self.free[i].end;
+                self.free.remove(i);
+            }
+            (true, false) => self.free[i - 1].end = range.end,
+            (false, true) => self.free[i].start = range.start,
+            (false, false) => self.free.insert(i, range),
+        }
+    }
+}
+
+/// A device-local buffer handed out in pieces
+struct Arena<T>
+where
+    [T]: BufferContents,
+{
+    buffer: Arc<DeviceLocalBuffer<[T]>>,
+    ranges: RangeAllocator,
+}
+
+impl<T> Arena<T>
+where
+    [T]: BufferContents,
+    T: Send + Sync + Copy + 'static,
+{
+    fn new(
+        allocator: &(impl MemoryAllocator + ?Sized),
+        capacity: u32,
+        usage: BufferUsage,
+        queue_family_indices: impl IntoIterator<Item = u32>,
+    ) -> Self {
+        let buffer = DeviceLocalBuffer::array(
+            allocator,
+            capacity as u64,
+            BufferUsage {
+                transfer_dst: true,
+                ..usage
+            },
+            queue_family_indices,
+        )
+        .unwrap();
+
+        Self {
+            buffer,
+            ranges: RangeAllocator::new(capacity),
+        }
+    }
+
+    /// Copy `data` into `range` of the arena through a staging buffer
+    fn upload<L, A: CommandBufferAllocator>(
+        &self,
+        allocator: &(impl MemoryAllocator + ?Sized),
+        range: &Range<u32>,
+        data: impl ExactSizeIterator<Item = T>,
+        uploads: &mut AutoCommandBufferBuilder<L, A>,
+    ) {
+        upload(allocator, &self.buffer, range, data, uploads)
+    }
+}
+
+/// Copy `data` into `range` of `buffer` through a staging buffer
+fn upload<T, L, A: CommandBufferAllocator>(
+    allocator: &(impl MemoryAllocator + ?Sized),
+    buffer: &Arc<DeviceLocalBuffer<[T]>>,
+    range: &Range<u32>,
+    data: impl ExactSizeIterator<Item = T>,
+    uploads: &mut AutoCommandBufferBuilder<L, A>,
+) where
+    [T]: BufferContents,
+    T: Send + Sync + Copy + 'static,
+{
+    let staging = CpuAccessibleBuffer::from_iter(
+        allocator,
+        BufferUsage {
+            transfer_src: true,
+            ..Default::default()
+        },
+        false,
+        data,
+    )
+    .unwrap();
+
+    uploads
+        .copy_buffer(CopyBufferInfoTyped {
+            regions: [BufferCopy {
+                src_offset: 0,
+                dst_offset: range.start as u64,
+                size: range.len() as u64,
+                ..Default::default()
+            }]
+            .into(),
+            ..CopyBufferInfoTyped::buffers(staging, buffer.clone())
+        })
+        .unwrap();
+}
+
+/// The index arena: 16-bit, unless some chunk of the map has more vertices
+/// than those reach
+enum IndexArena {
+    Narrow(Arena<u16>),
+    Wide(Arena<u32>),
+}
+
+impl IndexArena {
+    fn ranges(&mut self) -> &mut RangeAllocator {
+        match self {
+            Self::Narrow(arena) => &mut arena.ranges,
+            Self::Wide(arena) => &mut arena.ranges,
+        }
+    }
+
+    /// Bytes per index
+    fn index_size(&self) -> usize {
+        match self {
+            Self::Narrow(_) => std::mem::size_of::<u16>(),
+            Self::Wide(_) => std::mem::size_of::<u32>(),
+        }
+    }
+
+    /// Copy `indices` into `range` of the arena, narrowing them if it's
+    /// 16-bit
+    fn upload<L, A: CommandBufferAllocator>(
+        &self,
+        allocator: &(impl MemoryAllocator + ?Sized),
+        range: &Range<u32>,
+        indices: &[u32],
+        uploads: &mut AutoCommandBufferBuilder<L, A>,
+    ) {
+        match self {
+            Self::Narrow(arena) => {
+                let narrow = indices.iter().map(|&index| match index {
+                    Chunk::RESTART_INDEX => u16::MAX,
+                    index => index as u16,
+                });
+                arena.upload(allocator, range, narrow, uploads)
+            }
+            Self::Wide(arena) => arena.upload(allocator, range, indices.iter().copied(), uploads),
+        }
+    }
+}
+
+/// A 2D array image with a full mip chain. Vulkano's `StorageImage` only
+/// ever has one level, and `ImmutableImage` can't be written to again once
+/// it's initialized, so this is a `StorageImage` with mipmaps: it stays in the
+/// general layout, which lets single layers be overwritten while the others
+/// are sampled.
+#[derive(Debug)]
+pub struct MipmappedImage {
+    inner: Arc<Image>,
+}
+
+impl MipmappedImage {
+    fn new(
+        allocator: &(impl MemoryAllocator + ?Sized),
+        dimensions: ImageDimensions,
+        mip_levels: u32,
+        format: Format,
+        usage: ImageUsage,
+        queue_family_indices: impl IntoIterator<Item = u32>,
+    ) -> Arc<Self> {
+        let queue_family_indices: Vec<_> = queue_family_indices.into_iter().collect();
+        let raw_image = RawImage::new(
+            allocator.device().clone(),
+            ImageCreateInfo {
+                dimensions,
+                format: Some(format),
+                mip_levels,
+                usage,
+                sharing: if queue_family_indices.len() >= 2 {
+                    Sharing::Concurrent(queue_family_indices.into())
+                } else {
+                    Sharing::Exclusive
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let allocation = unsafe {
+            allocator.allocate_unchecked(AllocationCreateInfo {
+                requirements: raw_image.memory_requirements()[0],
+                allocation_type: AllocationType::NonLinear,
+                usage: MemoryUsage::GpuOnly,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                dedicated_allocation: Some(DedicatedAllocation::Image(&raw_image)),
+                ..Default::default()
+            })
+        }
+        .unwrap();
+        let inner = unsafe { raw_image.bind_memory_unchecked([allocation]) }
+            .map_err(|(err, _, _)| err)
+            .unwrap();
+
+        Arc::new(Self {
+            inner: Arc::new(inner),
+        })
+    }
+}
+
+unsafe impl DeviceOwned for MipmappedImage {
+    fn device(&self) -> &Arc<Device> {
+        self.inner.device()
+    }
+}
+
+unsafe impl ImageAccess for MipmappedImage {
+    fn inner(&self) -> ImageInner<'_> {
+        ImageInner {
+            image: &self.inner,
+            first_layer: 0,
+            num_layers: self.inner.dimensions().array_layers(),
+            first_mipmap_level: 0,
+            num_mipmap_levels: self.inner.mip_levels(),
+        }
+    }
+
+    fn initial_layout_requirement(&self) -> ImageLayout {
+        ImageLayout::General
+    }
+
+    fn final_layout_requirement(&self) -> ImageLayout {
+        ImageLayout::General
+    }
+
+    fn descriptor_layouts(&self) -> Option<ImageDescriptorLayouts> {
+        Some(ImageDescriptorLayouts {
+            storage_image: ImageLayout::General,
+            combined_image_sampler: ImageLayout::General,
+            sampled_image: ImageLayout::General,
+            input_attachment: ImageLayout::General,
+        })
+    }
+}
+
+impl PartialEq for MipmappedImage {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner() == other.inner()
+    }
+}
+
+impl Eq for MipmappedImage {}
+
+impl Hash for MipmappedImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner().hash(state);
+    }
+}
+
+/// The tiles a texture array is made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureTiles {
+    /// Tiles of other sizes are resized to this
+    pub size: u32,
+    /// The codec of the blocks every tile is kept as, if they go up as they
+    /// are
+    pub blocks: Option<Codec>,
+}
+
+impl TextureTiles {
+    /// What an array needs for `textures`, starting with a root. Tiles go up
+    /// as their blocks if the device samples BC formats (`compressed`) and
+    /// they're all kept as blocks of one codec and size; otherwise anything
+    /// kept as blocks is decoded on the way up.
+    pub fn of<'a>(
+        mut textures: impl Iterator<Item = &'a Texture>,
+        compressed: bool,
+    ) -> Option<Self> {
+        let first = textures.next()?;
+        let blocks = first
+            .blocks
+            .as_ref()
+            .map(|blocks| blocks.codec)
+            .filter(|&codec| {
+                compressed
+                    && textures.all(|texture| {
+                        texture.size == first.size
+                            && texture.blocks.as_ref().is_some_and(|b| b.codec == codec)
+                    })
+            });
+
+        Some(Self {
+            size: first.size,
+            blocks,
+        })
+    }
+}
+
+/// A 2D array image, one tile texture per layer, with its mip chain; BC
+/// tiles only have their full size blocks, so their arrays have no chain,
+/// and the tree's coarser levels stand in for it
+struct TextureArray {
+    image: Arc<MipmappedImage>,
+    view: Arc<ImageView<MipmappedImage>>,
+    tiles: TextureTiles,
+    /// Whether colors are sRGB, and mip levels get averaged as linear light
+    srgb: bool,
+}
+
+impl TextureArray {
+    fn new(
+        allocator: &(impl MemoryAllocator + ?Sized),
+        tiles: TextureTiles,
+        n_layers: u32,
+        srgb: bool,
+        queue_family_indices: impl IntoIterator<Item = u32>,
+    ) -> Self {
+        let format = match (tiles.blocks, srgb) {
+            (None, true) => Format::R8G8B8A8_SRGB,
+            (None, false) => Format::R8G8B8A8_UNORM,
+            (Some(Codec::Bc1), true) => Format::BC1_RGB_SRGB_BLOCK,
+            (Some(Codec::Bc1), false) => Format::BC1_RGB_UNORM_BLOCK,
+            (Some(Codec::Bc3), true) => Format::BC3_SRGB_BLOCK,
+            (Some(Codec::Bc3), false) => Format::BC3_UNORM_BLOCK,
+            (Some(codec), _) => unreachable!("{:?} tiles aren't kept as blocks", codec),
+        };
+        let dimensions = ImageDimensions::Dim2d {
+            width: tiles.size,
+            height: tiles.size,
+            array_layers: n_layers,
+        };
+        let mip_levels = match tiles.blocks {
+            Some(_) => 1,
+            None => dimensions.max_mip_levels(),
+        };
+
+        let image = MipmappedImage::new(
+            allocator,
+            dimensions,
+            mip_levels,
+            format,
+            ImageUsage {
+                transfer_dst: true,
+                sampled: true,
+                ..Default::default()
+            },
+            queue_family_indices,
+        );
+
+        Self {
+            view: ImageView::new_default(image.clone()).unwrap(),
+            image,
+            tiles,
+            srgb,
+        }
+    }
+
+    /// Bytes taken by a single layer, all its mip levels included
+    fn layer_bytes(&self) -> u64 {
+        let dimensions = self.image.dimensions();
+        let format = self.image.format();
+        let [block_width, block_height, _] = format.block_extent();
+        let block_size = format.block_size().unwrap();
+        (0..self.image.mip_levels())
+            .filter_map(|level| dimensions.mip_level_dimensions(level))
+            .map(|d| {
+                d.width().div_ceil(block_width) as u64
+                    * d.height().div_ceil(block_height) as u64
+                    * block_size
+            })
+            .sum()
+    }
+
+    /// Copy the texture into `layer` through a staging buffer: its blocks
+    /// if the array takes them, otherwise its image and mip chain
+    fn upload<L, A: CommandBufferAllocator>(
+        &self,
+        allocator: &(impl MemoryAllocator + ?Sized),
+        layer: u32,
+        texture: &Texture,
+        uploads: &mut AutoCommandBufferBuilder<L, A>,
+    ) {
+        let subresource = ImageSubresourceLayers {
+            array_layers: layer..layer + 1,
+            ..self.image.subresource_layers()
+        };
+        let staging = |data: Vec<u8>| {
+            CpuAccessibleBuffer::from_iter(
+                allocator,
+                BufferUsage {
+                    transfer_src: true,
+                    ..Default::default()
+                },
+                false,
+                data,
+            )
+            .unwrap()
+        };
+
+        if let (Some(_), Some(blocks)) = (self.tiles.blocks, &texture.blocks) {
+            let region = BufferImageCopy {
+                image_subresource: subresource,
+                image_extent: [texture.size, texture.size, 1],
+                ..Default::default()
+            };
+            uploads
+                .copy_buffer_to_image(CopyBufferToImageInfo {
+                    regions: [region].into(),
+                    ..CopyBufferToImageInfo::buffer_image(
+                        staging(blocks.data.clone()),
+                        self.image.clone(),
+                    )
+                })
+                .unwrap();
+            return;
+        }
+
+        // Levels of a tree can have tiles of different sizes, but the array
+        // has one
+        let size = self.tiles.size;
+        let mut texture = texture.rgba();
+        if texture.size != size {
+            texture = Cow::Owned(texture.resized(size));
+        }
+
+        // Levels are packed one after the other in the chain
+        let mut buffer_offset = 0;
+        let regions = (0..texture.mip_levels().min(self.image.mip_levels()))
+            .map(|mip_level| {
+                let size = (texture.size >> mip_level).max(1);
+                let region = BufferImageCopy {
+                    buffer_offset,
+                    image_subresource: ImageSubresourceLayers {
+                        mip_level,
+                        ..subresource.clone()
+                    },
+                    image_extent: [size, size, 1],
+                    ..Default::default()
+                };
+                buffer_offset += size as u64 * size as u64 * 4;
+                region
+            })
+            .collect();
+
+        uploads
+            .copy_buffer_to_image(CopyBufferToImageInfo {
+                regions,
+                ..CopyBufferToImageInfo::buffer_image(
+                    staging(texture.mip_chain(self.srgb)),
+                    self.image.clone(),
+                )
+            })
+            .unwrap();
+    }
+}
+
+/// Where a resident tile lives in the pool's buffers
+#[derive(Debug, Clone)]
+pub struct ResidentTile {
+    /// Range of the vertex arena, in vertices
+    pub vertices: Range<u32>,
+    /// Range of the index arena, in indices
+    pub indices: Range<u32>,
+    /// Layer of the texture arrays holding the tile's textures
+    pub layer: u32,
+    /// The last generation that asked for the tile
+    last_used: u64,
+}
+
+/// Keeps tile geometry in two device-local arenas, one for vertices and one
+/// for indices, with a normal per vertex beside the vertices for maps that
+/// have no normal map to light them, and tile textures in the layers of texture arrays, one for
+/// colors and one for normals. Tiles are uploaded on demand and stay resident
+/// until room is needed for others, at which point the least recently used
+/// ones go.
+pub struct TilePool {
+    vertices: Arena<QuantizedVertex>,
+    /// At the same places as `vertices`; `None` for maps with a normal map,
+    /// which keeps their vertices at 8 bytes
+    vertex_normals: Option<Arc<DeviceLocalBuffer<[VertexNormal]>>>,
+    indices: IndexArena,
+    /// `None` for maps without a color map
+    colors: Option<TextureArray>,
+    /// `None` for maps without a normal map
+    normals: Option<TextureArray>,
+    /// Bound in place of a missing texture array
+    placeholder: Arc<ImageView<MipmappedImage>>,
+    /// A tile gets the same layer in both texture arrays
+    layers: RangeAllocator,
+    resident: HashMap<TileId, ResidentTile>,
+    /// Vertex units to world units, for working out normals
+    scale: [f64; 3],
+    generation: u64,
+    /// Bytes recorded for upload since the last `take_uploaded_bytes`
+    uploaded_bytes: u64,
+}
+
+impl TilePool {
+    /// Vertices the vertex arena can hold; 8MB of `QuantizedVertex`, and 4MB
+    /// of normals for maps without a normal map
+    pub const VERTEX_CAPACITY: u32 = 1 << 20;
+    /// Indices the index arena can hold; 4MB of `u16`, or 8MB of `u32` for
+    /// maps that need them
+    pub const INDEX_CAPACITY: u32 = 1 << 21;
+    /// Layers of the texture array; every device supports at least this many
+    pub const TEXTURE_CAPACITY: u32 = 256;
+
+    /// Make a pool whose arenas are usable from all the given queue families,
+    /// for color and normal textures of the given tiles, if the map has them,
+    /// and chunks whose vertex units are `scale` world units. Without normal
+    /// tiles, vertices get normals of their own. Indices are
+    /// 32-bit only with `wide_indices`, for maps with chunks that need them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        allocator: &(impl MemoryAllocator + ?Sized),
+        queue_family_indices: &[u32],
+        scale: [f64; 3],
+        vertex_capacity: u32,
+        index_capacity: u32,
+        wide_indices: bool,
+        color_tiles: Option<TextureTiles>,
+        normal_tiles: Option<TextureTiles>,
+        texture_capacity: u32,
+    ) -> Self {
+        let texture_array = |tiles, n_layers, srgb| {
+            TextureArray::new(
+                allocator,
+                tiles,
+                n_layers,
+                srgb,
+                queue_family_indices.iter().copied(),
+            )
+        };
+
+        let colors = color_tiles.map(|tiles| texture_array(tiles, texture_capacity, true));
+        let normals = normal_tiles.map(|tiles| texture_array(tiles, texture_capacity, false));
+        let vertex_usage = BufferUsage {
+            vertex_buffer: true,
+            ..Default::default()
+        };
+        let vertex_normals = normals.is_none().then(|| {
+            Arena::<VertexNormal>::new(
+                allocator,
+                vertex_capacity,
+                vertex_usage,
+                queue_family_indices.iter().copied(),
+            )
+            .buffer
+        });
+
+        let index_usage = BufferUsage {
+            index_buffer: true,
+            ..Default::default()
+        };
+
+        // Without textures, layers are only there to be handed out
+        let n_layers = if colors.is_some() || normals.is_some() {
+            texture_capacity
+        } else {
+            u32::MAX
+        };
+
+        Self {
+            vertices: Arena::new(
+                allocator,
+                vertex_capacity,
+                vertex_usage,
+                queue_family_indices.iter().copied(),
+            ),
+            vertex_normals,
+            indices: match wide_indices {
+                false => IndexArena::Narrow(Arena::new(
+                    allocator,
+                    index_capacity,
+                    index_usage,
+                    queue_family_indices.iter().copied(),
+                )),
+                true => IndexArena::Wide(Arena::new(
+                    allocator,
+                    index_capacity,
+                    index_usage,
+                    queue_family_indices.iter().copied(),
+                )),
+            },
+            placeholder: texture_array(
+                TextureTiles {
+                    size: 1,
+                    blocks: None,
+                },
+                1,
+                false,
+            )
+            .view,
+            colors,
+            normals,
+            layers: RangeAllocator::new(n_layers),
+            resident: HashMap::new(),
+            scale,
+            generation: 0,
+            uploaded_bytes: 0,
+        }
+    }
+
+    /// Bind the vertex arena and the normals beside it. Maps with a normal
+    /// map never read per-vertex normals, so the arena stands in for them
+    pub fn bind_vertex_buffers<L, A: CommandBufferAllocator>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<L, A>,
+    ) {
+        let vertices = self.vertices.buffer.clone();
+        match &self.vertex_normals {
+            Some(normals) => builder.bind_vertex_buffers(0, (vertices, normals.clone())),
+            None => builder.bind_vertex_buffers(0, (vertices.clone(), vertices)),
+        };
+    }
+
+    /// Bytes per vertex, its normal included if it has one
+    fn vertex_size(&self) -> usize {
+        let normal_size = match self.vertex_normals {
+            Some(_) => std::mem::size_of::<VertexNormal>(),
+            None => 0,
+        };
+        std::mem::size_of::<QuantizedVertex>() + normal_size
+    }
+
+    /// Bind the index arena, whichever width it is
+    pub fn bind_index_buffer<L, A: CommandBufferAllocator>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<L, A>,
+    ) {
+        match &self.indices {
+            IndexArena::Narrow(arena) => builder.bind_index_buffer(arena.buffer.clone()),
+            IndexArena::Wide(arena) => builder.bind_index_buffer(arena.buffer.clone()),
+        };
+    }
+
+    /// The view over all the layers of the color texture array, or a
+    /// placeholder if the map has no color map
+    pub fn color_array(&self) -> &Arc<ImageView<MipmappedImage>> {
+        self.colors.as_ref().map_or(&self.placeholder, |a| &a.view)
+    }
+
+    /// The view over all the layers of the normal texture array, or a
+    /// placeholder if the map has no normal map
+    pub fn normal_array(&self) -> &Arc<ImageView<MipmappedImage>> {
+        self.normals.as_ref().map_or(&self.placeholder, |a| &a.view)
+    }
+
+    /// Bytes a tile takes in the texture arrays
+    fn texture_bytes(&self) -> u64 {
+        [&self.colors, &self.normals]
+            .into_iter()
+            .flatten()
+            .map(TextureArray::layer_bytes)
+            .sum()
+    }
+
+    /// Bytes of the vertex arena taken by resident tiles
+    pub fn resident_vertex_bytes(&self) -> u64 {
+        let n: u64 = self
+            .resident
+            .values()
+            .map(|t| t.vertices.len() as u64)
+            .sum();
+        n * self.vertex_size() as u64
+    }
+
+    /// Bytes of the index arena taken by resident tiles
+    pub fn resident_index_bytes(&self) -> u64 {
+        let n: u64 = self.resident.values().map(|t| t.indices.len() as u64).sum();
+        n * self.indices.index_size() as u64
+    }
+
+    /// Bytes of the texture arrays taken by resident tiles
+    pub fn resident_texture_bytes(&self) -> u64 {
+        self.resident.len() as u64 * self.texture_bytes()
+    }
+
+    /// Bytes recorded for upload since the last call
+    pub fn take_uploaded_bytes(&mut self) -> u64 {
+        std::mem::take(&mut self.uploaded_bytes)
+    }
+
+    pub fn get(&self, id: &TileId) -> Option<&ResidentTile> {
+        self.resident.get(id)
+    }
+
+    pub fn is_resident(&self, id: &TileId) -> bool {
+        self.resident.contains_key(id)
+    }
+
+    /// Start a new generation. Tiles requested from now on will not be
+    /// evicted to make room for each other.
+    pub fn next_generation(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Make sure the tile is resident, recording its upload into `uploads`
+    /// if it isn't. Returns whether the tile is resident afterwards; it may
+    /// not be if the tiles of this generation already fill the pool. Textures
+    /// are expected if and only if the pool was made with their tiles. Chunks
+    /// without vertices or indices have nothing to draw; they never become
+    /// resident, and nothing is missing for them either, so they get `true`.
+    pub fn request<L, A: CommandBufferAllocator>(
+        &mut self,
+        id: TileId,
+        chunk: &Chunk,
+        color: Option<&Texture>,
+        normals: Option<&Texture>,
+        allocator: &(impl MemoryAllocator + ?Sized),
+        uploads: &mut AutoCommandBufferBuilder<L, A>,
+    ) -> bool {
+        if let Some(tile) = self.resident.get_mut(&id) {
+            tile.last_used = self.generation;
+            return true;
+        }
+
+        // Staging buffers can't be empty, and there'd be nothing to draw
+        if chunk.vertices.is_empty() || chunk.indices.is_empty() {
+            return true;
+        }
+
+        let Some(vertices) = self.allocate(|pool| &mut pool.vertices.ranges, chunk.vertices.len())
+        else {
+            return false;
+        };
+
+        let Some(indices) = self.allocate(|pool| pool.indices.ranges(), chunk.indices.len()) else {
+            self.vertices.ranges.free(vertices);
+            return false;
+        };
+
+        let Some(layers) = self.allocate(|pool| &mut pool.layers, 1) else {
+            self.vertices.ranges.free(vertices);
+            self.indices.ranges().free(indices);
+            return false;
+        };
+
+        self.vertices.upload(
+            allocator,
+            &vertices,
+            chunk.vertices.iter().map(HFVertex::quantized),
+            uploads,
+        );
+        if let Some(buffer) = &self.vertex_normals {
+            let normals = chunk.vertex_normals(self.scale);
+            let normals = normals.into_iter().map(|[x, y, z]| [x, y, z, 0]);
+            upload(allocator, buffer, &vertices, normals, uploads);
+        }
+        self.indices
+            .upload(allocator, &indices, &chunk.indices, uploads);
+        for (array, texture) in [(&self.colors, color), (&self.normals, normals)] {
+            if let (Some(array), Some(texture)) = (array, texture) {
+                array.upload(allocator, layers.start, texture, uploads);
+            }
+        }
+
+        self.uploaded_bytes += (vertices.len() * self.vertex_size()
+            + indices.len() * self.indices.index_size()) as u64
+            + self.texture_bytes();
+
+        self.resident.insert(
+            id,
+            ResidentTile {
+                vertices,
+                indices,
+                layer: layers.start,
+                last_used: self.generation,
+            },
+        );
+
+        true
+    }
+
+    /// Drop the tile from the pool, freeing its slots for reuse
+    pub fn evict(&mut self, id: &TileId) {
+        if let Some(tile) = self.resident.remove(id) {
+            self.vertices.ranges.free(tile.vertices);
+            self.indices.ranges().free(tile.indices);
+            self.layers.free(tile.layer..tile.layer + 1);
+        }
+    }
+
+    /// Allocate `len` elements from one of the pool's allocators, evicting
+    /// tiles of older generations until they fit
+    fn allocate(
+        &mut self,
+        ranges: fn(&mut Self) -> &mut RangeAllocator,
+        len: usize,
+    ) -> Option<Range<u32>> {
+        loop {
+            if let Some(range) = ranges(self).allocate(len as u32) {
+                return Some(range);
+            }
+
+            if !self.evict_least_recent() {
+                return None;
+            }
+        }
+    }
+
+    /// Evict the least recently used tile of an older generation, if any
+    fn evict_least_recent(&mut self) -> bool {
+        let victim = self
+            .resident
+            .iter()
+            .filter(|(_, tile)| tile.last_used < self.generation)
+            .min_by_key(|(_, tile)| tile.last_used)
+            .map(|(id, _)| *id);
+
+        match victim {
+            Some(id) => {
+                self.evict(&id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RangeAllocator;
+
+    #[test]
+    fn allocates_first_fit() {
+        let mut ranges = RangeAllocator::new(10);
+        assert_eq!(ranges.allocate(4), Some(0..4));
+        assert_eq!(ranges.allocate(4), Some(4..8));
+        assert_eq!(ranges.allocate(4), None);
+        assert_eq!(ranges.allocate(2), Some(8..10));
+        assert_eq!(ranges.allocate(1), None);
+    }
+
+    #[test]
+    fn reuses_and_merges_freed_ranges() {
+        let mut ranges = RangeAllocator::new(12);
+        let a = ranges.allocate(4).unwrap();
+        let b = ranges.allocate(4).unwrap();
+        let c = ranges.allocate(4).unwrap();
+
+        ranges.free(a);
+        ranges.free(c);
+        assert_eq!(ranges.allocate(6), None);
+
+        ranges.free(b);
+        assert_eq!(ranges.allocate(12), Some(0..12));
+    }
+}
@@ -6,16 +6,41 @@ use vulkano::{
         QueueCreateInfo, QueueFlags,
     },
     image::{ImageUsage, SwapchainImage},
-    instance::{Instance, InstanceCreateInfo},
-    swapchain::{Surface, Swapchain, SwapchainCreateInfo},
+    instance::{Instance, InstanceCreateInfo, InstanceExtensions},
+    swapchain::{PresentMode, Surface, Swapchain, SwapchainCreateInfo},
     VulkanLibrary,
 };
 use vulkano_win::VkSurfaceBuild;
 use winit::{
+    dpi::PhysicalSize,
     event_loop::EventLoop,
-    window::{Window, WindowBuilder},
+    window::{Fullscreen, Window, WindowBuilder},
 };
 
+/// How the window should be opened
+#[derive(Debug, Clone)]
+pub struct WindowSettings {
+    /// Inner size in pixels, ignored when fullscreen
+    pub size: [u32; 2],
+    pub fullscreen: bool,
+    /// Falls back to `Fifo`, which is always there, when unsupported
+    pub present_mode: PresentMode,
+    /// Whether to enable the debug utils extension so that a debug
+    /// messenger can be made
+    pub debug_utils: bool,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        Self {
+            size: [1280, 720],
+            fullscreen: false,
+            present_mode: PresentMode::Fifo,
+            debug_utils: false,
+        }
+    }
+}
+
 /// Responsible for tracking the vulkan and window states
 pub struct WindowState {
     /// The vulkan instance
@@ -24,6 +49,9 @@ pub struct WindowState {
     pub device: Arc<Device>,
     /// The graphics/presentation queue
     pub queue: Arc<Queue>,
+    /// The queue tile uploads go through; a dedicated transfer queue when
+    /// the device has one, the graphics queue otherwise
+    pub transfer_queue: Arc<Queue>,
     /// The vulkan surface of the window
     pub surface: Arc<Surface>,
     /// The vulkan swapchain
@@ -33,9 +61,12 @@ pub struct WindowState {
 }
 
 impl WindowState {
-    fn create_vulkan_instance() -> Arc<Instance> {
+    fn create_vulkan_instance(debug_utils: bool) -> Arc<Instance> {
         let library = VulkanLibrary::new().unwrap();
-        let required_extentions = vulkano_win::required_extensions(&library);
+        let required_extentions = InstanceExtensions {
+            ext_debug_utils: debug_utils && library.supported_extensions().ext_debug_utils,
+            ..vulkano_win::required_extensions(&library)
+        };
 
         Instance::new(
             library,
@@ -50,11 +81,15 @@ impl WindowState {
 
     fn create_surface(
         title: String,
+        settings: &WindowSettings,
         event_loop: &EventLoop<()>,
         instance: Arc<Instance>,
     ) -> Arc<Surface> {
+        let [width, height] = settings.size;
         WindowBuilder::new()
             .with_title(title)
+            .with_inner_size(PhysicalSize::new(width, height))
+            .with_fullscreen(settings.fullscreen.then_some(Fullscreen::Borderless(None)))
             .build_vk_surface(event_loop, instance.clone())
             .unwrap()
     }
@@ -62,10 +97,18 @@ impl WindowState {
     fn get_device_and_queue(
         instance: Arc<Instance>,
         surface: Arc<Surface>,
-    ) -> (Arc<Device>, Arc<Queue>) {
+    ) -> (Arc<Device>, Arc<Queue>, Arc<Queue>) {
         let device_extensions = DeviceExtensions {
             khr_swapchain: true,
-            khr_push_descriptor: true,
+            ..Default::default()
+        };
+
+        // All the selected tiles go in one indirect draw, told apart by their
+        // first instance
+        let device_features = Features {
+            fill_mode_non_solid: true,
+            multi_draw_indirect: true,
+            draw_indirect_first_instance: true,
             ..Default::default()
         };
 
@@ -73,6 +116,7 @@ impl WindowState {
             .enumerate_physical_devices()
             .unwrap()
             .filter(|p| p.supported_extensions().contains(&device_extensions))
+            .filter(|p| p.supported_features().contains(&device_features))
             .filter_map(|p| {
                 p.queue_family_properties()
                     .iter()
@@ -95,31 +139,53 @@ impl WindowState {
             })
             .expect("error finding queue.");
 
+        // Anisotropic filtering and sampling BC textures are nice to have, so
+        // they're only turned on where they're there
+        let supported = physical_device.supported_features();
+        let device_features = Features {
+            sampler_anisotropy: supported.sampler_anisotropy,
+            texture_compression_bc: supported.texture_compression_bc,
+            ..device_features
+        };
+
+        let transfer_family_index = physical_device
+            .queue_family_properties()
+            .iter()
+            .position(|q| q.queue_flags.transfer && !q.queue_flags.graphics)
+            .map(|i| i as u32);
+
+        let mut queue_create_infos = vec![QueueCreateInfo {
+            queue_family_index,
+            ..Default::default()
+        }];
+        if let Some(queue_family_index) = transfer_family_index {
+            queue_create_infos.push(QueueCreateInfo {
+                queue_family_index,
+                ..Default::default()
+            });
+        }
+
         let (device, mut queues) = Device::new(
             physical_device,
             DeviceCreateInfo {
-                enabled_features: Features {
-                    fill_mode_non_solid: true,
-                    ..Default::default()
-                },
+                enabled_features: device_features,
                 enabled_extensions: device_extensions,
-                queue_create_infos: vec![QueueCreateInfo {
-                    queue_family_index,
-                    ..Default::default()
-                }],
+                queue_create_infos,
                 ..Default::default()
             },
         )
         .unwrap();
 
         let queue = queues.next().unwrap();
+        let transfer_queue = queues.next().unwrap_or_else(|| queue.clone());
 
-        (device, queue)
+        (device, queue, transfer_queue)
     }
 
     fn create_swapchain(
         device: Arc<Device>,
         surface: Arc<Surface>,
+        present_mode: PresentMode,
     ) -> (Arc<Swapchain>, Vec<Arc<SwapchainImage>>) {
         let surface_capabilities = device
             .physical_device()
@@ -134,6 +200,21 @@ impl WindowState {
                 .0,
         );
 
+        let present_mode = if device
+            .physical_device()
+            .surface_present_modes(&surface)
+            .unwrap()
+            .any(|mode| mode == present_mode)
+        {
+            present_mode
+        } else {
+            eprintln!(
+                "warning: present mode {:?} is not supported, using Fifo",
+                present_mode
+            );
+            PresentMode::Fifo
+        };
+
         let window = surface.object().unwrap().downcast_ref::<Window>().unwrap();
 
         Swapchain::new(
@@ -152,25 +233,39 @@ impl WindowState {
                     .iter()
                     .next()
                     .unwrap(),
+                present_mode,
                 ..Default::default()
             },
         )
         .unwrap()
     }
 
+    /// The queue families that resources shared between the graphics and
+    /// transfer queues have to be usable from
+    pub fn queue_family_indices(&self) -> Vec<u32> {
+        let mut indices = vec![self.queue.queue_family_index()];
+        if self.transfer_queue.queue_family_index() != indices[0] {
+            indices.push(self.transfer_queue.queue_family_index());
+        }
+        indices
+    }
+
     /// Creates the window state given its title
-    pub fn create(title: String) -> (Self, EventLoop<()>) {
+    pub fn create(title: String, settings: &WindowSettings) -> (Self, EventLoop<()>) {
         let event_loop = EventLoop::new();
-        let instance = Self::create_vulkan_instance();
-        let surface = Self::create_surface(title, &event_loop, instance.clone());
-        let (device, queue) = Self::get_device_and_queue(instance.clone(), surface.clone());
-        let (swapchain, images) = Self::create_swapchain(device.clone(), surface.clone());
+        let instance = Self::create_vulkan_instance(settings.debug_utils);
+        let surface = Self::create_surface(title, settings, &event_loop, instance.clone());
+        let (device, queue, transfer_queue) =
+            Self::get_device_and_queue(instance.clone(), surface.clone());
+        let (swapchain, images) =
+            Self::create_swapchain(device.clone(), surface.clone(), settings.present_mode);
 
         (
             Self {
                 instance,
                 device,
                 queue,
+                transfer_queue,
                 surface,
                 swapchain,
                 swapchain_images: images,
@@ -0,0 +1,128 @@
+//! Peak memory while loading the test maps. This is its own test binary, and
+//! its tests take turns, so nothing else allocates while one measures.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use group_project::{cell::Cell, map::Map, texture_quadtree::TexturedQuadTree};
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+use test_util::TempDir;
+
+/// Keeps count of the bytes allocated right now, and the most there were
+struct PeakAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grew(size: usize) {
+    let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(current, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grew(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        grew(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Counted as the new block arriving before the old one goes, which
+        // is what happens when it can't grow in place
+        grew(new_size);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+/// Held by whichever test is measuring
+static MEASURING: Mutex<()> = Mutex::new(());
+
+/// Past the last chunk or tile of a file, far more than loading it should
+/// need on top of what it keeps; a load that copied the whole file would
+/// have to hold this too
+const PADDING: usize = 16 << 20;
+
+/// The bytes what `load` loads holds, and the most that were held at once
+/// while loading it
+fn measure<T>(load: impl FnOnce() -> T) -> (usize, usize) {
+    // The thread pool allocates once, the first time it's used
+    rayon::broadcast(|_| ());
+
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let loaded = load();
+    let held = CURRENT.load(Ordering::Relaxed) - before;
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    drop(loaded);
+
+    (held, peak)
+}
+
+#[test]
+fn loading_peaks_near_what_the_map_holds() {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+
+    for path in ["maps/test-map1", "maps/test-map2"] {
+        let (held, peak) = measure(|| Map::new(path).unwrap());
+        println!("{}: holds {} bytes, peaked at {}", path, held, peak);
+        assert!(
+            peak <= held * 3 / 2,
+            "{} peaked at {} bytes to hold {}",
+            path,
+            peak,
+            held
+        );
+    }
+}
+
+#[test]
+fn files_are_read_a_chunk_or_tile_at_a_time() {
+    let _measuring = MEASURING.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new("memory");
+
+    for map in ["maps/test-map1", "maps/test-map2"] {
+        for file in ["hf.cell", "color.tqt", "norm.tqt"] {
+            let mut data = fs::read(format!("{}/00_00/{}", map, file)).unwrap();
+            data.resize(data.len() + PADDING, 0);
+            let path = dir.join(file);
+            fs::write(&path, data).unwrap();
+
+            let (held, peak) = if file == "hf.cell" {
+                measure(|| Cell::new(&path, (0, 0), None, None, 1024).unwrap())
+            } else {
+                measure(|| TexturedQuadTree::new(&path).unwrap())
+            };
+            println!("{}/{}: holds {} bytes, peaked at {}", map, file, held, peak);
+            assert!(
+                peak - held < PADDING / 4,
+                "{}/{} peaked at {} bytes to hold {}",
+                map,
+                file,
+                peak,
+                held
+            );
+        }
+    }
+}